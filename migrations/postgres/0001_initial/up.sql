@@ -0,0 +1,24 @@
+-- "IF NOT EXISTS" everywhere so that databases created before migrations existed
+-- can be brought under version control without losing anything.
+CREATE TABLE IF NOT EXISTS chat_messages (
+    id SERIAL PRIMARY KEY,
+    author TEXT NOT NULL,
+    timestamp TIMESTAMP NOT NULL,
+    content TEXT NOT NULL,
+    tags INTEGER NOT NULL DEFAULT 0,
+    color TEXT,
+    channel TEXT,
+    tsv TSVECTOR
+);
+CREATE INDEX IF NOT EXISTS chat_messages_timestamp_idx ON chat_messages (timestamp);
+CREATE INDEX IF NOT EXISTS chat_messages_tsv_idx ON chat_messages USING GIN (tsv);
+
+DROP TRIGGER IF EXISTS chat_messages_tsv_update ON chat_messages;
+CREATE TRIGGER chat_messages_tsv_update BEFORE INSERT OR UPDATE ON chat_messages
+    FOR EACH ROW EXECUTE PROCEDURE tsvector_update_trigger(tsv, 'pg_catalog.english', content);
+
+CREATE TABLE IF NOT EXISTS chat_users (
+    username TEXT PRIMARY KEY,
+    password TEXT NOT NULL,
+    admin BOOLEAN NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,2 @@
+-- nothing to do: the column stays a TEXT, since it may already hold PHC strings
+SELECT 1;
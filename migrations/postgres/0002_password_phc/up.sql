@@ -0,0 +1,2 @@
+-- PHC strings are longer than the legacy 64 chars hashes
+ALTER TABLE chat_users ALTER COLUMN password TYPE TEXT;
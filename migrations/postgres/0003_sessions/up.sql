@@ -0,0 +1,9 @@
+-- auth_keys are never stored as is, only their sha256
+CREATE TABLE chat_sessions (
+    key_hash TEXT PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES chat_users (username) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL,
+    last_used TIMESTAMP NOT NULL,
+    user_agent TEXT
+);
+CREATE INDEX chat_sessions_username_idx ON chat_sessions (username);
@@ -0,0 +1,7 @@
+DROP TABLE chat_message_revisions;
+DROP INDEX chat_messages_change_id_idx;
+ALTER TABLE chat_messages DROP COLUMN created_change_id;
+ALTER TABLE chat_messages DROP COLUMN change_id;
+DROP FUNCTION chat_next_change_id();
+DROP TABLE chat_last_change;
+ALTER TABLE chat_messages DROP COLUMN edited_at;
@@ -0,0 +1,33 @@
+ALTER TABLE chat_messages ADD COLUMN edited_at TIMESTAMP;
+
+-- Every change of a message gives it a new change_id, which incremental queries use as their cursor.
+--
+-- The change_ids are taken from the single row of chat_last_change by chat_next_change_id(),
+-- which keeps that row locked until the end of the transaction: changes commit in the order of
+-- their change_id, so once a change_id is visible, so is every change before it.
+-- A sequence wouldn't do, as a smaller value may still be uncommitted while a larger one is read.
+CREATE TABLE chat_last_change (
+    change_id BIGINT NOT NULL
+);
+CREATE FUNCTION chat_next_change_id() RETURNS BIGINT AS $$
+    UPDATE chat_last_change SET change_id = change_id + 1 RETURNING change_id;
+$$ LANGUAGE SQL VOLATILE;
+
+-- created_change_id is the change_id the message was sent with, the existing messages start from their id
+ALTER TABLE chat_messages ADD COLUMN change_id BIGINT;
+ALTER TABLE chat_messages ADD COLUMN created_change_id BIGINT;
+UPDATE chat_messages SET change_id = id, created_change_id = id;
+ALTER TABLE chat_messages ALTER COLUMN change_id SET NOT NULL;
+ALTER TABLE chat_messages ALTER COLUMN created_change_id SET NOT NULL;
+CREATE INDEX chat_messages_change_id_idx ON chat_messages (change_id);
+INSERT INTO chat_last_change (change_id) SELECT COALESCE(MAX(change_id), 0) FROM chat_messages;
+
+-- every content a message had before being edited
+CREATE TABLE chat_message_revisions (
+    id SERIAL PRIMARY KEY,
+    message_id INTEGER NOT NULL REFERENCES chat_messages (id) ON DELETE CASCADE,
+    content TEXT NOT NULL,
+    edited_at TIMESTAMP NOT NULL,
+    edited_by TEXT NOT NULL
+);
+CREATE INDEX chat_message_revisions_message_id_idx ON chat_message_revisions (message_id);
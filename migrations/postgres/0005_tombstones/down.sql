@@ -0,0 +1,4 @@
+DELETE FROM chat_messages WHERE deleted_at IS NOT NULL;
+ALTER TABLE chat_messages DROP COLUMN delete_reason;
+ALTER TABLE chat_messages DROP COLUMN deleted_by;
+ALTER TABLE chat_messages DROP COLUMN deleted_at;
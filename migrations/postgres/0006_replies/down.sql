@@ -0,0 +1,2 @@
+DROP INDEX chat_messages_reply_to_idx;
+ALTER TABLE chat_messages DROP COLUMN reply_to;
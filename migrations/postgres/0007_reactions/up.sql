@@ -0,0 +1,7 @@
+CREATE TABLE chat_reactions (
+    message_id INTEGER NOT NULL REFERENCES chat_messages (id) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    emoji TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (message_id, username, emoji)
+);
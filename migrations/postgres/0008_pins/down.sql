@@ -0,0 +1,3 @@
+DROP INDEX chat_messages_pinned_idx;
+ALTER TABLE chat_messages DROP COLUMN pinned_by;
+ALTER TABLE chat_messages DROP COLUMN pinned_at;
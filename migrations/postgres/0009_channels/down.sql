@@ -0,0 +1,2 @@
+DROP INDEX chat_messages_channel_idx;
+DROP TABLE chat_channels;
@@ -0,0 +1,13 @@
+CREATE TABLE chat_channels (
+    name TEXT PRIMARY KEY,
+    topic TEXT,
+    description TEXT,
+    -- NULL for the channels which were used before this table existed
+    creator TEXT,
+    created_at TIMESTAMP NOT NULL,
+    archived BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX chat_messages_channel_idx ON chat_messages (channel);
+
+INSERT INTO chat_channels (name, created_at)
+    SELECT channel, MIN(timestamp) FROM chat_messages WHERE channel IS NOT NULL GROUP BY channel;
@@ -0,0 +1,2 @@
+DROP TABLE chat_channel_members;
+ALTER TABLE chat_channels DROP COLUMN private;
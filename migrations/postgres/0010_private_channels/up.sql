@@ -0,0 +1,10 @@
+-- only the members of a private channel can see it
+ALTER TABLE chat_channels ADD COLUMN private BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE chat_channel_members (
+    channel TEXT NOT NULL REFERENCES chat_channels (name) ON DELETE CASCADE,
+    username TEXT NOT NULL REFERENCES chat_users (username) ON DELETE CASCADE,
+    added_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (channel, username)
+);
+CREATE INDEX chat_channel_members_username_idx ON chat_channel_members (username);
@@ -0,0 +1 @@
+ALTER TABLE chat_channels DROP COLUMN direct;
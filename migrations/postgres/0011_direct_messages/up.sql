@@ -0,0 +1,2 @@
+-- direct conversations are private channels whose members never change
+ALTER TABLE chat_channels ADD COLUMN direct BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,7 @@
+CREATE TABLE chat_read_markers (
+    username TEXT NOT NULL REFERENCES chat_users (username) ON DELETE CASCADE,
+    -- '' for the default channel
+    channel TEXT NOT NULL,
+    last_read_id INTEGER NOT NULL,
+    PRIMARY KEY (username, channel)
+);
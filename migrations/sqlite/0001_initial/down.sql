@@ -0,0 +1,5 @@
+DROP TRIGGER chat_messages_fts_delete;
+DROP TRIGGER chat_messages_fts_insert;
+DROP TABLE chat_messages_fts;
+DROP TABLE chat_users;
+DROP TABLE chat_messages;
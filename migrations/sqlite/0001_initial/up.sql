@@ -0,0 +1,26 @@
+-- Same tables as the postgres ones, except for the fulltext search which is done
+-- with an external content FTS5 table kept up to date by triggers.
+CREATE TABLE IF NOT EXISTS chat_messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    author TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    content TEXT NOT NULL,
+    tags INTEGER NOT NULL DEFAULT 0,
+    color TEXT,
+    channel TEXT
+);
+CREATE INDEX IF NOT EXISTS chat_messages_timestamp_idx ON chat_messages (timestamp);
+
+CREATE TABLE IF NOT EXISTS chat_users (
+    username TEXT PRIMARY KEY,
+    password TEXT NOT NULL,
+    admin BOOLEAN NOT NULL DEFAULT 0
+);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS chat_messages_fts USING fts5(content, content='chat_messages', content_rowid='id');
+CREATE TRIGGER IF NOT EXISTS chat_messages_fts_insert AFTER INSERT ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (rowid, content) VALUES (new.id, new.content);
+END;
+CREATE TRIGGER IF NOT EXISTS chat_messages_fts_delete AFTER DELETE ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (chat_messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+END;
@@ -0,0 +1,29 @@
+DROP TABLE chat_message_revisions;
+DROP TRIGGER chat_messages_fts_update;
+DROP TRIGGER chat_messages_change_update;
+DROP TRIGGER chat_messages_change_insert;
+DROP TABLE chat_last_change;
+DROP INDEX chat_messages_change_id_idx;
+
+-- the bundled sqlite can't drop columns, so the table is rebuilt without them
+CREATE TABLE chat_messages_without_edits (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    author TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    content TEXT NOT NULL,
+    tags INTEGER NOT NULL DEFAULT 0,
+    color TEXT,
+    channel TEXT
+);
+INSERT INTO chat_messages_without_edits (id, author, timestamp, content, tags, color, channel)
+    SELECT id, author, timestamp, content, tags, color, channel FROM chat_messages;
+DROP TABLE chat_messages;
+ALTER TABLE chat_messages_without_edits RENAME TO chat_messages;
+CREATE INDEX chat_messages_timestamp_idx ON chat_messages (timestamp);
+
+CREATE TRIGGER chat_messages_fts_insert AFTER INSERT ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (rowid, content) VALUES (new.id, new.content);
+END;
+CREATE TRIGGER chat_messages_fts_delete AFTER DELETE ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (chat_messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+END;
@@ -0,0 +1,38 @@
+-- Every change of a message gives it a new change_id, which incremental queries use as their cursor,
+-- and created_change_id is the one it was sent with: the existing messages start from their id.
+-- (sqlite can't add a NOT NULL column without a default, but they are always set)
+ALTER TABLE chat_messages ADD COLUMN edited_at TEXT;
+ALTER TABLE chat_messages ADD COLUMN change_id INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE chat_messages ADD COLUMN created_change_id INTEGER NOT NULL DEFAULT 0;
+UPDATE chat_messages SET change_id = id, created_change_id = id;
+CREATE INDEX chat_messages_change_id_idx ON chat_messages (change_id);
+
+-- sqlite has no sequences: the last change_id given is kept here, so that deleting
+-- the last changed message doesn't make its change_id available again.
+-- Every change sets the change_id of the message to `(SELECT change_id + 1 FROM chat_last_change)`,
+-- which the triggers then store: as sqlite has a single writer, the changes are visible in this order
+CREATE TABLE chat_last_change (
+    change_id INTEGER NOT NULL
+);
+INSERT INTO chat_last_change (change_id) SELECT IFNULL(MAX(change_id), 0) FROM chat_messages;
+CREATE TRIGGER chat_messages_change_insert AFTER INSERT ON chat_messages BEGIN
+    UPDATE chat_last_change SET change_id = new.change_id;
+END;
+CREATE TRIGGER chat_messages_change_update AFTER UPDATE OF change_id ON chat_messages BEGIN
+    UPDATE chat_last_change SET change_id = new.change_id;
+END;
+
+CREATE TRIGGER chat_messages_fts_update AFTER UPDATE OF content ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (chat_messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+    INSERT INTO chat_messages_fts (rowid, content) VALUES (new.id, new.content);
+END;
+
+-- every content a message had before being edited
+CREATE TABLE chat_message_revisions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id INTEGER NOT NULL REFERENCES chat_messages (id) ON DELETE CASCADE,
+    content TEXT NOT NULL,
+    edited_at TEXT NOT NULL,
+    edited_by TEXT NOT NULL
+);
+CREATE INDEX chat_message_revisions_message_id_idx ON chat_message_revisions (message_id);
@@ -0,0 +1,5 @@
+-- deleted messages are kept as tombstones until they are purged,
+-- so that the clients which already have them can learn that they were deleted
+ALTER TABLE chat_messages ADD COLUMN deleted_at TEXT;
+ALTER TABLE chat_messages ADD COLUMN deleted_by TEXT;
+ALTER TABLE chat_messages ADD COLUMN delete_reason TEXT;
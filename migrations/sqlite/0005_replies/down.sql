@@ -0,0 +1,43 @@
+DROP INDEX chat_messages_reply_to_idx;
+
+-- the bundled sqlite can't drop columns, so the table is rebuilt without it
+CREATE TABLE chat_messages_without_replies (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    author TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    content TEXT NOT NULL,
+    tags INTEGER NOT NULL DEFAULT 0,
+    color TEXT,
+    channel TEXT,
+    edited_at TEXT,
+    change_id INTEGER NOT NULL DEFAULT 0,
+    created_change_id INTEGER NOT NULL DEFAULT 0,
+    deleted_at TEXT,
+    deleted_by TEXT,
+    delete_reason TEXT
+);
+INSERT INTO chat_messages_without_replies (id, author, timestamp, content, tags, color, channel, edited_at, change_id, created_change_id,
+                                           deleted_at, deleted_by, delete_reason)
+    SELECT id, author, timestamp, content, tags, color, channel, edited_at, change_id, created_change_id,
+           deleted_at, deleted_by, delete_reason FROM chat_messages;
+DROP TABLE chat_messages;
+ALTER TABLE chat_messages_without_replies RENAME TO chat_messages;
+CREATE INDEX chat_messages_timestamp_idx ON chat_messages (timestamp);
+CREATE INDEX chat_messages_change_id_idx ON chat_messages (change_id);
+
+CREATE TRIGGER chat_messages_fts_insert AFTER INSERT ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (rowid, content) VALUES (new.id, new.content);
+END;
+CREATE TRIGGER chat_messages_fts_delete AFTER DELETE ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (chat_messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+END;
+CREATE TRIGGER chat_messages_fts_update AFTER UPDATE OF content ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (chat_messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+    INSERT INTO chat_messages_fts (rowid, content) VALUES (new.id, new.content);
+END;
+CREATE TRIGGER chat_messages_change_insert AFTER INSERT ON chat_messages BEGIN
+    UPDATE chat_last_change SET change_id = new.change_id;
+END;
+CREATE TRIGGER chat_messages_change_update AFTER UPDATE OF change_id ON chat_messages BEGIN
+    UPDATE chat_last_change SET change_id = new.change_id;
+END;
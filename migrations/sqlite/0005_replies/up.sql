@@ -0,0 +1,3 @@
+-- replies always point to the root of their thread
+ALTER TABLE chat_messages ADD COLUMN reply_to INTEGER REFERENCES chat_messages (id) ON DELETE SET NULL;
+CREATE INDEX chat_messages_reply_to_idx ON chat_messages (reply_to);
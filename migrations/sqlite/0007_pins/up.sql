@@ -0,0 +1,3 @@
+ALTER TABLE chat_messages ADD COLUMN pinned_at TEXT;
+ALTER TABLE chat_messages ADD COLUMN pinned_by TEXT;
+CREATE INDEX chat_messages_pinned_idx ON chat_messages (channel) WHERE pinned_at IS NOT NULL;
@@ -0,0 +1,15 @@
+DROP TABLE chat_channel_members;
+
+-- the bundled sqlite can't drop columns, so the table is rebuilt without it
+CREATE TABLE chat_channels_without_private (
+    name TEXT PRIMARY KEY,
+    topic TEXT,
+    description TEXT,
+    creator TEXT,
+    created_at TEXT NOT NULL,
+    archived BOOLEAN NOT NULL DEFAULT 0
+);
+INSERT INTO chat_channels_without_private (name, topic, description, creator, created_at, archived)
+    SELECT name, topic, description, creator, created_at, archived FROM chat_channels;
+DROP TABLE chat_channels;
+ALTER TABLE chat_channels_without_private RENAME TO chat_channels;
@@ -0,0 +1,14 @@
+-- the bundled sqlite can't drop columns, so the table is rebuilt without it
+CREATE TABLE chat_channels_without_direct (
+    name TEXT PRIMARY KEY,
+    topic TEXT,
+    description TEXT,
+    creator TEXT,
+    created_at TEXT NOT NULL,
+    archived BOOLEAN NOT NULL DEFAULT 0,
+    private BOOLEAN NOT NULL DEFAULT 0
+);
+INSERT INTO chat_channels_without_direct (name, topic, description, creator, created_at, archived, private)
+    SELECT name, topic, description, creator, created_at, archived, private FROM chat_channels;
+DROP TABLE chat_channels;
+ALTER TABLE chat_channels_without_direct RENAME TO chat_channels;
@@ -0,0 +1,7 @@
+-- no row for the messages sent with the no_notif tag
+CREATE TABLE chat_mentions (
+    message_id INTEGER NOT NULL REFERENCES chat_messages (id) ON DELETE CASCADE,
+    username TEXT NOT NULL REFERENCES chat_users (username) ON DELETE CASCADE,
+    PRIMARY KEY (message_id, username)
+);
+CREATE INDEX chat_mentions_username_idx ON chat_mentions (username, message_id);
@@ -0,0 +1,8 @@
+-- only the users who changed their status have a row, the others are online
+CREATE TABLE chat_user_statuses (
+    username TEXT PRIMARY KEY REFERENCES chat_users (username) ON DELETE CASCADE,
+    -- online, away, dnd or invisible
+    state TEXT NOT NULL,
+    status_text TEXT,
+    status_emoji TEXT
+);
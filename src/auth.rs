@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use iron::prelude::*;
+use iron::{BeforeMiddleware,typemap};
+use iron::headers::{Authorization,Bearer};
+use chatbix::{Chatbix,ChatbixInterface};
+use user::AuthenticatedUser;
+
+use error::*;
+
+/// Request extension holding the outcome of checking the `Authorization` header.
+///
+/// It is only present if the header was sent.
+pub struct Authenticated;
+
+impl typemap::Key for Authenticated {
+    type Value = Result<AuthenticatedUser>;
+}
+
+/// Resolves `Authorization: Bearer <auth_key>` into the `Authenticated` extension.
+///
+/// Failures are not returned right away but stored in the extension, so that the routes
+/// answer them like any other error.
+pub struct AuthMiddleware<C> {
+    chatbix: Arc<Chatbix<C>>,
+}
+
+impl<C> AuthMiddleware<C> {
+    pub fn new(chatbix: Arc<Chatbix<C>>) -> AuthMiddleware<C> {
+        AuthMiddleware {
+            chatbix,
+        }
+    }
+}
+
+impl<C> BeforeMiddleware for AuthMiddleware<C> where Chatbix<C>: ChatbixInterface, C: Send + Sync + 'static {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let auth_key = match req.headers.get::<Authorization<Bearer>>() {
+            Some(&Authorization(Bearer { ref token })) => token.clone(),
+            None => return Ok(()),
+        };
+        let user = self.chatbix.authenticate(&auth_key);
+        req.extensions.insert::<Authenticated>(user);
+        Ok(())
+    }
+}
+
+/// Returns the user making the request: the one from the `Authorization` header if it was sent,
+/// otherwise the one described by the legacy `username` and `auth_key` fields,
+/// sent in the query string or in the JSON body.
+///
+/// None if there are no credentials at all.
+pub fn user<C>(req: &mut Request, chatbix: &Chatbix<C>, username: Option<&str>, auth_key: Option<&str>)
+    -> Result<Option<AuthenticatedUser>>
+    where Chatbix<C>: ChatbixInterface
+{
+    if let Some(header_user) = req.extensions.remove::<Authenticated>() {
+        return header_user.map(Some);
+    }
+    match (username, auth_key) {
+        (Some(username), Some(auth_key)) => chatbix.authenticate_as(username, auth_key).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// same as `user`, but fails with `NotLoggedIn` if there are no credentials at all
+pub fn required_user<C>(req: &mut Request, chatbix: &Chatbix<C>, username: Option<&str>, auth_key: Option<&str>)
+    -> Result<AuthenticatedUser>
+    where Chatbix<C>: ChatbixInterface
+{
+    match user(req, chatbix, username, auth_key)? {
+        Some(user) => Ok(user),
+        None => bail!(ErrorKind::NotLoggedIn),
+    }
+}
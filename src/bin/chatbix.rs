@@ -1,5 +1,29 @@
 extern crate chatbix;
 
+use std::env;
+use std::process;
+use chatbix::MigrateCommand;
+
+fn usage() -> ! {
+    println!("Usage: chatbix [--memory]");
+    println!("       chatbix migrate [up|down|status]");
+    process::exit(1)
+}
+
 fn main() {
-    chatbix::run_pg();
+    let args : Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|a| a.as_str()) {
+        None => chatbix::run(),
+        Some("--memory") => chatbix::run_memory(),
+        Some("migrate") => {
+            let command = match args.get(1).map(|a| a.as_str()) {
+                None | Some("up") => MigrateCommand::Up,
+                Some("down") => MigrateCommand::Down,
+                Some("status") => MigrateCommand::Status,
+                Some(_) => usage(),
+            };
+            chatbix::migrate(command)
+        },
+        Some(_) => usage(),
+    }
 }
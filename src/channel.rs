@@ -0,0 +1,119 @@
+use chrono::NaiveDateTime;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use super::message::Message;
+use super::utils::{timestamp_ser,optional_timestamp_ser};
+
+use error::*;
+
+/// a row of chat_channels, along with some statistics about its messages
+///
+/// the default channel (`channel: None` in messages) is not one of them
+#[derive(Debug,Serialize,Clone)]
+pub struct Channel {
+    pub name: String,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    /// None for the channels which were used before channels had to be created
+    pub creator: Option<String>,
+    #[serde(serialize_with = "timestamp_ser")]
+    pub created_at: NaiveDateTime,
+    /// archived channels are kept, but can't be posted to in strict mode
+    pub archived: bool,
+    /// only the members of private channels can see them
+    pub private: bool,
+    /// direct conversations are private channels whose members can't change,
+    /// listed by /api/dms instead of /api/channels
+    pub direct: bool,
+    /// not counting deleted messages
+    pub message_count: i64,
+    /// when the last message was sent, if any
+    #[serde(serialize_with = "optional_timestamp_ser")]
+    pub last_activity: Option<NaiveDateTime>,
+}
+
+impl Channel {
+    /// whether the user may change the channel
+    pub fn is_owner(&self, username: &str, admin: bool) -> bool {
+        admin || self.creator.as_ref().map_or(false, |creator| creator == username)
+    }
+}
+
+/// Channel names are sent as is in the query strings, and several of them can be given
+/// separated by commas (`channels=C1,C2`), so they can't contain commas nor spaces.
+///
+/// The `dm-` prefix is reserved for direct conversations.
+pub fn check_channel_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 64 || name.chars().any(|c| c == ',' || c.is_whitespace()) {
+        bail!(ErrorKind::InvalidChannelName);
+    }
+    if name.starts_with(DIRECT_PREFIX) {
+        bail!(ErrorKind::InvalidChannelName);
+    }
+    Ok(())
+}
+
+const DIRECT_PREFIX: &str = "dm-";
+
+/// including the user opening the conversation
+pub const MAX_DIRECT_PARTICIPANTS: usize = 8;
+
+/// The name of the channel of the direct conversation between these users, which must be sorted
+/// and without duplicates: opening the same conversation again gives the same channel.
+pub fn direct_channel_name(participants: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(&participants.join("\n"));
+    let mut name = DIRECT_PREFIX.to_owned();
+    name.push_str(&hasher.result_str()[..32]);
+    name
+}
+
+/// what a user hasn't read yet in a channel, their own messages excluded
+#[derive(Debug,Serialize,Clone,Default)]
+pub struct Unread {
+    pub count: i64,
+    /// the unread messages mentioning the user
+    pub mentions: i64,
+}
+
+/// a direct conversation, as listed by /api/dms
+#[derive(Debug,Serialize)]
+pub struct Conversation {
+    /// the name of the channel the messages of the conversation are sent to
+    pub channel: String,
+    /// sorted, the user asking included
+    pub participants: Vec<String>,
+    /// the last message which is not deleted, if any
+    pub last_message: Option<Message>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participants(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn direct_channel_name_is_the_same_for_the_same_participants() {
+        let name = direct_channel_name(&participants(&["alice", "bob"]));
+        assert_eq!(name, direct_channel_name(&participants(&["alice", "bob"])));
+        assert!(name.starts_with(DIRECT_PREFIX));
+        assert_eq!(name.len(), DIRECT_PREFIX.len() + 32);
+    }
+
+    #[test]
+    fn direct_channel_name_differs_for_other_participants() {
+        let name = direct_channel_name(&participants(&["alice", "bob"]));
+        assert!(name != direct_channel_name(&participants(&["alice", "carol"])));
+        assert!(name != direct_channel_name(&participants(&["alice", "bob", "carol"])));
+    }
+
+    #[test]
+    fn direct_channel_names_are_reserved() {
+        let name = direct_channel_name(&participants(&["alice", "bob"]));
+        assert!(check_channel_name(&name).is_err());
+        assert!(check_channel_name("general").is_ok());
+    }
+}
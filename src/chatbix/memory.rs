@@ -0,0 +1,625 @@
+use std::sync::{Arc,RwLock};
+use std::sync::atomic::AtomicBool;
+use std::collections::{HashMap,BTreeMap,BTreeSet};
+use std::cmp::{max,Ordering};
+use chrono::NaiveDateTime;
+use super::{Chatbix,ChatbixInterface,Interval,Match};
+use message::{NewMessage,Message,Revision,Tombstone,Reaction};
+use channel::{Channel,Unread};
+use user::{ConnectedUsers,TypingUsers,CachedUsers,StoredUser,Session,UserStatus};
+use config::Config;
+use hub::Hub;
+use utils::{now,words,Slots};
+
+use error::*;
+
+/// Storage backend keeping everything in the process' memory.
+///
+/// Nothing is persisted: everything is lost when the server stops. This is meant for tests,
+/// development and throwaway demo instances, so that a postgres instance isn't required.
+pub struct MemoryStore {
+    data: RwLock<MemoryData>,
+    /// as there is no database to make someone an admin in,
+    /// these users are admins once registered
+    admins: Vec<String>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    /// sorted by id, which is also the insertion (and thus the timestamp) order
+    messages: Vec<Message>,
+    last_id: i32,
+    /// the change_id of the last change
+    last_change_id: i64,
+    /// in the order they were made
+    revisions: Vec<Revision>,
+    /// in the order they were added
+    reactions: Vec<Reaction>,
+    /// (message id, username mentioned), in the order they were added
+    mentions: Vec<(i32, String)>,
+    /// (message id, when it was pinned), since `Message` only tells whether it is
+    pins: Vec<(i32, NaiveDateTime)>,
+    /// name -> channel, whose statistics are computed when reading it
+    channels: BTreeMap<String, Channel>,
+    /// channel name -> usernames of its members
+    channel_members: BTreeMap<String, BTreeSet<String>>,
+    /// (username, channel or '' for the default one) -> id of the last message read
+    read_markers: HashMap<(String, String), i32>,
+    users: HashMap<String, StoredUser>,
+    /// username -> the status they chose
+    statuses: HashMap<String, UserStatus>,
+    /// key_hash -> session
+    sessions: HashMap<String, Session>,
+    /// word -> (message id -> number of occurences of the word in the message)
+    fulltext_index: HashMap<String, HashMap<i32, u32>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            data: RwLock::new(MemoryData::default()),
+            admins: Vec::new(),
+        }
+    }
+
+    pub fn with_admins(self, admins: Vec<String>) -> MemoryStore {
+        MemoryStore {
+            admins,
+            ..self
+        }
+    }
+}
+
+fn in_channels(channel: &Option<String>, channels: &[String], include_default_channel: bool) -> bool {
+    match *channel {
+        None => include_default_channel,
+        Some(ref c) => channels.contains(c),
+    }
+}
+
+impl MemoryData {
+    fn next_change_id(&mut self) -> i64 {
+        self.last_change_id += 1;
+        self.last_change_id
+    }
+
+    /// the channel along with its statistics
+    fn channel_with_stats(&self, channel: &Channel) -> Channel {
+        let messages = self.messages.iter()
+            .filter(|m| m.deleted.is_none() && m.channel.as_ref() == Some(&channel.name));
+        let (message_count, last_activity) = messages.fold((0, None), |(count, _), m| (count + 1, Some(m.timestamp)));
+        Channel {
+            message_count,
+            last_activity,
+            ..channel.clone()
+        }
+    }
+
+    /// gives the message a new change_id, so that incremental queries return it again
+    fn touch_message(&mut self, id: i32) {
+        let change_id = self.next_change_id();
+        if let Ok(position) = self.messages.binary_search_by_key(&id, |m| m.id) {
+            self.messages[position].change_id = change_id;
+        }
+    }
+
+    fn index_message(&mut self, id: i32, content: &str) {
+        for word in words(content) {
+            *self.fulltext_index.entry(word).or_default().entry(id).or_insert(0) += 1;
+        }
+    }
+
+    fn unindex_message(&mut self, id: i32, content: &str) {
+        for word in words(content) {
+            let now_empty = match self.fulltext_index.get_mut(&word) {
+                Some(ids) => {
+                    ids.remove(&id);
+                    ids.is_empty()
+                },
+                None => false,
+            };
+            if now_empty {
+                self.fulltext_index.remove(&word);
+            }
+        }
+    }
+}
+
+impl ChatbixInterface for Chatbix<MemoryStore> {
+    type InitParams = MemoryStore;
+
+    fn new(init_params: Self::InitParams) -> Chatbix<MemoryStore> {
+        Chatbix {
+            connection: init_params,
+            connected_users: RwLock::new(ConnectedUsers::new()),
+            typing_users: RwLock::new(TypingUsers::new()),
+            cached_users: RwLock::new(CachedUsers::new()),
+            hub: Hub::new(),
+            config: Config::default(),
+            waiting: Slots::new(Config::default().max_waiting),
+            notifications_url: None,
+            listening: AtomicBool::new(false),
+        }
+    }
+
+    fn new_message(&self, author: &str, tags: i32, reply_to: Option<i32>, new_message: &NewMessage) -> Result<Message> {
+        let timestamp = now();
+        let mut data = self.connection.data.write().unwrap();
+        data.last_id += 1;
+        let id = data.last_id;
+        data.index_message(id, &new_message.content);
+        let change_id = data.next_change_id();
+        let message = Message {
+            id,
+            author: author.to_owned(),
+            timestamp,
+            content: new_message.content.clone(),
+            tags,
+            color: new_message.color.clone(),
+            channel: new_message.channel.clone(),
+            reply_to,
+            reply_count: 0,
+            reactions: Vec::new(),
+            pinned: false,
+            edited_at: None,
+            change_id,
+            created_change_id: change_id,
+            deleted: None,
+        };
+        data.messages.push(message.clone());
+        if let Some(reply_to) = reply_to {
+            // so that the new reply count reaches the polling clients
+            data.touch_message(reply_to);
+        }
+        Ok(message)
+    }
+
+    fn get_message(&self, id: i32) -> Result<Option<Message>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.messages.binary_search_by_key(&id, |m| m.id).ok().map(|position| data.messages[position].clone()))
+    }
+
+    fn add_mentions(&self, message_id: i32, usernames: &[String]) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        for username in usernames {
+            if !data.mentions.iter().any(|&(id, ref u)| id == message_id && u == username) {
+                data.mentions.push((message_id, username.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_mentions(&self, username: &str, before: Option<i32>, limit: i64, hidden_channels: &[String]) -> Result<Vec<Message>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.messages.iter().rev()
+            .filter(|m| before.map_or(true, |before| m.id < before))
+            .filter(|m| m.deleted.is_none() && !m.channel.as_ref().map_or(false, |channel| hidden_channels.contains(channel)))
+            .filter(|m| data.mentions.iter().any(|&(id, ref u)| id == m.id && u == username))
+            .take(max(limit, 0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn edit_message(&self, id: i32, content: &str, edited_by: &str) -> Result<Message> {
+        let edited_at = now();
+        let mut data = self.connection.data.write().unwrap();
+        let position = match data.messages.binary_search_by_key(&id, |m| m.id) {
+            Ok(position) => position,
+            Err(_) => bail!(ErrorKind::UnknownMessage),
+        };
+        let previous_content = data.messages[position].content.clone();
+        data.unindex_message(id, &previous_content);
+        data.index_message(id, content);
+        data.revisions.push(Revision {
+            message_id: id,
+            content: previous_content,
+            edited_at,
+            edited_by: edited_by.to_owned(),
+        });
+        let change_id = data.next_change_id();
+        let message = &mut data.messages[position];
+        message.content = content.to_owned();
+        message.edited_at = Some(edited_at);
+        message.change_id = change_id;
+        Ok(message.clone())
+    }
+
+    fn get_thread(&self, root_id: i32) -> Result<Vec<Message>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.messages.iter()
+           .filter(|m| (m.id == root_id || m.reply_to == Some(root_id)) && m.deleted.is_none())
+           .cloned()
+           .collect())
+    }
+
+    fn get_reply_counts(&self, ids: &[i32]) -> Result<HashMap<i32, i64>> {
+        let data = self.connection.data.read().unwrap();
+        let mut reply_counts = HashMap::new();
+        for message in data.messages.iter().filter(|m| m.deleted.is_none()) {
+            if let Some(reply_to) = message.reply_to {
+                if ids.contains(&reply_to) {
+                    *reply_counts.entry(reply_to).or_insert(0) += 1;
+                }
+            }
+        }
+        Ok(reply_counts)
+    }
+
+    fn add_reaction(&self, message_id: i32, username: &str, emoji: &str) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        let exists = data.reactions.iter()
+            .any(|r| r.message_id == message_id && r.username == username && r.emoji == emoji);
+        if !exists {
+            data.reactions.push(Reaction {
+                message_id,
+                username: username.to_owned(),
+                emoji: emoji.to_owned(),
+            });
+            // so that the reaction reaches the polling clients
+            data.touch_message(message_id);
+        }
+        Ok(())
+    }
+
+    fn remove_reaction(&self, message_id: i32, username: &str, emoji: &str) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        let count = data.reactions.len();
+        data.reactions.retain(|r| !(r.message_id == message_id && r.username == username && r.emoji == emoji));
+        if data.reactions.len() != count {
+            data.touch_message(message_id);
+        }
+        Ok(())
+    }
+
+    fn get_reactions(&self, ids: &[i32]) -> Result<Vec<Reaction>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.reactions.iter().filter(|r| ids.contains(&r.message_id)).cloned().collect())
+    }
+
+    fn set_pinned(&self, message_id: i32, pinned_by: Option<&str>) -> Result<()> {
+        let timestamp = now();
+        let mut data = self.connection.data.write().unwrap();
+        data.pins.retain(|&(id, _)| id != message_id);
+        if pinned_by.is_some() {
+            data.pins.push((message_id, timestamp));
+        }
+        if let Ok(position) = data.messages.binary_search_by_key(&message_id, |m| m.id) {
+            data.messages[position].pinned = pinned_by.is_some();
+        }
+        // so that the pin reaches the polling clients
+        data.touch_message(message_id);
+        Ok(())
+    }
+
+    fn get_pins(&self, channel: Option<&str>) -> Result<Vec<Message>> {
+        let data = self.connection.data.read().unwrap();
+        // the most recently pinned are at the end
+        Ok(data.pins.iter().rev().filter_map(|&(id, _)| {
+            data.messages.binary_search_by_key(&id, |m| m.id).ok().map(|position| &data.messages[position])
+        }).filter(|m| m.deleted.is_none() && m.channel.as_deref() == channel).cloned().collect())
+    }
+
+    fn create_channel(&self, channel: &Channel) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        if data.channels.contains_key(&channel.name) {
+            bail!(ErrorKind::ChannelNameInUse);
+        }
+        data.channels.insert(channel.name.clone(), channel.clone());
+        Ok(())
+    }
+
+    fn update_channel(&self, channel: &Channel) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        if let Some(stored) = data.channels.get_mut(&channel.name) {
+            stored.topic = channel.topic.clone();
+            stored.description = channel.description.clone();
+            stored.archived = channel.archived;
+            stored.private = channel.private;
+        }
+        Ok(())
+    }
+
+    fn add_channel_member(&self, channel: &str, username: &str) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        data.channel_members.entry(channel.to_owned()).or_default().insert(username.to_owned());
+        Ok(())
+    }
+
+    fn remove_channel_member(&self, channel: &str, username: &str) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        if let Some(members) = data.channel_members.get_mut(channel) {
+            members.remove(username);
+        }
+        Ok(())
+    }
+
+    fn get_channel_members(&self, channel: &str) -> Result<Vec<String>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.channel_members.get(channel).map_or_else(Vec::new, |members| members.iter().cloned().collect()))
+    }
+
+    fn get_hidden_channels(&self, username: Option<&str>) -> Result<Vec<String>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.channels.values().filter(|channel| {
+            channel.private && !username.map_or(false, |username| {
+                data.channel_members.get(&channel.name).map_or(false, |members| members.contains(username))
+            })
+        }).map(|channel| channel.name.clone()).collect())
+    }
+
+    fn get_channel(&self, name: &str) -> Result<Option<Channel>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.channels.get(name).map(|channel| data.channel_with_stats(channel)))
+    }
+
+    fn get_channels(&self) -> Result<Vec<Channel>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.channels.values().map(|channel| data.channel_with_stats(channel)).collect())
+    }
+
+    fn get_direct_channels(&self, username: &str) -> Result<Vec<Channel>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.channels.values().filter(|channel| {
+            channel.direct && data.channel_members.get(&channel.name).map_or(false, |members| members.contains(username))
+        }).map(|channel| data.channel_with_stats(channel)).collect())
+    }
+
+    fn get_last_messages(&self, channels: &[String]) -> Result<Vec<Message>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(channels.iter().filter_map(|channel| {
+            data.messages.iter().rev().find(|m| m.deleted.is_none() && m.channel.as_ref() == Some(channel)).cloned()
+        }).collect())
+    }
+
+    fn set_read_marker(&self, username: &str, channel: &str, message_id: i32) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        let last_read_id = data.read_markers.entry((username.to_owned(), channel.to_owned())).or_insert(message_id);
+        *last_read_id = max(*last_read_id, message_id);
+        Ok(())
+    }
+
+    fn get_unread(&self, username: &str, channels: &[String], include_default_channel: bool) -> Result<HashMap<String, Unread>> {
+        let data = self.connection.data.read().unwrap();
+        let mut unread : HashMap<String, Unread> = HashMap::new();
+        let messages = data.messages.iter()
+            .filter(|m| m.deleted.is_none() && m.author != username && in_channels(&m.channel, channels, include_default_channel));
+        for message in messages {
+            let channel = message.channel.clone().unwrap_or_default();
+            let last_read_id = data.read_markers.get(&(username.to_owned(), channel.clone())).cloned().unwrap_or(0);
+            if message.id <= last_read_id {
+                continue;
+            }
+            let unread = unread.entry(channel).or_default();
+            unread.count += 1;
+            if data.mentions.iter().any(|&(id, ref u)| id == message.id && u == username) {
+                unread.mentions += 1;
+            }
+        }
+        Ok(unread)
+    }
+
+    fn get_revisions(&self, message_id: i32) -> Result<Vec<Revision>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.revisions.iter().filter(|r| r.message_id == message_id).cloned().collect())
+    }
+
+    fn delete_message(&self, id: i32, deleted_by: &str, reason: Option<&str>) -> Result<()> {
+        let deleted_at = now();
+        let mut data = self.connection.data.write().unwrap();
+        let position = match data.messages.binary_search_by_key(&id, |m| m.id) {
+            Ok(position) if data.messages[position].deleted.is_none() => position,
+            _ => bail!(ErrorKind::UnknownMessage),
+        };
+        let content = data.messages[position].content.clone();
+        data.unindex_message(id, &content);
+        let change_id = data.next_change_id();
+        let reply_to = {
+            let message = &mut data.messages[position];
+            message.change_id = change_id;
+            message.deleted = Some(Tombstone {
+                id,
+                deleted_by: deleted_by.to_owned(),
+                deleted_at,
+                reason: reason.map(|r| r.to_owned()),
+            });
+            message.reply_to
+        };
+        if let Some(reply_to) = reply_to {
+            // the reply count of the root changed
+            data.touch_message(reply_to);
+        }
+        Ok(())
+    }
+
+    fn purge_message(&self, id: i32) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        let position = match data.messages.binary_search_by_key(&id, |m| m.id) {
+            Ok(position) => position,
+            Err(_) => bail!(ErrorKind::UnknownMessage),
+        };
+        let message = data.messages.remove(position);
+        data.unindex_message(id, &message.content);
+        data.revisions.retain(|r| r.message_id != id);
+        data.reactions.retain(|r| r.message_id != id);
+        data.mentions.retain(|&(message_id, _)| message_id != id);
+        data.pins.retain(|&(message_id, _)| message_id != id);
+        // same as ON DELETE SET NULL
+        for reply in data.messages.iter_mut().filter(|m| m.reply_to == Some(id)) {
+            reply.reply_to = None;
+        }
+        Ok(())
+    }
+
+    fn get_messages<V: AsRef<[String]>>(&self, interval: Interval, channels: V, include_default_channel: bool) -> Result<Vec<Message>> {
+        let channels = channels.as_ref();
+        let data = self.connection.data.read().unwrap();
+        let messages = data.messages.iter()
+            .filter(|m| in_channels(&m.channel, channels, include_default_channel));
+        let messages : Vec<Message> = match interval {
+            Interval::Last(last) => {
+                let mut messages = messages.filter(|m| m.deleted.is_none()).rev()
+                    .take(max(last, 0) as usize)
+                    .cloned()
+                    .collect::<Vec<Message>>();
+                messages.reverse();
+                messages
+            },
+            Interval::AllFromTimestamp(timestamp) => {
+                let since = data.messages.iter().filter(|m| m.timestamp <= timestamp).map(|m| m.created_change_id).max();
+                messages.filter(|m| m.timestamp > timestamp || since.is_some_and(|since| m.change_id > since)).cloned().collect()
+            },
+            Interval::FromToTimestamp(timestamp, timestamp_end) =>
+                messages.filter(|m| m.deleted.is_none() && m.timestamp > timestamp && m.timestamp < timestamp_end).cloned().collect(),
+            Interval::AllFromId(id) => {
+                let since = data.messages.binary_search_by_key(&id, |m| m.id).ok().map(|position| data.messages[position].created_change_id);
+                messages.filter(|m| m.id > id || since.is_some_and(|since| m.change_id > since)).cloned().collect()
+            },
+            Interval::AllFromChange(change_id) =>
+                messages.filter(|m| m.change_id > change_id).cloned().collect(),
+        };
+        Ok(messages)
+    }
+
+    fn last_change_id(&self) -> Result<i64> {
+        Ok(self.connection.data.read().unwrap().last_change_id)
+    }
+
+    fn create_user(&self, username: &str, password_hash: &str) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        if data.users.contains_key(username) {
+            bail!(ErrorKind::UsernameInUse);
+        }
+        data.users.insert(username.to_owned(), StoredUser {
+            password: password_hash.to_owned(),
+            admin: self.connection.admins.iter().any(|admin| admin == username),
+        });
+        Ok(())
+    }
+
+    fn get_user(&self, username: &str) -> Result<Option<StoredUser>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.users.get(username).map(|user| StoredUser {
+            password: user.password.clone(),
+            admin: user.admin,
+        }))
+    }
+
+    fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        if let Some(user) = data.users.get_mut(username) {
+            user.password = password_hash.to_owned();
+        };
+        Ok(())
+    }
+
+    fn set_user_status(&self, username: &str, status: &UserStatus) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        data.statuses.insert(username.to_owned(), status.clone());
+        Ok(())
+    }
+
+    fn get_user_status(&self, username: &str) -> Result<Option<UserStatus>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.statuses.get(username).cloned())
+    }
+
+    fn store_session(&self, session: &Session) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        data.sessions.insert(session.key_hash.clone(), session.clone());
+        Ok(())
+    }
+
+    fn load_session(&self, key_hash: &str) -> Result<Option<(Session, bool)>> {
+        let data = self.connection.data.read().unwrap();
+        Ok(data.sessions.get(key_hash).and_then(|session| {
+            data.users.get(&session.username).map(|user| (session.clone(), user.admin))
+        }))
+    }
+
+    fn touch_session(&self, key_hash: &str, last_used: NaiveDateTime) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        if let Some(session) = data.sessions.get_mut(key_hash) {
+            session.last_used = last_used;
+        };
+        Ok(())
+    }
+
+    fn delete_session(&self, key_hash: &str) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        data.sessions.remove(key_hash);
+        Ok(())
+    }
+
+    fn get_sessions(&self, username: &str) -> Result<Vec<Session>> {
+        let data = self.connection.data.read().unwrap();
+        let mut sessions = data.sessions.values()
+            .filter(|session| session.username == username)
+            .cloned()
+            .collect::<Vec<Session>>();
+        sessions.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+        Ok(sessions)
+    }
+
+    fn delete_other_sessions(&self, username: &str, key_hash: &str) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        data.sessions.retain(|k, session| session.username != username || k == key_hash);
+        Ok(())
+    }
+
+    fn delete_expired_sessions(&self, created_before: NaiveDateTime, used_before: NaiveDateTime) -> Result<()> {
+        let mut data = self.connection.data.write().unwrap();
+        data.sessions.retain(|_, session| session.created_at >= created_before && session.last_used >= used_before);
+        Ok(())
+    }
+
+    /// there is only ever one instance using the memory
+    fn notify_message(&self, _message_id: i32) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn listen_notifications(_chatbix: Arc<Self>) {
+    }
+
+    /// Every word of the query must be present in the message for it to match,
+    /// operators like `&` or `|` are simply ignored.
+    ///
+    /// The rank is the number of occurences of the query words in the message,
+    /// divided by the number of words of the message.
+    fn fulltext_search(&self, query: &str, limit: i64, hidden_channels: &[String]) -> Result<Vec<Match>> {
+        let limit = max(limit, 0) as usize;
+        let query = words(query);
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+        let data = self.connection.data.read().unwrap();
+        let mut occurences : HashMap<i32, u32> = match data.fulltext_index.get(&query[0]) {
+            Some(ids) => ids.clone(),
+            None => return Ok(Vec::new()),
+        };
+        for word in &query[1..] {
+            let ids = match data.fulltext_index.get(word) {
+                Some(ids) => ids,
+                None => return Ok(Vec::new()),
+            };
+            occurences = occurences.into_iter()
+                .filter_map(|(id, count)| ids.get(&id).map(|c| (id, count + c)))
+                .collect();
+        }
+        let mut matches = occurences.into_iter().filter_map(|(id, count)| {
+            data.messages.binary_search_by_key(&id, |m| m.id).ok().and_then(|position| {
+                let message = &data.messages[position];
+                if message.channel.as_ref().map_or(false, |channel| hidden_channels.contains(channel)) {
+                    return None;
+                }
+                Some(Match {
+                    user: message.author.clone(),
+                    message: message.content.clone(),
+                    rank: count as f32 / max(words(&message.content).len(), 1) as f32,
+                })
+            })
+        }).collect::<Vec<Match>>();
+        matches.sort_by(|a, b| b.rank.partial_cmp(&a.rank).unwrap_or(Ordering::Equal));
+        matches.truncate(limit);
+        Ok(matches)
+    }
+}
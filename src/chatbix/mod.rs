@@ -0,0 +1,1841 @@
+use std::sync::{Arc,RwLock};
+use std::sync::mpsc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration as StdDuration;
+use std::collections::HashMap;
+use super::message::{NewMessage,Message,Revision,Tombstone,Reaction,ReactionCount,parse_mentions,check_emoji};
+use super::user::{ConnectedUser,ConnectedUsers,UserStatus,PresenceState,MAX_STATUS_TEXT_LENGTH,Typing,TypingUsers,CachedUser,CachedUsers,AuthenticatedUser,StoredUser,Session,SessionInfo};
+use super::user::{new_auth_key,hash_auth_key};
+use super::config::{Config,AnonymousNames};
+use super::channel::{Channel,Conversation,Unread,check_channel_name,direct_channel_name,MAX_DIRECT_PARTICIPANTS};
+use super::password::{self,Verification};
+use super::hub::{Hub,Event,Published};
+use super::utils::{now,Slots,Slot};
+use chrono::NaiveDateTime;
+
+use error::*;
+
+mod memory;
+#[cfg(feature = "postgres")]
+mod pg;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use self::memory::MemoryStore;
+#[cfg(feature = "postgres")]
+pub use self::pg::PgPool;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::{SqlitePool,SqliteConnectionManager};
+
+#[derive(Clone, Copy)]
+pub enum Interval {
+    /// the messages sent after this one, and the ones changed since it was sent
+    AllFromId(i32),
+    /// the messages sent or changed after this change, see `Message::change_id`
+    AllFromChange(i64),
+    /// the messages sent after this timestamp, and the ones changed since
+    /// the last message sent by then
+    AllFromTimestamp(NaiveDateTime),
+    FromToTimestamp(NaiveDateTime, NaiveDateTime),
+    Last(i64),
+}
+
+impl Default for Interval {
+    fn default() -> Interval {
+        Interval::Last(150)
+    }
+}
+
+pub trait ChatbixInterface {
+    type InitParams;
+    fn new(init_params: Self::InitParams) -> Self;
+
+    /// deleted messages are only returned for the incremental intervals (AllFromId, AllFromChange and AllFromTimestamp),
+    /// so that their tombstones can be sent
+    fn get_messages<V: AsRef<[String]>>(&self, interval: Interval, channels: V, include_default_channel: bool) -> Result<Vec<Message>>;
+
+    /// the change_id of the last change of any message, 0 if there never was any.
+    ///
+    /// Every change up to this one is visible to the queries made afterwards.
+    fn last_change_id(&self) -> Result<i64>;
+
+    /// stores the message as is: the author, the tags and the root of the thread have already been checked,
+    /// and returns it as it was stored
+    fn new_message(&self, author: &str, tags: i32, reply_to: Option<i32>, new_message: &NewMessage) -> Result<Message>;
+
+    fn get_message(&self, id: i32) -> Result<Option<Message>>;
+
+    /// records that the message mentions these users
+    fn add_mentions(&self, message_id: i32, usernames: &[String]) -> Result<()>;
+
+    /// the messages mentioning the user, the most recent first, at most `limit` of them
+    /// sent before message `before` if given, deleted messages and `hidden_channels` excluded
+    fn get_mentions(&self, username: &str, before: Option<i32>, limit: i64, hidden_channels: &[String]) -> Result<Vec<Message>>;
+
+    /// replaces the content of the message, keeping the previous one as a revision,
+    /// and returns the edited message
+    /// You should probably use edit instead if coming from a user
+    fn edit_message(&self, id: i32, content: &str, edited_by: &str) -> Result<Message>;
+
+    /// the root and its replies, deleted messages excluded, the oldest first
+    fn get_thread(&self, root_id: i32) -> Result<Vec<Message>>;
+
+    /// the number of replies to each of these messages, deleted replies excluded
+    /// the messages without replies may be missing from the map
+    fn get_reply_counts(&self, ids: &[i32]) -> Result<HashMap<i32, i64>>;
+
+    /// adds the reaction if it isn't there yet
+    fn add_reaction(&self, message_id: i32, username: &str, emoji: &str) -> Result<()>;
+
+    /// removes the reaction if it is there
+    fn remove_reaction(&self, message_id: i32, username: &str, emoji: &str) -> Result<()>;
+
+    /// the reactions to these messages, the oldest first
+    fn get_reactions(&self, ids: &[i32]) -> Result<Vec<Reaction>>;
+
+    /// pins the message in its channel, or unpins it if `pinned_by` is None
+    fn set_pinned(&self, message_id: i32, pinned_by: Option<&str>) -> Result<()>;
+
+    /// the pinned messages of the channel (the default one if None), the most recently pinned first
+    fn get_pins(&self, channel: Option<&str>) -> Result<Vec<Message>>;
+
+    /// stores a new channel, whose statistics are ignored
+    /// fails with ChannelNameInUse if there is already a channel with this name
+    fn create_channel(&self, channel: &Channel) -> Result<()>;
+
+    /// stores the topic, description, archived and private flags of the channel
+    fn update_channel(&self, channel: &Channel) -> Result<()>;
+
+    /// adds the user to the members of the channel, if they aren't one already
+    fn add_channel_member(&self, channel: &str, username: &str) -> Result<()>;
+
+    /// removes the user from the members of the channel, if they are one
+    fn remove_channel_member(&self, channel: &str, username: &str) -> Result<()>;
+
+    /// the usernames of the members of the channel, sorted
+    fn get_channel_members(&self, channel: &str) -> Result<Vec<String>>;
+
+    /// the private channels `username` is not a member of (all of them if None)
+    fn get_hidden_channels(&self, username: Option<&str>) -> Result<Vec<String>>;
+
+    fn get_channel(&self, name: &str) -> Result<Option<Channel>>;
+
+    /// every channel, sorted by name
+    fn get_channels(&self) -> Result<Vec<Channel>>;
+
+    /// the direct conversations the user is a member of
+    fn get_direct_channels(&self, username: &str) -> Result<Vec<Channel>>;
+
+    /// the last message of each of these channels, deleted messages excluded
+    /// the channels without messages are missing
+    fn get_last_messages(&self, channels: &[String]) -> Result<Vec<Message>>;
+
+    /// moves the read marker of the user in the channel ('' for the default one) to `message_id`,
+    /// unless it is already past it
+    fn set_read_marker(&self, username: &str, channel: &str, message_id: i32) -> Result<()>;
+
+    /// what the user hasn't read in these channels, keyed by channel ('' for the default one),
+    /// the mentions being the ones recorded by add_mentions
+    /// the channels without unread messages may be missing from the map
+    fn get_unread(&self, username: &str, channels: &[String], include_default_channel: bool) -> Result<HashMap<String, Unread>>;
+
+    /// the previous contents of the message, the oldest first
+    fn get_revisions(&self, message_id: i32) -> Result<Vec<Revision>>;
+
+    /// forces message deletion, leaving a tombstone
+    /// fails with UnknownMessage if there is no such message, or if it is already deleted
+    /// You should probably use try_del instead if coming from a user
+    fn delete_message(&self, id: i32, deleted_by: &str, reason: Option<&str>) -> Result<()>;
+
+    /// really removes the message from the database, deleted or not, along with its revisions
+    fn purge_message(&self, id: i32) -> Result<()>;
+
+    /// stores a new user, with an already hashed password
+    /// fails with UsernameInUse if the username is already taken
+    fn create_user(&self, username: &str, password_hash: &str) -> Result<()>;
+
+    fn get_user(&self, username: &str) -> Result<Option<StoredUser>>;
+
+    /// replaces the stored password hash of a user
+    fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<()>;
+
+    /// replaces the stored status of a registered user
+    fn set_user_status(&self, username: &str, status: &UserStatus) -> Result<()>;
+
+    /// None if the user never changed their status
+    fn get_user_status(&self, username: &str) -> Result<Option<UserStatus>>;
+
+    fn store_session(&self, session: &Session) -> Result<()>;
+
+    /// returns the session along with whether its user is an admin
+    fn load_session(&self, key_hash: &str) -> Result<Option<(Session, bool)>>;
+
+    /// updates the last_used time of the session
+    fn touch_session(&self, key_hash: &str, last_used: NaiveDateTime) -> Result<()>;
+
+    fn delete_session(&self, key_hash: &str) -> Result<()>;
+
+    /// returns every session of the user, the most recently used first
+    fn get_sessions(&self, username: &str) -> Result<Vec<Session>>;
+
+    /// deletes every session of the user except the one with this key_hash
+    fn delete_other_sessions(&self, username: &str, key_hash: &str) -> Result<()>;
+
+    /// deletes the sessions created before `created_before` or last used before `used_before`
+    fn delete_expired_sessions(&self, created_before: NaiveDateTime, used_before: NaiveDateTime) -> Result<()>;
+
+    /// Tells every instance sharing the database, this one included, that the message is new, changed
+    /// or was deleted, so that they publish it by calling `publish_notified`.
+    /// Returns false if the database can't do that, in which case the caller publishes it itself.
+    fn notify_message(&self, message_id: i32) -> Result<bool>;
+
+    /// starts receiving the notifications of `notify_message`, if the database can send them
+    fn listen_notifications(chatbix: Arc<Self>) where Self: Sized;
+
+    /// Do a fulltext search on all the messages, except the ones sent to `hidden_channels`
+    fn fulltext_search(&self, query: &str, limit: i64, hidden_channels: &[String]) -> Result<Vec<Match>>;
+}
+
+#[derive(Debug, Serialize)]
+pub struct Match {
+    pub user: String,
+    pub message: String,
+    pub rank: f32,
+}
+
+pub struct Chatbix<Connection> {
+    connection: Connection,
+    connected_users: RwLock<ConnectedUsers>,
+    typing_users: RwLock<TypingUsers>,
+    cached_users: RwLock<CachedUsers>,
+    config: Config,
+    hub: Hub,
+    /// the slots of the event streams and long polls, see `Config::max_waiting`
+    waiting: Slots,
+    /// where the notifications of `notify_message` are received from, see `listen_notifications`
+    #[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+    notifications_url: Option<String>,
+    /// whether these notifications are being received
+    #[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+    listening: AtomicBool,
+}
+
+impl<C> Chatbix<C> {
+    pub fn with_config(mut self, config: Config) -> Chatbix<C> {
+        self.waiting = Slots::new(config.max_waiting);
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Takes the slot of an event stream or a long poll, which is freed when the `Slot` is dropped
+    pub fn waiting_slot(&self) -> Result<Slot> {
+        match self.waiting.take() {
+            Some(slot) => Ok(slot),
+            None => bail!(ErrorKind::TooManyWaiting),
+        }
+    }
+
+    pub fn refresh_users(&self) {
+        let mut connected_users = self.connected_users.write().unwrap();
+        if connected_users.refresh() {
+            self.publish_presence(connected_users.as_vec());
+        }
+    }
+
+    /// where new messages, deletions and presence changes are published
+    pub fn hub(&self) -> &Hub {
+        &self.hub
+    }
+
+    fn publish_presence(&self, users: Vec<ConnectedUser>) {
+        self.hub.publish(Published {
+            event: Event::Presence(users),
+            audience: None,
+        });
+    }
+
+    /// forgets the sessions cached for too long, so that they are read again from the database
+    pub fn refresh_cached_users(&self) {
+        self.cached_users.write().unwrap().refresh(now(), &self.config.sessions);
+    }
+}
+
+impl Interval {
+    /// whether the interval is used to poll for what happened since the last query
+    pub fn is_incremental(&self) -> bool {
+        match *self {
+            Interval::AllFromId(_) | Interval::AllFromChange(_) | Interval::AllFromTimestamp(_) => true,
+            Interval::FromToTimestamp(_, _) | Interval::Last(_) => false,
+        }
+    }
+}
+
+impl<C> Chatbix<C> where Chatbix<C>:ChatbixInterface {
+    /// returns some auth_key
+    pub fn register(&self, username: &str, password: &str, user_agent: Option<&str>) -> Result<String> {
+        let password_hash = password::hash(password, &self.config.password);
+        self.create_user(username, &password_hash)?;
+        // the memory backend makes admins of some new users
+        let admin = self.get_user(username)?.is_some_and(|user| user.admin);
+        self.open_session(username, admin, user_agent)
+    }
+
+    /// return auth_key
+    ///
+    /// passwords stored with an outdated scheme or cost are hashed again on the fly
+    pub fn login(&self, username: &str, password: &str, user_agent: Option<&str>) -> Result<String> {
+        let user = match self.get_user(username)? {
+            Some(user) => user,
+            None => bail!(ErrorKind::InvalidCredentials),
+        };
+        match password::verify(password, &user.password, &self.config.password) {
+            Verification::Invalid => bail!(ErrorKind::InvalidCredentials),
+            Verification::Valid => {},
+            Verification::NeedsRehash => {
+                let password_hash = password::hash(password, &self.config.password);
+                self.set_password_hash(username, &password_hash)?;
+            },
+        };
+        self.open_session(username, user.admin, user_agent)
+    }
+
+    pub fn logout(&self, user: &AuthenticatedUser) -> Result<()> {
+        let key_hash = hash_auth_key(&user.auth_key);
+        self.cached_users.write().unwrap().revoke(&key_hash);
+        self.delete_session(&key_hash)
+    }
+
+    /// lists the sessions of the user
+    pub fn sessions(&self, user: &AuthenticatedUser) -> Result<Vec<SessionInfo>> {
+        let current = hash_auth_key(&user.auth_key);
+        let sessions = self.get_sessions(&user.username)?;
+        Ok(sessions.into_iter().map(|session| SessionInfo {
+            current: session.key_hash == current,
+            id: session.key_hash,
+            created_at: session.created_at,
+            last_used: session.last_used,
+            user_agent: session.user_agent,
+        }).collect())
+    }
+
+    /// revokes one session of the user, `session_id` being the id returned by `sessions`
+    pub fn revoke_session(&self, user: &AuthenticatedUser, session_id: &str) -> Result<()> {
+        let sessions = self.get_sessions(&user.username)?;
+        if !sessions.iter().any(|session| session.key_hash == session_id) {
+            bail!(ErrorKind::UnknownSession);
+        }
+        self.cached_users.write().unwrap().revoke(session_id);
+        self.delete_session(session_id)
+    }
+
+    /// revokes every session of the user except the one making the request
+    pub fn revoke_other_sessions(&self, user: &AuthenticatedUser) -> Result<()> {
+        self.cached_users.write().unwrap().revoke_others(&user.username, &user.auth_key);
+        self.delete_other_sessions(&user.username, &hash_auth_key(&user.auth_key))
+    }
+
+    /// replaces the auth_key by a new one, with a new session
+    pub fn refresh_session(&self, user: &AuthenticatedUser, user_agent: Option<&str>) -> Result<String> {
+        let refreshed_auth_key = self.open_session(&user.username, user.admin, user_agent)?;
+        self.logout(user)?;
+        Ok(refreshed_auth_key)
+    }
+
+    /// creates (and stores) a new session, every login having its own
+    fn open_session(&self, username: &str, admin: bool, user_agent: Option<&str>) -> Result<String> {
+        let auth_key = new_auth_key();
+        let key_hash = hash_auth_key(&auth_key);
+        let now = now();
+        // nothing else removes the sessions that are never used again
+        self.delete_expired_sessions(now - self.config.sessions.max_age, now - self.config.sessions.idle_timeout)?;
+        self.store_session(&Session {
+            key_hash: key_hash.clone(),
+            username: username.to_owned(),
+            created_at: now,
+            last_used: now,
+            user_agent: user_agent.map(|u| u.to_owned()),
+        })?;
+        let mut cached_users = self.cached_users.write().unwrap();
+        cached_users.insert(&auth_key, CachedUser {
+            username: username.to_owned(),
+            key_hash,
+            admin,
+            created_at: now,
+            last_used: now,
+            cached_at: now,
+        });
+        Ok(auth_key)
+    }
+
+    /// Returns the user this auth_key belongs to.
+    ///
+    /// The cache is checked first, and then the database:
+    /// sessions survive restarts, while the cache does not,
+    /// and the cache only keeps them for a short while.
+    /// Expired sessions are deleted on the spot.
+    pub fn authenticate(&self, auth_key: &str) -> Result<AuthenticatedUser> {
+        let now = now();
+        let limits = &self.config.sessions;
+        let cached_user = self.cached_users.read().unwrap().get(auth_key, now, limits).cloned();
+        let cached_user = match cached_user {
+            Some(cached_user) => {
+                if limits.expired(cached_user.created_at, cached_user.last_used, now) {
+                    self.cached_users.write().unwrap().revoke(&cached_user.key_hash);
+                    self.delete_session(&cached_user.key_hash)?;
+                    bail!(ErrorKind::SessionExpired);
+                }
+                let persist = self.cached_users.write().unwrap().touch(auth_key, now, limits);
+                if persist {
+                    self.touch_session(&cached_user.key_hash, now)?;
+                }
+                cached_user
+            },
+            None => {
+                let (session, admin) = match self.load_session(&hash_auth_key(auth_key))? {
+                    Some(session) => session,
+                    None => bail!(ErrorKind::NotLoggedIn),
+                };
+                if limits.expired(session.created_at, session.last_used, now) {
+                    self.delete_session(&session.key_hash)?;
+                    bail!(ErrorKind::SessionExpired);
+                }
+                let last_used = if session.last_used + limits.persist_interval < now {
+                    self.touch_session(&session.key_hash, now)?;
+                    now
+                } else {
+                    session.last_used
+                };
+                let cached_user = CachedUser {
+                    username: session.username,
+                    key_hash: session.key_hash,
+                    admin,
+                    created_at: session.created_at,
+                    last_used,
+                    cached_at: now,
+                };
+                self.cached_users.write().unwrap().insert(auth_key, cached_user.clone());
+                cached_user
+            },
+        };
+        Ok(AuthenticatedUser {
+            username: cached_user.username,
+            admin: cached_user.admin,
+            auth_key: auth_key.to_owned(),
+        })
+    }
+
+    /// same as `authenticate`, for the legacy `username` + `auth_key` pairs:
+    /// fails with InvalidAuthKey if the auth_key belongs to someone else
+    pub fn authenticate_as(&self, username: &str, auth_key: &str) -> Result<AuthenticatedUser> {
+        let user = self.authenticate(auth_key)?;
+        if user.username != username {
+            bail!(ErrorKind::InvalidAuthKey);
+        }
+        Ok(user)
+    }
+
+    /// Returns the messages of the interval, along with the tombstones of the deleted ones
+    /// and the change to poll from next time with `Interval::AllFromChange`.
+    ///
+    /// There are only tombstones for incremental intervals, `None` otherwise.
+    /// `viewer` is the user making the request, if any: the private channels they
+    /// can't see are silently left out.
+    pub fn messages<V: AsRef<[String]>>(&self, interval: Interval, channels: V, include_default_channel: bool, viewer: Option<&AuthenticatedUser>)
+        -> Result<(Vec<Message>, Option<Vec<Tombstone>>, i64)>
+    {
+        let incremental = interval.is_incremental();
+        // read first: what changes in between is sent again next time rather than missed
+        let last_change_id = self.last_change_id()?;
+        let channels = self.visible_channels(channels.as_ref(), viewer)?;
+        let (deleted, mut messages) : (Vec<Message>, Vec<Message>) = self.get_messages(interval, channels, include_default_channel)?
+            .into_iter()
+            .partition(|m| m.deleted.is_some());
+        self.annotate(&mut messages, viewer)?;
+        let tombstones = if incremental {
+            Some(deleted.into_iter().filter_map(|m| m.deleted).collect())
+        } else {
+            None
+        };
+        Ok((messages, tombstones, last_change_id))
+    }
+
+    /// Same as `messages`, except that when an incremental interval has nothing new,
+    /// it waits for up to `wait` seconds (60 at most) for a message to be sent, changed or deleted in the channels.
+    /// Fails with TooManyWaiting instead of waiting if `Config::max_waiting` requests already are.
+    pub fn wait_messages<V: AsRef<[String]>>(&self, interval: Interval, channels: V, include_default_channel: bool,
+                                             viewer: Option<&AuthenticatedUser>, wait: u64)
+        -> Result<(Vec<Message>, Option<Vec<Tombstone>>, i64)>
+    {
+        if wait == 0 || !interval.is_incremental() {
+            return self.messages(interval, channels, include_default_channel, viewer);
+        }
+        let channels = self.visible_channels(channels.as_ref(), viewer)?;
+        // subscribed before the first query, so that nothing is missed in between
+        let (sender, receiver) = mpsc::channel();
+        let listener = {
+            let username = viewer.map(|viewer| viewer.username.clone());
+            let channels = channels.clone();
+            self.hub.subscribe(move |published| {
+                let wakes_up = match published.event {
+                    Event::Presence(_) | Event::Typing(_) => false,
+                    Event::Message(_) | Event::Deleted(_, _) =>
+                        published.is_for(username.as_deref(), &channels, include_default_channel),
+                };
+                if wakes_up {
+                    let _ = sender.send(());
+                }
+                // only wakes up the request once
+                !wakes_up
+            })
+        };
+        let result = self.messages_or_wait(receiver, interval, &channels, include_default_channel, viewer, wait);
+        // the request is over, whether it was woken up or not
+        self.hub.unsubscribe(listener);
+        result
+    }
+
+    /// the messages of the interval, or the ones after `receiver` is woken up if there are none yet
+    fn messages_or_wait(&self, receiver: mpsc::Receiver<()>, interval: Interval, channels: &[String], include_default_channel: bool,
+                        viewer: Option<&AuthenticatedUser>, wait: u64)
+        -> Result<(Vec<Message>, Option<Vec<Tombstone>>, i64)>
+    {
+        let (messages, deleted, last_change_id) = self.messages(interval, channels, include_default_channel, viewer)?;
+        if !messages.is_empty() || deleted.as_ref().map_or(false, |deleted| !deleted.is_empty()) {
+            return Ok((messages, deleted, last_change_id));
+        }
+        let _slot = self.waiting_slot()?;
+        match receiver.recv_timeout(StdDuration::from_secs(::std::cmp::min(wait, 60))) {
+            Ok(()) => self.messages(interval, channels, include_default_channel, viewer),
+            Err(_) => Ok((messages, deleted, last_change_id)),
+        }
+    }
+
+    /// the root of the thread and its replies
+    pub fn thread(&self, root_id: i32, viewer: Option<&AuthenticatedUser>) -> Result<Vec<Message>> {
+        self.visible_message(root_id, viewer)?;
+        let mut messages = self.get_thread(root_id)?;
+        self.annotate(&mut messages, viewer)?;
+        Ok(messages)
+    }
+
+    /// fills what is not stored along with the messages themselves,
+    /// as seen by `viewer`
+    fn annotate<'a, I: IntoIterator<Item=&'a mut Message>>(&self, messages: I, viewer: Option<&AuthenticatedUser>) -> Result<()> {
+        let messages = messages.into_iter().collect::<Vec<&mut Message>>();
+        let ids = messages.iter().map(|m| m.id).collect::<Vec<i32>>();
+        let reply_counts = self.get_reply_counts(&ids)?;
+        let reactions = self.get_reactions(&ids)?;
+        for message in messages {
+            message.reply_count = reply_counts.get(&message.id).cloned().unwrap_or(0);
+            message.reactions = Vec::new();
+            let id = message.id;
+            for reaction in reactions.iter().filter(|r| r.message_id == id) {
+                let me = viewer.map_or(false, |viewer| viewer.username == reaction.username);
+                if let Some(count) = message.reactions.iter_mut().find(|c| c.emoji == reaction.emoji) {
+                    count.count += 1;
+                    count.me |= me;
+                    continue;
+                }
+                message.reactions.push(ReactionCount {
+                    emoji: reaction.emoji.clone(),
+                    count: 1,
+                    me,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// the root of the thread a reply to `message_id` belongs to,
+    /// which must exist and be in `channel`
+    fn thread_root(&self, message_id: i32, channel: &Option<String>) -> Result<i32> {
+        let parent = self.existing_message(message_id)?;
+        if parent.channel != *channel {
+            bail!(ErrorKind::NotInChannel);
+        }
+        // replies to replies go to the same thread
+        Ok(parent.reply_to.unwrap_or(parent.id))
+    }
+
+    /// the message if it exists and is not deleted
+    fn existing_message(&self, message_id: i32) -> Result<Message> {
+        match self.get_message(message_id)? {
+            Some(ref message) if message.deleted.is_some() => bail!(ErrorKind::UnknownMessage),
+            Some(message) => Ok(message),
+            None => bail!(ErrorKind::UnknownMessage),
+        }
+    }
+
+    /// the message if it exists, is not deleted, and `viewer` can see its channel:
+    /// messages of hidden channels are unknown rather than forbidden
+    fn visible_message(&self, message_id: i32, viewer: Option<&AuthenticatedUser>) -> Result<Message> {
+        let message = self.existing_message(message_id)?;
+        if let Some(ref channel) = message.channel {
+            if self.hidden_channels(viewer)?.contains(channel) {
+                bail!(ErrorKind::UnknownMessage);
+            }
+        }
+        Ok(message)
+    }
+
+    /// the channels among `channels` which `viewer` can see
+    pub fn visible_channels(&self, channels: &[String], viewer: Option<&AuthenticatedUser>) -> Result<Vec<String>> {
+        let hidden = self.hidden_channels(viewer)?;
+        Ok(channels.iter().filter(|c| !hidden.contains(*c)).cloned().collect())
+    }
+
+    /// the private channels `viewer` is not a member of
+    fn hidden_channels(&self, viewer: Option<&AuthenticatedUser>) -> Result<Vec<String>> {
+        self.get_hidden_channels(viewer.map(|viewer| viewer.username.as_str()))
+    }
+
+    /// the previous contents of a message which is not deleted
+    pub fn revisions(&self, message_id: i32, viewer: Option<&AuthenticatedUser>) -> Result<Vec<Revision>> {
+        self.visible_message(message_id, viewer)?;
+        self.get_revisions(message_id)
+    }
+
+    /// marks every message of the channel up to `message_id` (included) as read by the user
+    pub fn mark_read(&self, user: &AuthenticatedUser, message_id: i32) -> Result<()> {
+        // deleted messages may be the last ones a client knows about
+        let message = match self.get_message(message_id)? {
+            Some(message) => message,
+            None => bail!(ErrorKind::UnknownMessage),
+        };
+        let channel = message.channel.unwrap_or_default();
+        if self.hidden_channels(Some(user))?.contains(&channel) {
+            bail!(ErrorKind::UnknownMessage);
+        }
+        self.set_read_marker(&user.username, &channel, message_id)
+    }
+
+    /// what the user hasn't read in these channels, keyed by channel ('' for the default one),
+    /// the private channels they can't see excluded
+    pub fn unread(&self, user: &AuthenticatedUser, channels: &[String], include_default_channel: bool) -> Result<HashMap<String, Unread>> {
+        let channels = self.visible_channels(channels, Some(user))?;
+        let mut unread = self.get_unread(&user.username, &channels, include_default_channel)?;
+        for channel in channels {
+            unread.entry(channel).or_default();
+        }
+        if include_default_channel {
+            unread.entry(String::new()).or_default();
+        }
+        Ok(unread)
+    }
+
+    /// fulltext search among the messages `viewer` can see
+    pub fn search(&self, query: &str, limit: i64, viewer: Option<&AuthenticatedUser>) -> Result<Vec<Match>> {
+        let hidden = self.hidden_channels(viewer)?;
+        self.fulltext_search(query, limit, &hidden)
+    }
+
+    /// Stores a new message sent by `user`, or anonymously if there is none.
+    ///
+    /// Messages of authenticated users get the logged_in tag, and their author is always
+    /// the authenticated username, whatever the message says.
+    pub fn post_message(&self, new_message: &NewMessage, user: Option<&AuthenticatedUser>) -> Result<Message> {
+        let mut tags : i32 = new_message.tags.unwrap_or(0) & 0b000_0000_0000_0000_0000_0011_1111_1110i32; // see User.tags for more info
+        let author = match user {
+            Some(user) => {
+                tags |= 1;
+                user.username.clone()
+            },
+            None => self.anonymous_name(&new_message.username)?,
+        };
+        if let Some(ref channel) = new_message.channel {
+            if self.hidden_channels(user)?.contains(channel) {
+                bail!(ErrorKind::Forbidden);
+            }
+            self.check_channel_writable(channel)?;
+        }
+        let reply_to = match new_message.reply_to {
+            Some(message_id) => Some(self.thread_root(message_id, &new_message.channel)?),
+            None => None,
+        };
+        let mut message = self.new_message(&author, tags, reply_to, new_message)?;
+        // no_notif: nobody is notified, so there is nothing to record
+        if tags & 8 == 0 {
+            self.record_mentions(&message)?;
+        }
+        self.annotate(Some(&mut message), user)?;
+        self.publish_message(&message)?;
+        // the message the author was typing is sent
+        self.typing(&message.author, &message.channel.clone().unwrap_or_default(), false)?;
+        Ok(message)
+    }
+
+    /// publishes the message to the hub, which sends it to everyone who can see it
+    fn publish_message(&self, message: &Message) -> Result<()> {
+        if self.notify_message(message.id)? {
+            return Ok(());
+        }
+        let mut message = message.clone();
+        // the message was annotated for someone in particular
+        for reaction in &mut message.reactions {
+            reaction.me = false;
+        }
+        let audience = self.audience(&message.channel)?;
+        self.hub.publish(Published {
+            event: Event::Message(message),
+            audience,
+        });
+        Ok(())
+    }
+
+    /// publishes the message as it is stored, or its tombstone if it was deleted,
+    /// after a notification from `notify_message`
+    #[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+    fn publish_notified(&self, message_id: i32) -> Result<()> {
+        let mut message = match self.get_message(message_id)? {
+            Some(message) => message,
+            // purged in the meantime
+            None => return Ok(()),
+        };
+        let audience = self.audience(&message.channel)?;
+        let event = match message.deleted.take() {
+            Some(tombstone) => Event::Deleted(tombstone, message.channel),
+            None => {
+                self.annotate(Some(&mut message), None)?;
+                Event::Message(message)
+            },
+        };
+        self.hub.publish(Published {
+            event,
+            audience,
+        });
+        Ok(())
+    }
+
+    /// None if anyone can see the channel, its members if it is private
+    fn audience(&self, channel: &Option<String>) -> Result<Option<Vec<String>>> {
+        let name = match *channel {
+            Some(ref name) => name,
+            None => return Ok(None),
+        };
+        match self.get_channel(name)? {
+            Some(ref channel) if channel.private => Ok(Some(self.get_channel_members(name)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// records the registered users mentioned by the message, its author excluded
+    fn record_mentions(&self, message: &Message) -> Result<()> {
+        let mut mentioned = Vec::new();
+        for username in parse_mentions(&message.content) {
+            if username != message.author && self.get_user(&username)?.is_some() {
+                mentioned.push(username);
+            }
+        }
+        if mentioned.is_empty() {
+            return Ok(());
+        }
+        self.add_mentions(message.id, &mentioned)
+    }
+
+    /// the messages mentioning the user in the channels they can see, the most recent first
+    pub fn mentions(&self, user: &AuthenticatedUser, before: Option<i32>, limit: i64) -> Result<Vec<Message>> {
+        let hidden = self.hidden_channels(Some(user))?;
+        let mut messages = self.get_mentions(&user.username, before, limit, &hidden)?;
+        self.annotate(&mut messages, Some(user))?;
+        Ok(messages)
+    }
+
+    /// in strict mode, messages can only be sent to channels which exist and are not archived
+    fn check_channel_writable(&self, name: &str) -> Result<()> {
+        if !self.config.strict_channels {
+            return Ok(());
+        }
+        match self.get_channel(name)? {
+            None => bail!(ErrorKind::UnknownChannel),
+            Some(ref channel) if channel.archived => bail!(ErrorKind::ChannelArchived),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// the channels `viewer` can see, direct conversations excluded
+    pub fn channels(&self, viewer: Option<&AuthenticatedUser>) -> Result<Vec<Channel>> {
+        let hidden = self.hidden_channels(viewer)?;
+        Ok(self.get_channels()?.into_iter().filter(|channel| !channel.direct && !hidden.contains(&channel.name)).collect())
+    }
+
+    /// creates a channel owned by the user, and returns it
+    ///
+    /// the creator is the first member of a private channel
+    pub fn new_channel(&self, user: &AuthenticatedUser, name: &str, topic: Option<&str>, description: Option<&str>, private: bool)
+        -> Result<Channel>
+    {
+        check_channel_name(name)?;
+        self.create_channel(&Channel {
+            name: name.to_owned(),
+            topic: topic.map(|t| t.to_owned()),
+            description: description.map(|d| d.to_owned()),
+            creator: Some(user.username.clone()),
+            created_at: now(),
+            archived: false,
+            private,
+            direct: false,
+            message_count: 0,
+            last_activity: None,
+        })?;
+        if private {
+            self.add_channel_member(name, &user.username)?;
+        }
+        self.existing_channel(name)
+    }
+
+    /// changes what is given of the topic, the description, the archived and private flags of the channel,
+    /// for its creator and admins only, and returns the channel
+    pub fn edit_channel(&self, user: &AuthenticatedUser, name: &str, topic: Option<String>, description: Option<String>,
+                        archived: Option<bool>, private: Option<bool>)
+        -> Result<Channel>
+    {
+        let mut channel = self.existing_channel(name)?;
+        if channel.direct || !channel.is_owner(&user.username, user.admin) {
+            bail!(ErrorKind::Forbidden);
+        }
+        if topic.is_some() {
+            channel.topic = topic;
+        }
+        if description.is_some() {
+            channel.description = description;
+        }
+        if let Some(archived) = archived {
+            channel.archived = archived;
+        }
+        if let Some(private) = private {
+            // the creator would otherwise be locked out of their own channel
+            if private && !channel.private {
+                if let Some(ref creator) = channel.creator {
+                    self.add_channel_member(name, creator)?;
+                }
+            }
+            channel.private = private;
+        }
+        self.update_channel(&channel)?;
+        Ok(channel)
+    }
+
+    /// the members of the channel, for those who can see it or manage it
+    pub fn channel_members(&self, user: &AuthenticatedUser, name: &str) -> Result<Vec<String>> {
+        let channel = self.existing_channel(name)?;
+        if !channel.is_owner(&user.username, user.admin) && self.hidden_channels(Some(user))?.contains(&channel.name) {
+            bail!(ErrorKind::UnknownChannel);
+        }
+        self.get_channel_members(name)
+    }
+
+    /// adds and removes members of the channel, for its creator and admins only,
+    /// and returns the members
+    ///
+    /// fails with UnknownUser if one of the users to add isn't registered
+    pub fn edit_channel_members(&self, user: &AuthenticatedUser, name: &str, add: &[String], remove: &[String]) -> Result<Vec<String>> {
+        let channel = self.existing_channel(name)?;
+        // the participants of a direct conversation are fixed
+        if channel.direct || !channel.is_owner(&user.username, user.admin) {
+            bail!(ErrorKind::Forbidden);
+        }
+        for username in add {
+            if self.get_user(username)?.is_none() {
+                bail!(ErrorKind::UnknownUser);
+            }
+        }
+        for username in add {
+            self.add_channel_member(name, username)?;
+        }
+        for username in remove {
+            self.remove_channel_member(name, username)?;
+        }
+        self.get_channel_members(name)
+    }
+
+    /// Opens the direct conversation between the user and `participants`, or returns the existing one.
+    ///
+    /// Fails with InvalidParticipants if there is nobody else or more than MAX_DIRECT_PARTICIPANTS,
+    /// and with UnknownUser if one of them isn't registered.
+    pub fn open_direct(&self, user: &AuthenticatedUser, participants: &[String]) -> Result<Conversation> {
+        let mut participants = participants.to_vec();
+        participants.push(user.username.clone());
+        participants.sort();
+        participants.dedup();
+        if participants.len() < 2 || participants.len() > MAX_DIRECT_PARTICIPANTS {
+            bail!(ErrorKind::InvalidParticipants);
+        }
+        for username in &participants {
+            if self.get_user(username)?.is_none() {
+                bail!(ErrorKind::UnknownUser);
+            }
+        }
+        let name = direct_channel_name(&participants);
+        let created = self.create_channel(&Channel {
+            name: name.clone(),
+            topic: None,
+            description: None,
+            creator: Some(user.username.clone()),
+            created_at: now(),
+            archived: false,
+            private: true,
+            direct: true,
+            message_count: 0,
+            last_activity: None,
+        });
+        match created {
+            Ok(()) => {},
+            // opened before, by any of the participants
+            Err(Error(ErrorKind::ChannelNameInUse, _)) => {
+                if !self.existing_channel(&name)?.direct {
+                    bail!(ErrorKind::ChannelNameInUse);
+                }
+            },
+            Err(e) => return Err(e),
+        }
+        // in both cases, since someone else may be opening it at the same time
+        for username in &participants {
+            self.add_channel_member(&name, username)?;
+        }
+        let mut last_messages = self.get_last_messages(&[name.clone()])?;
+        self.annotate(&mut last_messages, Some(user))?;
+        Ok(Conversation {
+            channel: name,
+            participants,
+            last_message: last_messages.pop(),
+        })
+    }
+
+    /// the direct conversations of the user, the most recently active first
+    pub fn conversations(&self, user: &AuthenticatedUser) -> Result<Vec<Conversation>> {
+        let mut channels = self.get_direct_channels(&user.username)?;
+        channels.sort_by(|a, b| b.last_activity.unwrap_or(b.created_at).cmp(&a.last_activity.unwrap_or(a.created_at)));
+        let names = channels.iter().map(|c| c.name.clone()).collect::<Vec<String>>();
+        let mut last_messages = self.get_last_messages(&names)?;
+        self.annotate(&mut last_messages, Some(user))?;
+        let mut conversations = Vec::with_capacity(channels.len());
+        for channel in channels {
+            let position = last_messages.iter().position(|m| m.channel.as_ref() == Some(&channel.name));
+            conversations.push(Conversation {
+                participants: self.get_channel_members(&channel.name)?,
+                last_message: position.map(|position| last_messages.swap_remove(position)),
+                channel: channel.name,
+            });
+        }
+        Ok(conversations)
+    }
+
+    fn existing_channel(&self, name: &str) -> Result<Channel> {
+        match self.get_channel(name)? {
+            Some(channel) => Ok(channel),
+            None => bail!(ErrorKind::UnknownChannel),
+        }
+    }
+
+    /// whether the user is allowed to manage the channel (the default one if None):
+    /// admins can manage every channel, and creators their own
+    fn owns_channel(&self, user: &AuthenticatedUser, channel: Option<&str>) -> Result<bool> {
+        if user.admin {
+            return Ok(true);
+        }
+        match channel {
+            Some(name) => Ok(self.get_channel(name)?.map_or(false, |channel| channel.is_owner(&user.username, false))),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the name someone who isn't authenticated is allowed to use instead of `username`,
+    /// depending on `Config::anonymous_names`.
+    fn anonymous_name(&self, username: &str) -> Result<String> {
+        if username.is_empty() {
+            bail!(ErrorKind::MissingParameter("username"));
+        }
+        match self.config.anonymous_names {
+            AnonymousNames::Allow => Ok(username.to_owned()),
+            AnonymousNames::Forbid => {
+                if self.get_user(username)?.is_some() {
+                    bail!(ErrorKind::Forbidden);
+                }
+                Ok(username.to_owned())
+            },
+            AnonymousNames::Suffix(ref suffix) => {
+                let mut name = username.to_owned();
+                // the suffixed name may be registered as well
+                while self.get_user(&name)?.is_some() {
+                    name.push_str(suffix);
+                }
+                Ok(name)
+            },
+        }
+    }
+
+    /// Marks `user` as typing in the channel, or `username` if nobody is authenticated,
+    /// until they stop saying so or send `typing: false`.
+    pub fn set_typing(&self, user: Option<&AuthenticatedUser>, username: &str, channel: Option<&str>, typing: bool) -> Result<()> {
+        let username = match user {
+            Some(user) => user.username.clone(),
+            None => self.anonymous_name(username)?,
+        };
+        if let Some(channel) = channel {
+            if self.hidden_channels(user)?.iter().any(|c| c == channel) {
+                bail!(ErrorKind::Forbidden);
+            }
+            self.check_channel_writable(channel)?;
+        }
+        self.typing(&username, channel.unwrap_or(""), typing)
+    }
+
+    /// updates who is typing in the channel ("" for the default one), and publishes the change if any
+    fn typing(&self, username: &str, channel: &str, typing: bool) -> Result<()> {
+        let typing = {
+            let mut typing_users = self.typing_users.write().unwrap();
+            if !typing_users.update(channel, username, typing) {
+                return Ok(());
+            }
+            typing_users.in_channel(channel)
+        };
+        self.publish_typing(typing)
+    }
+
+    fn publish_typing(&self, typing: Typing) -> Result<()> {
+        let channel = if typing.channel.is_empty() { None } else { Some(typing.channel.clone()) };
+        let audience = self.audience(&channel)?;
+        self.hub.publish(Published {
+            event: Event::Typing(typing),
+            audience,
+        });
+        Ok(())
+    }
+
+    /// forgets the users who stopped typing, and tells the others
+    pub fn refresh_typing(&self) {
+        let changed = {
+            let mut typing_users = self.typing_users.write().unwrap();
+            let channels = typing_users.refresh();
+            channels.into_iter()
+                .map(|channel| typing_users.in_channel(&channel))
+                .collect::<Vec<Typing>>()
+        };
+        for typing in changed {
+            if let Err(e) = self.publish_typing(typing) {
+                println!("Unexpected error while publishing who is typing: `{}`", e);
+            }
+        }
+    }
+
+    /// who is typing in these channels, keyed by channel ('' for the default one),
+    /// the private channels `viewer` can't see excluded
+    pub fn typing_in(&self, channels: &[String], include_default_channel: bool, viewer: Option<&AuthenticatedUser>)
+        -> Result<HashMap<String, Vec<String>>>
+    {
+        let mut channels = self.visible_channels(channels, viewer)?;
+        if include_default_channel {
+            channels.push(String::new());
+        }
+        let typing_users = self.typing_users.read().unwrap();
+        Ok(channels.into_iter().map(|channel| {
+            let typing = typing_users.in_channel(&channel);
+            (typing.channel, typing.usernames)
+        }).collect())
+    }
+
+    /// the connected users reading one of these channels, the private channels `viewer` can't see excluded
+    pub fn heartbeat(&self, channels: &[String], include_default_channel: bool, viewer: Option<&AuthenticatedUser>)
+        -> Result<Vec<ConnectedUser>>
+    {
+        let channels = self.visible_channels(channels, viewer)?;
+        Ok(self.connected_users.read().unwrap().in_channels(&channels, include_default_channel))
+    }
+
+    /// who is reading each of these channels, keyed by channel ('' for the default one),
+    /// the private channels `viewer` can't see excluded
+    pub fn users_by_channel(&self, channels: &[String], include_default_channel: bool, viewer: Option<&AuthenticatedUser>)
+        -> Result<HashMap<String, Vec<String>>>
+    {
+        let channels = self.visible_channels(channels, viewer)?;
+        Ok(self.connected_users.read().unwrap().by_channel(&channels, include_default_channel))
+    }
+
+    /// marks `user` as connected, or `username` if nobody is authenticated,
+    /// which follows the same rules as the authors of anonymous messages,
+    /// and returns the connected users reading one of these channels
+    pub fn heartbeat_mut(&self, user: Option<&AuthenticatedUser>, username: &str, active: bool,
+                         channels: &[String], include_default_channel: bool)
+        -> Result<Vec<ConnectedUser>>
+    {
+        let (username, logged_in) = match user {
+            Some(user) => (user.username.clone(), true),
+            None => (self.anonymous_name(username)?, false),
+        };
+        let channels = self.visible_channels(channels, user)?;
+        // the status of registered users is only read when they connect
+        let status = if logged_in && !self.connected_users.read().unwrap().contains(&username) {
+            self.get_user_status(&username)?
+        } else {
+            None
+        };
+        let mut connected_users = self.connected_users.write().unwrap();
+        let mut changed = connected_users.update(&username, logged_in, active, &channels, include_default_channel);
+        if let Some(ref status) = status {
+            changed |= connected_users.set_status(&username, status);
+        }
+        if changed {
+            self.publish_presence(connected_users.as_vec());
+        }
+        Ok(connected_users.in_channels(&channels, include_default_channel))
+    }
+
+    /// the status chosen by the user, online without any text if they never changed it
+    pub fn status(&self, user: &AuthenticatedUser) -> Result<UserStatus> {
+        Ok(self.get_user_status(&user.username)?.unwrap_or_default())
+    }
+
+    /// Replaces the status of the user, which is kept until they change it again.
+    ///
+    /// The state is one of online, away, dnd and invisible.
+    pub fn set_status(&self, user: &AuthenticatedUser, state: &str, status_text: Option<&str>, status_emoji: Option<&str>)
+        -> Result<UserStatus>
+    {
+        let status_text = match status_text.map(|text| text.trim()) {
+            Some("") | None => None,
+            Some(text) => Some(text),
+        };
+        if status_text.map_or(false, |text| text.chars().count() > MAX_STATUS_TEXT_LENGTH) {
+            bail!(ErrorKind::InvalidStatus);
+        }
+        if let Some(emoji) = status_emoji {
+            check_emoji(emoji)?;
+        }
+        let status = UserStatus {
+            state: PresenceState::parse(state)?,
+            status_text: status_text.map(|text| text.to_owned()),
+            status_emoji: status_emoji.map(|emoji| emoji.to_owned()),
+        };
+        self.set_user_status(&user.username, &status)?;
+        let mut connected_users = self.connected_users.write().unwrap();
+        if connected_users.set_status(&user.username, &status) {
+            self.publish_presence(connected_users.as_vec());
+        }
+        Ok(status)
+    }
+
+    /// checks if user is allowed to edit first: only admins and the author,
+    /// if the message was sent while logged in, are
+    pub fn edit(&self, user: &AuthenticatedUser, message_id: i32, content: &str) -> Result<Message> {
+        let message = self.visible_message(message_id, Some(user))?;
+        let is_author = message.tags & 1 == 1 && message.author == user.username;
+        if !(is_author || user.admin) {
+            bail!(ErrorKind::Forbidden);
+        }
+        let mut message = self.edit_message(message_id, content, &user.username)?;
+        self.annotate(Some(&mut message), Some(user))?;
+        self.publish_message(&message)?;
+        Ok(message)
+    }
+
+    /// adds (or removes if `add` is false) the reaction of the user to the message,
+    /// and returns the message with its new reactions
+    pub fn react(&self, user: &AuthenticatedUser, message_id: i32, emoji: &str, add: bool) -> Result<Message> {
+        check_emoji(emoji)?;
+        self.visible_message(message_id, Some(user))?;
+        if add {
+            self.add_reaction(message_id, &user.username, emoji)?;
+        } else {
+            self.remove_reaction(message_id, &user.username, emoji)?;
+        }
+        let mut message = self.existing_message(message_id)?;
+        self.annotate(Some(&mut message), Some(user))?;
+        self.publish_message(&message)?;
+        Ok(message)
+    }
+
+    /// pins (or unpins if `pin` is false) the message, for admins and the owner of its channel only,
+    /// and returns the message
+    pub fn pin(&self, user: &AuthenticatedUser, message_id: i32, pin: bool) -> Result<Message> {
+        let message = self.visible_message(message_id, Some(user))?;
+        if !self.owns_channel(user, message.channel.as_deref())? {
+            bail!(ErrorKind::Forbidden);
+        }
+        self.set_pinned(message_id, if pin { Some(&user.username) } else { None })?;
+        let mut message = self.existing_message(message_id)?;
+        self.annotate(Some(&mut message), Some(user))?;
+        self.publish_message(&message)?;
+        Ok(message)
+    }
+
+    /// the pinned messages of the channel, none if `viewer` can't see it
+    pub fn pins(&self, channel: Option<&str>, viewer: Option<&AuthenticatedUser>) -> Result<Vec<Message>> {
+        if let Some(channel) = channel {
+            if self.hidden_channels(viewer)?.iter().any(|hidden| hidden == channel) {
+                return Ok(Vec::new());
+            }
+        }
+        let mut messages = self.get_pins(channel)?;
+        self.annotate(&mut messages, viewer)?;
+        Ok(messages)
+    }
+
+    /// checks if user is allowed to delete first
+    pub fn try_del(&self, user: &AuthenticatedUser, message_id: i32, reason: Option<&str>) -> Result<()> {
+        if !user.admin {
+            bail!(ErrorKind::Forbidden);
+        }
+        self.delete_message(message_id, &user.username, reason)?;
+        if self.notify_message(message_id)? {
+            return Ok(());
+        }
+        if let Some(message) = self.get_message(message_id)? {
+            if let Some(tombstone) = message.deleted {
+                let audience = self.audience(&message.channel)?;
+                self.hub.publish(Published {
+                    event: Event::Deleted(tombstone, message.channel),
+                    audience,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// admins only: removes every trace of the message
+    pub fn try_purge(&self, user: &AuthenticatedUser, message_id: i32) -> Result<()> {
+        if user.admin {
+            self.purge_message(message_id)
+        } else {
+            Err(Error::from_kind(ErrorKind::Forbidden))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use message::NewMessage;
+    use password::PasswordParams;
+    use user::SessionLimits;
+    use chrono::Duration;
+    use std::sync::{Arc,Mutex};
+    use std::thread;
+    use std::time::Instant;
+    use super::*;
+
+    // cheap enough for the tests
+    const PASSWORD_PARAMS: PasswordParams = PasswordParams { log_n: 4, r: 1, p: 1 };
+
+    fn config() -> Config {
+        Config {
+            password: PASSWORD_PARAMS,
+            ..Config::default()
+        }
+    }
+
+    fn chatbix() -> Chatbix<MemoryStore> {
+        Chatbix::<MemoryStore>::new(MemoryStore::new()).with_config(config())
+    }
+
+    fn new_message(content: &str, channel: Option<&str>) -> NewMessage {
+        NewMessage {
+            username: "someone".to_owned(),
+            content: content.to_owned(),
+            tags: None,
+            color: None,
+            channel: channel.map(|c| c.to_owned()),
+            auth_key: None,
+            reply_to: None,
+        }
+    }
+
+    fn admin() -> AuthenticatedUser {
+        AuthenticatedUser {
+            username: "admin".to_owned(),
+            admin: true,
+            auth_key: String::new(),
+        }
+    }
+
+    fn contents(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|message| message.content.as_str()).collect()
+    }
+
+    #[test]
+    fn intervals_filter_the_messages_of_the_channels() {
+        let chatbix = chatbix();
+        chatbix.post_message(&new_message("first", None), None).unwrap();
+        chatbix.post_message(&new_message("second", Some("rust")), None).unwrap();
+        chatbix.post_message(&new_message("third", Some("off-topic")), None).unwrap();
+        chatbix.post_message(&new_message("fourth", None), None).unwrap();
+        let rust = vec!["rust".to_owned()];
+        let messages = chatbix.get_messages(Interval::Last(2), &rust, true).unwrap();
+        assert_eq!(contents(&messages), vec!["second", "fourth"]);
+        let messages = chatbix.get_messages(Interval::AllFromId(1), &rust, true).unwrap();
+        assert_eq!(contents(&messages), vec!["second", "fourth"]);
+        let messages = chatbix.get_messages(Interval::AllFromId(0), &rust, false).unwrap();
+        assert_eq!(contents(&messages), vec!["second"]);
+    }
+
+    #[test]
+    fn fulltext_search_requires_every_word() {
+        let chatbix = chatbix();
+        chatbix.post_message(&new_message("the borrow checker", None), None).unwrap();
+        chatbix.post_message(&new_message("the checker of borrows", None), None).unwrap();
+        chatbix.post_message(&new_message("checker checker", None), None).unwrap();
+        let matches = chatbix.fulltext_search("Checker BORROW", 10, &[]).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].message, "the borrow checker");
+        let matches = chatbix.fulltext_search("checker", 2, &[]).unwrap();
+        assert_eq!(matches[0].message, "checker checker");
+        assert_eq!(matches.len(), 2);
+    }
+
+    #[test]
+    fn memory_admins_are_admins_once_registered() {
+        let chatbix = Chatbix::<MemoryStore>::new(MemoryStore::new().with_admins(vec!["boss".to_owned()]))
+            .with_config(config());
+        chatbix.register("boss", "password", None).unwrap();
+        chatbix.register("someone", "password", None).unwrap();
+        let boss_key = chatbix.login("boss", "password", None).unwrap();
+        let someone_key = chatbix.login("someone", "password", None).unwrap();
+        assert!(chatbix.authenticate_as("boss", &boss_key).unwrap().admin);
+        assert!(!chatbix.authenticate_as("someone", &someone_key).unwrap().admin);
+    }
+
+    #[test]
+    fn legacy_passwords_are_hashed_again_on_login() {
+        let chatbix = chatbix();
+        // the first 64 hex chars of sha512("password")
+        chatbix.create_user("someone", "b109f3bbbc244eb82441917ed06d618b9008dd09b3befd1b5e07394c706a8bb9").unwrap();
+        assert!(chatbix.login("someone", "Password", None).is_err());
+        chatbix.login("someone", "password", None).unwrap();
+        let stored = chatbix.get_user("someone").unwrap().unwrap().password;
+        assert!(stored.starts_with("$scrypt$"));
+        assert!(matches!(password::verify("password", &stored, &PASSWORD_PARAMS), Verification::Valid));
+        chatbix.login("someone", "password", None).unwrap();
+    }
+
+    #[test]
+    fn sessions_are_read_again_once_the_cache_expires() {
+        let chatbix = chatbix();
+        let auth_key = chatbix.register("someone", "password", None).unwrap();
+        // as if the user had logged out through another instance
+        chatbix.delete_session(&hash_auth_key(&auth_key)).unwrap();
+        assert!(!chatbix.authenticate_as("someone", &auth_key).unwrap().admin);
+        let chatbix = chatbix.with_config(Config {
+            sessions: SessionLimits { cache_ttl: Duration::zero(), ..SessionLimits::default() },
+            ..config()
+        });
+        assert!(matches!(chatbix.authenticate(&auth_key).unwrap_err().kind(), ErrorKind::NotLoggedIn));
+    }
+
+    #[test]
+    fn every_login_has_its_own_session() {
+        let chatbix = chatbix();
+        let phone = chatbix.register("someone", "password", Some("phone")).unwrap();
+        let laptop = chatbix.login("someone", "password", Some("laptop")).unwrap();
+        assert!(phone != laptop);
+        let sessions = chatbix.sessions(&chatbix.authenticate(&laptop).unwrap()).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().any(|session| session.current && session.user_agent.as_deref() == Some("laptop")));
+        chatbix.revoke_other_sessions(&chatbix.authenticate(&laptop).unwrap()).unwrap();
+        assert!(matches!(chatbix.authenticate(&phone).unwrap_err().kind(), ErrorKind::NotLoggedIn));
+        assert!(!chatbix.authenticate_as("someone", &laptop).unwrap().admin);
+    }
+
+    #[test]
+    fn refreshed_sessions_replace_the_old_auth_key() {
+        let chatbix = chatbix();
+        let auth_key = chatbix.register("someone", "password", None).unwrap();
+        let refreshed_auth_key = chatbix.refresh_session(&chatbix.authenticate(&auth_key).unwrap(), None).unwrap();
+        assert!(refreshed_auth_key != auth_key);
+        assert_eq!(refreshed_auth_key.len(), 32);
+        assert!(matches!(chatbix.authenticate(&auth_key).unwrap_err().kind(), ErrorKind::NotLoggedIn));
+        assert!(!chatbix.authenticate_as("someone", &refreshed_auth_key).unwrap().admin);
+    }
+
+    #[test]
+    fn authenticated_messages_are_tagged_and_signed_by_their_author() {
+        let chatbix = chatbix();
+        let auth_key = chatbix.register("author", "password", None).unwrap();
+        let user = chatbix.authenticate(&auth_key).unwrap();
+        let message = chatbix.post_message(&new_message("signed", None), Some(&user)).unwrap();
+        assert_eq!(message.author, "author");
+        assert_eq!(message.tags & 1, 1);
+        let message = chatbix.post_message(&new_message("anonymous", None), None).unwrap();
+        assert_eq!(message.author, "someone");
+        assert_eq!(message.tags & 1, 0);
+        assert!(matches!(chatbix.authenticate_as("someone", &auth_key).unwrap_err().kind(), ErrorKind::InvalidAuthKey));
+    }
+
+    #[test]
+    fn registered_names_are_protected_from_anonymous_users() {
+        let chatbix = chatbix();
+        chatbix.register("someone", "password", None).unwrap();
+        let chatbix = chatbix.with_config(Config {
+            anonymous_names: AnonymousNames::Forbid,
+            ..config()
+        });
+        assert!(matches!(chatbix.post_message(&new_message("hi", None), None).unwrap_err().kind(), ErrorKind::Forbidden));
+        assert!(chatbix.heartbeat_mut(None, "someone", true, &[], true).is_err());
+        let chatbix = chatbix.with_config(Config {
+            anonymous_names: AnonymousNames::Suffix(" (guest)".to_owned()),
+            ..config()
+        });
+        let message = chatbix.post_message(&new_message("hi", None), None).unwrap();
+        assert_eq!(message.author, "someone (guest)");
+        let connected_users = chatbix.heartbeat_mut(None, "someone", true, &[], true).unwrap();
+        assert_eq!(*connected_users[0].username, "someone (guest)");
+    }
+
+    #[test]
+    fn only_the_author_or_an_admin_can_edit() {
+        let chatbix = Chatbix::<MemoryStore>::new(MemoryStore::new().with_admins(vec!["admin".to_owned()])).with_config(config());
+        let author = chatbix.authenticate(&chatbix.register("author", "password", None).unwrap()).unwrap();
+        let other = chatbix.authenticate(&chatbix.register("other", "password", None).unwrap()).unwrap();
+        let admin = chatbix.authenticate(&chatbix.register("admin", "password", None).unwrap()).unwrap();
+        let message = chatbix.post_message(&new_message("typo", None), Some(&author)).unwrap();
+        assert!(matches!(chatbix.edit(&other, message.id, "vandalized").unwrap_err().kind(), ErrorKind::Forbidden));
+        assert!(chatbix.edit(&author, message.id, "fixed").unwrap().edited_at.is_some());
+        chatbix.edit(&admin, message.id, "moderated").unwrap();
+        let revisions = chatbix.get_revisions(message.id).unwrap();
+        assert_eq!(revisions.iter().map(|r| r.content.as_str()).collect::<Vec<&str>>(), vec!["typo", "fixed"]);
+        assert_eq!(revisions[1].edited_by, "admin");
+    }
+
+    #[test]
+    fn all_from_id_returns_the_messages_changed_since() {
+        let chatbix = chatbix();
+        let author = chatbix.authenticate(&chatbix.register("author", "password", None).unwrap()).unwrap();
+        let first = chatbix.post_message(&new_message("first", None), Some(&author)).unwrap();
+        let second = chatbix.post_message(&new_message("second", None), None).unwrap();
+        chatbix.edit(&author, first.id, "first, edited").unwrap();
+        let (messages, _, _) = chatbix.messages(Interval::AllFromId(second.id), Vec::new(), true, None).unwrap();
+        assert_eq!(contents(&messages), vec!["first, edited"]);
+        let (messages, _, _) = chatbix.messages(Interval::AllFromId(first.id), Vec::new(), true, None).unwrap();
+        assert_eq!(contents(&messages), vec!["first, edited", "second"]);
+        // the edit was already there when this one was sent
+        let third = chatbix.post_message(&new_message("third", None), None).unwrap();
+        let (messages, _, _) = chatbix.messages(Interval::AllFromId(third.id), Vec::new(), true, None).unwrap();
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn all_from_change_returns_the_changed_messages_once() {
+        let chatbix = chatbix();
+        let author = chatbix.authenticate(&chatbix.register("author", "password", None).unwrap()).unwrap();
+        let first = chatbix.post_message(&new_message("first", None), Some(&author)).unwrap();
+        let second = chatbix.post_message(&new_message("second", None), None).unwrap();
+        let (_, _, change_id) = chatbix.messages(Interval::default(), Vec::new(), true, None).unwrap();
+        chatbix.edit(&author, first.id, "edited once").unwrap();
+        chatbix.edit(&author, first.id, "edited twice").unwrap();
+        chatbix.try_del(&admin(), second.id, Some("spam")).unwrap();
+        let (messages, deleted, next_change_id) = chatbix.messages(Interval::AllFromChange(change_id), Vec::new(), true, None).unwrap();
+        assert_eq!(contents(&messages), vec!["edited twice"]);
+        let deleted = deleted.unwrap();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].id, second.id);
+        assert_eq!(deleted[0].reason.as_deref(), Some("spam"));
+        // nothing new since
+        let (messages, deleted, _) = chatbix.messages(Interval::AllFromChange(next_change_id), Vec::new(), true, None).unwrap();
+        assert!(messages.is_empty());
+        assert!(deleted.unwrap().is_empty());
+    }
+
+    #[test]
+    fn legacy_cursors_return_the_tombstones_of_the_messages_deleted_since() {
+        let chatbix = chatbix();
+        let first = chatbix.post_message(&new_message("first", None), None).unwrap();
+        let second = chatbix.post_message(&new_message("second", None), None).unwrap();
+        chatbix.try_del(&admin(), first.id, None).unwrap();
+        let (messages, deleted, _) = chatbix.messages(Interval::AllFromId(second.id), Vec::new(), true, None).unwrap();
+        assert!(messages.is_empty());
+        assert_eq!(deleted.unwrap().iter().map(|t| t.id).collect::<Vec<i32>>(), vec![first.id]);
+        let (messages, deleted, _) = chatbix.messages(Interval::AllFromTimestamp(second.timestamp), Vec::new(), true, None).unwrap();
+        assert!(messages.is_empty());
+        assert_eq!(deleted.unwrap().iter().map(|t| t.id).collect::<Vec<i32>>(), vec![first.id]);
+        // the deletion was already there when this one was sent
+        let third = chatbix.post_message(&new_message("third", None), None).unwrap();
+        let (_, deleted, _) = chatbix.messages(Interval::AllFromId(third.id), Vec::new(), true, None).unwrap();
+        assert!(deleted.unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_incremental_intervals_return_tombstones() {
+        let chatbix = chatbix();
+        let message = chatbix.post_message(&new_message("hello", None), None).unwrap();
+        chatbix.try_del(&admin(), message.id, None).unwrap();
+        let (messages, deleted, _) = chatbix.messages(Interval::Last(10), Vec::new(), true, None).unwrap();
+        assert!(messages.is_empty());
+        assert!(deleted.is_none());
+        assert!(matches!(chatbix.edit(&admin(), message.id, "back").unwrap_err().kind(), ErrorKind::UnknownMessage));
+        chatbix.try_purge(&admin(), message.id).unwrap();
+        assert!(chatbix.get_message(message.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn replies_go_to_the_root_of_their_thread() {
+        let chatbix = chatbix();
+        let root = chatbix.post_message(&new_message("root", None), None).unwrap();
+        let reply = chatbix.post_message(&NewMessage { reply_to: Some(root.id), ..new_message("reply", None) }, None).unwrap();
+        let (_, _, change_id) = chatbix.messages(Interval::default(), Vec::new(), true, None).unwrap();
+        let nested = chatbix.post_message(&NewMessage { reply_to: Some(reply.id), ..new_message("nested", None) }, None).unwrap();
+        assert_eq!(nested.reply_to, Some(root.id));
+        let thread = chatbix.thread(root.id, None).unwrap();
+        assert_eq!(contents(&thread), vec!["root", "reply", "nested"]);
+        assert_eq!(thread[0].reply_count, 2);
+        // the root is sent again with its new reply count
+        let (messages, _, _) = chatbix.messages(Interval::AllFromChange(change_id), Vec::new(), true, None).unwrap();
+        assert_eq!(contents(&messages), vec!["root", "nested"]);
+        assert_eq!(messages[0].reply_count, 2);
+        chatbix.try_del(&admin(), reply.id, None).unwrap();
+        assert_eq!(chatbix.thread(root.id, None).unwrap()[0].reply_count, 1);
+        let error = chatbix.post_message(&NewMessage { reply_to: Some(root.id), ..new_message("elsewhere", Some("rust")) }, None).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::NotInChannel));
+    }
+
+    #[test]
+    fn reactions_are_counted_per_emoji() {
+        let chatbix = chatbix();
+        let message = chatbix.post_message(&new_message("hello", None), None).unwrap();
+        let someone = AuthenticatedUser {
+            username: "someone".to_owned(),
+            admin: false,
+            auth_key: String::new(),
+        };
+        chatbix.react(&admin(), message.id, "👍", true).unwrap();
+        // reacting twice with the same emoji counts once
+        chatbix.react(&admin(), message.id, "👍", true).unwrap();
+        chatbix.react(&someone, message.id, "🎉", true).unwrap();
+        let (_, _, change_id) = chatbix.messages(Interval::default(), Vec::new(), true, None).unwrap();
+        let reacted = chatbix.react(&someone, message.id, "👍", true).unwrap();
+        let reactions = reacted.reactions.iter().map(|r| (r.emoji.as_str(), r.count, r.me)).collect::<Vec<_>>();
+        assert_eq!(reactions, vec![("👍", 2, true), ("🎉", 1, true)]);
+        let (messages, _, _) = chatbix.messages(Interval::AllFromChange(change_id), Vec::new(), true, Some(&admin())).unwrap();
+        let reactions = messages[0].reactions.iter().map(|r| (r.emoji.as_str(), r.count, r.me)).collect::<Vec<_>>();
+        assert_eq!(reactions, vec![("👍", 2, true), ("🎉", 1, false)]);
+        chatbix.react(&admin(), message.id, "👍", false).unwrap();
+        // in the order they were first used by the remaining users
+        let (messages, _, _) = chatbix.messages(Interval::Last(1), Vec::new(), true, None).unwrap();
+        let reactions = messages[0].reactions.iter().map(|r| (r.emoji.as_str(), r.count, r.me)).collect::<Vec<_>>();
+        assert_eq!(reactions, vec![("🎉", 1, false), ("👍", 1, false)]);
+        let error = chatbix.react(&admin(), message.id, "thumbs up", true).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::InvalidEmoji));
+    }
+
+    #[test]
+    fn only_admins_pin_messages_in_their_channel() {
+        let chatbix = chatbix();
+        let first = chatbix.post_message(&new_message("first", None), None).unwrap();
+        let second = chatbix.post_message(&new_message("second", None), None).unwrap();
+        let elsewhere = chatbix.post_message(&new_message("elsewhere", Some("rust")), None).unwrap();
+        let someone = AuthenticatedUser {
+            username: "someone".to_owned(),
+            admin: false,
+            auth_key: String::new(),
+        };
+        assert!(matches!(chatbix.pin(&someone, first.id, true).unwrap_err().kind(), ErrorKind::Forbidden));
+        let (_, _, change_id) = chatbix.messages(Interval::default(), Vec::new(), true, None).unwrap();
+        assert!(chatbix.pin(&admin(), first.id, true).unwrap().pinned);
+        chatbix.pin(&admin(), second.id, true).unwrap();
+        chatbix.pin(&admin(), elsewhere.id, true).unwrap();
+        // the most recently pinned first
+        assert_eq!(contents(&chatbix.pins(None, None).unwrap()), vec!["second", "first"]);
+        assert_eq!(contents(&chatbix.pins(Some("rust"), None).unwrap()), vec!["elsewhere"]);
+        let (messages, _, _) = chatbix.messages(Interval::AllFromChange(change_id), Vec::new(), true, None).unwrap();
+        assert_eq!(contents(&messages), vec!["first", "second"]);
+        assert!(!chatbix.pin(&admin(), first.id, false).unwrap().pinned);
+        chatbix.try_del(&admin(), second.id, None).unwrap();
+        assert!(chatbix.pins(None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn strict_channels_must_exist_and_not_be_archived() {
+        let chatbix = Chatbix::<MemoryStore>::new(MemoryStore::new()).with_config(Config {
+            strict_channels: true,
+            ..config()
+        });
+        let owner = chatbix.authenticate(&chatbix.register("owner", "password", None).unwrap()).unwrap();
+        let other = chatbix.authenticate(&chatbix.register("other", "password", None).unwrap()).unwrap();
+        let error = chatbix.post_message(&new_message("hello", Some("rust")), None).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::UnknownChannel));
+        assert!(matches!(chatbix.new_channel(&owner, "rust,go", None, None, false).unwrap_err().kind(), ErrorKind::InvalidChannelName));
+        chatbix.new_channel(&owner, "rust", Some("crabs"), None, false).unwrap();
+        assert!(matches!(chatbix.new_channel(&other, "rust", None, None, false).unwrap_err().kind(), ErrorKind::ChannelNameInUse));
+        let message = chatbix.post_message(&new_message("hello", Some("rust")), None).unwrap();
+        let channels = chatbix.channels(None).unwrap();
+        assert_eq!((channels[0].message_count, channels[0].topic.as_deref()), (1, Some("crabs")));
+        // the owner of a channel moderates it
+        assert!(chatbix.pin(&owner, message.id, true).unwrap().pinned);
+        assert!(matches!(chatbix.pin(&other, message.id, false).unwrap_err().kind(), ErrorKind::Forbidden));
+        let error = chatbix.edit_channel(&other, "rust", None, None, Some(true), None).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::Forbidden));
+        let channel = chatbix.edit_channel(&owner, "rust", None, Some("about rust".to_owned()), Some(true), None).unwrap();
+        assert_eq!((channel.topic.as_deref(), channel.archived), (Some("crabs"), true));
+        let error = chatbix.post_message(&new_message("hello again", Some("rust")), None).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::ChannelArchived));
+    }
+
+    #[test]
+    fn private_channels_are_only_visible_to_their_members() {
+        let chatbix = chatbix();
+        let owner = chatbix.authenticate(&chatbix.register("owner", "password", None).unwrap()).unwrap();
+        let member = chatbix.authenticate(&chatbix.register("member", "password", None).unwrap()).unwrap();
+        let other = chatbix.authenticate(&chatbix.register("other", "password", None).unwrap()).unwrap();
+        chatbix.new_channel(&owner, "secret", None, None, true).unwrap();
+        let members = chatbix.edit_channel_members(&owner, "secret", &["member".to_owned()], &[]).unwrap();
+        assert_eq!(members, vec!["member", "owner"]);
+        let error = chatbix.edit_channel_members(&member, "secret", &["other".to_owned()], &[]).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::Forbidden));
+        let error = chatbix.edit_channel_members(&owner, "secret", &["nobody".to_owned()], &[]).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::UnknownUser));
+        let message = chatbix.post_message(&new_message("hidden treasure", Some("secret")), Some(&member)).unwrap();
+        let error = chatbix.post_message(&new_message("let me in", Some("secret")), Some(&other)).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::Forbidden));
+        let secret = vec!["secret".to_owned()];
+        let (messages, _, _) = chatbix.messages(Interval::default(), &secret, false, Some(&member)).unwrap();
+        assert_eq!(contents(&messages), vec!["hidden treasure"]);
+        let (messages, _, _) = chatbix.messages(Interval::default(), &secret, false, Some(&other)).unwrap();
+        assert!(messages.is_empty());
+        let (messages, _, _) = chatbix.messages(Interval::default(), &secret, false, None).unwrap();
+        assert!(messages.is_empty());
+        assert_eq!(chatbix.search("treasure", 10, Some(&member)).unwrap().len(), 1);
+        assert!(chatbix.search("treasure", 10, Some(&other)).unwrap().is_empty());
+        assert!(matches!(chatbix.thread(message.id, None).unwrap_err().kind(), ErrorKind::UnknownMessage));
+        assert_eq!(chatbix.channels(Some(&member)).unwrap().len(), 1);
+        assert!(chatbix.channels(Some(&other)).unwrap().is_empty());
+        assert!(matches!(chatbix.channel_members(&other, "secret").unwrap_err().kind(), ErrorKind::UnknownChannel));
+        chatbix.edit_channel_members(&owner, "secret", &[], &["member".to_owned()]).unwrap();
+        assert!(chatbix.channels(Some(&member)).unwrap().is_empty());
+        // making it public again shows it to everyone
+        chatbix.edit_channel(&owner, "secret", None, None, None, Some(false)).unwrap();
+        let (messages, _, _) = chatbix.messages(Interval::default(), &secret, false, Some(&other)).unwrap();
+        assert_eq!(contents(&messages), vec!["hidden treasure"]);
+    }
+
+    #[test]
+    fn direct_conversations_are_opened_once_and_listed_for_their_participants() {
+        let chatbix = chatbix();
+        let alice = chatbix.authenticate(&chatbix.register("alice", "password", None).unwrap()).unwrap();
+        let bob = chatbix.authenticate(&chatbix.register("bob", "password", None).unwrap()).unwrap();
+        let carol = chatbix.authenticate(&chatbix.register("carol", "password", None).unwrap()).unwrap();
+        let error = chatbix.open_direct(&alice, &["alice".to_owned()]).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::InvalidParticipants));
+        let error = chatbix.open_direct(&alice, &["nobody".to_owned()]).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::UnknownUser));
+        let conversation = chatbix.open_direct(&alice, &["bob".to_owned()]).unwrap();
+        assert_eq!(conversation.participants, vec!["alice", "bob"]);
+        assert!(conversation.last_message.is_none());
+        let channel = conversation.channel;
+        chatbix.post_message(&new_message("hi bob", Some(&channel)), Some(&alice)).unwrap();
+        // opened again by the other participant, it is the same conversation
+        let conversation = chatbix.open_direct(&bob, &["alice".to_owned(), "bob".to_owned()]).unwrap();
+        assert_eq!(conversation.channel, channel);
+        assert_eq!(conversation.last_message.unwrap().content, "hi bob");
+        let error = chatbix.post_message(&new_message("hi both", Some(&channel)), Some(&carol)).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::Forbidden));
+        assert_eq!(chatbix.conversations(&bob).unwrap().len(), 1);
+        assert!(chatbix.conversations(&carol).unwrap().is_empty());
+        // the participants are fixed, and the conversation isn't a channel
+        let error = chatbix.edit_channel_members(&alice, &channel, &["carol".to_owned()], &[]).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::Forbidden));
+        assert!(chatbix.channels(Some(&alice)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unread_counts_stop_at_the_read_marker() {
+        let chatbix = chatbix();
+        let alice = chatbix.authenticate(&chatbix.register("alice", "password", None).unwrap()).unwrap();
+        let bob = chatbix.authenticate(&chatbix.register("bob", "password", None).unwrap()).unwrap();
+        let rust = vec!["rust".to_owned()];
+        let first = chatbix.post_message(&new_message("hello", None), Some(&bob)).unwrap();
+        let second = chatbix.post_message(&new_message("hi @alice", None), Some(&bob)).unwrap();
+        chatbix.post_message(&new_message("my own", None), Some(&alice)).unwrap();
+        chatbix.post_message(&new_message("crabs", Some("rust")), Some(&bob)).unwrap();
+        let unread = chatbix.unread(&alice, &rust, true).unwrap();
+        assert_eq!((unread[""].count, unread[""].mentions), (2, 1));
+        assert_eq!((unread["rust"].count, unread["rust"].mentions), (1, 0));
+        chatbix.mark_read(&alice, first.id).unwrap();
+        let unread = chatbix.unread(&alice, &rust, true).unwrap();
+        assert_eq!((unread[""].count, unread[""].mentions), (1, 1));
+        chatbix.mark_read(&alice, second.id).unwrap();
+        // the marker never moves back
+        chatbix.mark_read(&alice, first.id).unwrap();
+        let unread = chatbix.unread(&alice, &rust, false).unwrap();
+        assert!(!unread.contains_key(""));
+        assert_eq!(unread["rust"].count, 1);
+        let unread = chatbix.unread(&alice, &[], true).unwrap();
+        assert_eq!(unread[""].count, 0);
+    }
+
+    #[test]
+    fn mentions_of_registered_users_are_recorded() {
+        let chatbix = chatbix();
+        let alice = chatbix.authenticate(&chatbix.register("alice", "password", None).unwrap()).unwrap();
+        let bob = chatbix.authenticate(&chatbix.register("bob", "password", None).unwrap()).unwrap();
+        let first = chatbix.post_message(&new_message("hi @alice and @nobody", None), Some(&bob)).unwrap();
+        chatbix.post_message(&new_message("talking to myself @alice", None), Some(&alice)).unwrap();
+        chatbix.post_message(&NewMessage { tags: Some(8), ..new_message("quietly @alice", None) }, Some(&bob)).unwrap();
+        let second = chatbix.post_message(&new_message("@alice again", Some("rust")), Some(&bob)).unwrap();
+        let mentions = chatbix.mentions(&alice, None, 50).unwrap();
+        assert_eq!(mentions.iter().map(|m| m.id).collect::<Vec<_>>(), vec![second.id, first.id]);
+        let mentions = chatbix.mentions(&alice, Some(second.id), 50).unwrap();
+        assert_eq!(contents(&mentions), vec!["hi @alice and @nobody"]);
+        assert_eq!(chatbix.mentions(&alice, None, 1).unwrap().len(), 1);
+        assert!(chatbix.mentions(&bob, None, 50).unwrap().is_empty());
+        let unread = chatbix.unread(&alice, &[], true).unwrap();
+        assert_eq!((unread[""].count, unread[""].mentions), (2, 1));
+        chatbix.try_del(&admin(), second.id, None).unwrap();
+        assert_eq!(chatbix.mentions(&alice, None, 50).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn events_are_published_to_those_who_can_see_them() {
+        let chatbix = chatbix();
+        let owner = chatbix.authenticate(&chatbix.register("owner", "password", None).unwrap()).unwrap();
+        chatbix.register("other", "password", None).unwrap();
+        chatbix.new_channel(&owner, "secret", None, None, true).unwrap();
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let received = published.clone();
+        chatbix.hub().subscribe(move |event: &Published| {
+            received.lock().unwrap().push(event.clone());
+            true
+        });
+        let message = chatbix.post_message(&new_message("hello", None), None).unwrap();
+        chatbix.post_message(&new_message("psst", Some("secret")), Some(&owner)).unwrap();
+        chatbix.try_del(&admin(), message.id, Some("spam")).unwrap();
+        let published = published.lock().unwrap();
+        assert_eq!(published.len(), 3);
+        let secret = vec!["secret".to_owned()];
+        assert!(published[0].is_for(None, &[], true));
+        assert!(!published[0].is_for(None, &secret, false));
+        assert!(published[1].is_for(Some("owner"), &secret, false));
+        assert!(!published[1].is_for(Some("other"), &secret, false));
+        assert!(!published[1].is_for(None, &secret, true));
+        match published[2].event {
+            Event::Deleted(ref tombstone, None) => assert_eq!(tombstone.reason.as_deref(), Some("spam")),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn wait_messages_waits_when_there_is_nothing_new() {
+        let chatbix = chatbix();
+        chatbix.post_message(&new_message("hello", None), None).unwrap();
+        let change_id = chatbix.last_change_id().unwrap();
+        let start = Instant::now();
+        let (messages, deleted, _) = chatbix.wait_messages(Interval::AllFromChange(change_id), Vec::new(), true, None, 1).unwrap();
+        assert!(messages.is_empty());
+        assert!(deleted.unwrap().is_empty());
+        assert!(start.elapsed() >= StdDuration::from_secs(1));
+    }
+
+    #[test]
+    fn wait_messages_wakes_up_for_a_new_message() {
+        let chatbix = Arc::new(chatbix());
+        let change_id = chatbix.last_change_id().unwrap();
+        let poster = {
+            let chatbix = chatbix.clone();
+            thread::spawn(move || {
+                thread::sleep(StdDuration::from_millis(100));
+                // neither wakes it up
+                chatbix.heartbeat_mut(None, "someone", true, &[], true).unwrap();
+                chatbix.post_message(&new_message("elsewhere", Some("other")), None).unwrap();
+                chatbix.post_message(&new_message("hello", None), None).unwrap();
+            })
+        };
+        let start = Instant::now();
+        let (messages, _, next_change_id) = chatbix.wait_messages(Interval::AllFromChange(change_id), Vec::new(), true, None, 30).unwrap();
+        poster.join().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "hello");
+        assert!(next_change_id > change_id);
+        assert!(start.elapsed() < StdDuration::from_secs(30));
+    }
+
+    #[test]
+    fn wait_messages_fails_when_too_many_are_waiting() {
+        let chatbix = chatbix().with_config(Config { max_waiting: 1, ..config() });
+        let slot = chatbix.waiting_slot().unwrap();
+        match chatbix.wait_messages(Interval::AllFromChange(0), Vec::new(), true, None, 1) {
+            Err(Error(ErrorKind::TooManyWaiting, _)) => {},
+            other => panic!("expected TooManyWaiting, got {:?}", other.map(|(messages, _, _)| messages.len())),
+        }
+        drop(slot);
+        assert!(chatbix.wait_messages(Interval::AllFromChange(0), Vec::new(), true, None, 1).is_ok());
+    }
+
+    #[test]
+    fn wait_messages_unsubscribes_once_over() {
+        let chatbix = chatbix();
+        chatbix.wait_messages(Interval::AllFromChange(0), Vec::new(), true, None, 1).unwrap();
+        assert_eq!(chatbix.hub.listener_count(), 0);
+        chatbix.post_message(&new_message("hello", None), None).unwrap();
+        let change_id = chatbix.last_change_id().unwrap();
+        // returns at once, there is something new
+        chatbix.wait_messages(Interval::AllFromChange(change_id - 1), Vec::new(), true, None, 60).unwrap();
+        assert_eq!(chatbix.hub.listener_count(), 0);
+    }
+
+    #[test]
+    fn typing_is_published_until_the_message_is_sent() {
+        let chatbix = chatbix();
+        let owner = chatbix.authenticate(&chatbix.register("owner", "password", None).unwrap()).unwrap();
+        chatbix.new_channel(&owner, "secret", None, None, true).unwrap();
+        let typing = Arc::new(Mutex::new(Vec::new()));
+        {
+            let typing = typing.clone();
+            chatbix.hub().subscribe(move |published| {
+                if let Event::Typing(ref t) = published.event {
+                    typing.lock().unwrap().push(t.usernames.clone());
+                }
+                true
+            });
+        }
+        chatbix.set_typing(None, "someone", None, true).unwrap();
+        chatbix.set_typing(None, "someone", None, true).unwrap();
+        assert_eq!(chatbix.typing_in(&[], true, None).unwrap()[""], vec!["someone"]);
+        chatbix.post_message(&new_message("hello", None), None).unwrap();
+        assert_eq!(*typing.lock().unwrap(), vec![vec!["someone".to_owned()], vec![]]);
+        assert!(chatbix.typing_in(&[], true, None).unwrap()[""].is_empty());
+        let error = chatbix.set_typing(None, "someone", Some("secret"), true).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::Forbidden));
+        chatbix.set_typing(Some(&owner), "", Some("secret"), true).unwrap();
+        let secret = vec!["secret".to_owned()];
+        assert_eq!(chatbix.typing_in(&secret, false, Some(&owner)).unwrap()["secret"], vec!["owner"]);
+        assert!(chatbix.typing_in(&secret, false, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn statuses_are_kept_and_shown_once_connected() {
+        let chatbix = chatbix();
+        let someone = chatbix.authenticate(&chatbix.register("someone", "password", None).unwrap()).unwrap();
+        assert_eq!(chatbix.status(&someone).unwrap().state, PresenceState::Online);
+        let status = chatbix.set_status(&someone, "dnd", Some("  in a meeting "), Some(":calendar:")).unwrap();
+        assert_eq!(status.status_text.as_deref(), Some("in a meeting"));
+        let error = chatbix.set_status(&someone, "asleep", None, None).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::InvalidStatus));
+        let error = chatbix.set_status(&someone, "online", Some(&"a".repeat(MAX_STATUS_TEXT_LENGTH + 1)), None).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::InvalidStatus));
+        let error = chatbix.set_status(&someone, "online", None, Some("two words")).unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::InvalidEmoji));
+        // read from the storage when connecting
+        let connected = chatbix.heartbeat_mut(Some(&someone), "someone", true, &[], true).unwrap();
+        assert_eq!(connected[0].state, PresenceState::Dnd);
+        assert_eq!(connected[0].status_emoji.as_deref(), Some(":calendar:"));
+        chatbix.set_status(&someone, "invisible", None, None).unwrap();
+        assert!(chatbix.heartbeat(&[], true, None).unwrap().is_empty());
+        assert_eq!(chatbix.status(&someone).unwrap().state, PresenceState::Invisible);
+    }
+
+    #[test]
+    fn readers_of_private_channels_are_only_listed_to_their_members() {
+        let chatbix = chatbix();
+        let owner = chatbix.authenticate(&chatbix.register("owner", "password", None).unwrap()).unwrap();
+        chatbix.new_channel(&owner, "secret", None, None, true).unwrap();
+        let secret = vec!["secret".to_owned()];
+        chatbix.heartbeat_mut(Some(&owner), "", true, &secret, false).unwrap();
+        chatbix.heartbeat_mut(None, "someone", true, &secret, true).unwrap();
+        let by_channel = chatbix.users_by_channel(&secret, true, Some(&owner)).unwrap();
+        assert_eq!(by_channel["secret"], vec!["owner"]);
+        assert_eq!(by_channel[""], vec!["someone"]);
+        let by_channel = chatbix.users_by_channel(&secret, true, None).unwrap();
+        assert!(!by_channel.contains_key("secret"));
+        assert!(chatbix.heartbeat(&secret, false, None).unwrap().is_empty());
+        assert_eq!(chatbix.heartbeat(&secret, false, Some(&owner)).unwrap().len(), 1);
+    }
+}
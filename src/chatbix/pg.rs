@@ -0,0 +1,638 @@
+use std::sync::{Arc,RwLock,Weak};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::cmp::{max,min};
+use std::time::Duration;
+use std::collections::HashMap;
+use super::{Chatbix,ChatbixInterface,Interval,Match};
+use message::{NewMessage,Message,Revision,Tombstone,Reaction};
+use channel::{Channel,Unread};
+use user::{ConnectedUsers,TypingUsers,CachedUsers,StoredUser,Session,UserStatus,PresenceState};
+use config::Config;
+use hub::Hub;
+use utils::{now,Slots};
+use chrono::NaiveDateTime;
+
+use error::*;
+use r2d2::{Pool,PooledConnection};
+
+use r2d2_postgres::PostgresConnectionManager as PgConnection;
+use postgres::{Connection,TlsMode};
+use postgres::rows::Row;
+use fallible_iterator::FallibleIterator;
+
+/// how long to wait for a notification before checking that the connection is still alive, in seconds
+const LISTEN_TIMEOUT_SECS: u64 = 30;
+/// the longest wait between two attempts to listen to the notifications, in seconds
+const MAX_LISTEN_BACKOFF_SECS: u64 = 60;
+
+// TODO: use get_opt instead of `get`, so that it doesnt crash when the columns are changed
+fn message_from_row(row: Row) -> Message {
+    let deleted_at : Option<NaiveDateTime> = row.get("deleted_at");
+    let pinned_at : Option<NaiveDateTime> = row.get("pinned_at");
+    Message {
+        id: row.get("id"),
+        author: row.get("author"),
+        timestamp: row.get("timestamp"),
+        content: row.get("content"),
+        tags: row.get("tags"),
+        color: row.get("color"),
+        channel: row.get("channel"),
+        reply_to: row.get("reply_to"),
+        reply_count: 0,
+        reactions: Vec::new(),
+        pinned: pinned_at.is_some(),
+        edited_at: row.get("edited_at"),
+        change_id: row.get("change_id"),
+        created_change_id: row.get("created_change_id"),
+        deleted: deleted_at.map(|deleted_at| Tombstone {
+            id: row.get("id"),
+            deleted_by: row.get::<_, Option<String>>("deleted_by").unwrap_or_default(),
+            deleted_at,
+            reason: row.get("delete_reason"),
+        }),
+    }
+}
+
+pub type PgPool = Pool<PgConnection>;
+
+const CHANNEL_QUERY: &str = "SELECT chat_channels.*, \
+    (SELECT COUNT(*) FROM chat_messages WHERE channel = chat_channels.name AND deleted_at IS NULL) AS message_count, \
+    (SELECT MAX(timestamp) FROM chat_messages WHERE channel = chat_channels.name AND deleted_at IS NULL) AS last_activity \
+    FROM chat_channels";
+
+fn channel_from_row(row: Row) -> Channel {
+    Channel {
+        name: row.get("name"),
+        topic: row.get("topic"),
+        description: row.get("description"),
+        creator: row.get("creator"),
+        created_at: row.get("created_at"),
+        archived: row.get("archived"),
+        private: row.get("private"),
+        direct: row.get("direct"),
+        message_count: row.get("message_count"),
+        last_activity: row.get("last_activity"),
+    }
+}
+
+/// LISTENs to `chatbix_messages` on a connection of its own and publishes the notified messages,
+/// until the connection is lost, or until the last Arc<Chatbix> is dropped, in which case it returns Ok.
+/// The connection is closed when this returns, before another one is opened.
+fn receive_notifications(database_url: &str, chatbix_weak: &Weak<Chatbix<PgPool>>) -> Result<()> {
+    let pg = Connection::connect(database_url, TlsMode::None)?;
+    pg.execute("LISTEN chatbix_messages",&[])?;
+    match chatbix_weak.upgrade() {
+        Some(chatbix) => chatbix.listening.store(true, Ordering::SeqCst),
+        None => return Ok(()),
+    }
+    let notifications = pg.notifications();
+    let mut notifications = notifications.timeout_iter(Duration::from_secs(LISTEN_TIMEOUT_SECS));
+    loop {
+        let notification = notifications.next()?;
+        let chatbix = match chatbix_weak.upgrade() {
+            Some(chatbix) => chatbix,
+            None => return Ok(()),
+        };
+        match notification {
+            Some(notification) => if let Ok(message_id) = notification.payload.parse::<i32>() {
+                if let Err(e) = chatbix.publish_notified(message_id) {
+                    println!("Unexpected error while publishing message {}: `{}`", message_id, e);
+                }
+            },
+            // a connection which silently died would otherwise be waited on forever
+            None => if pg.is_desynchronized() {
+                bail!("the connection is desynchronized");
+            } else {
+                pg.execute("SELECT 1",&[])?;
+            },
+        }
+    }
+}
+
+impl Chatbix<PgPool> {
+    /// Receives the notifications of `notify_message` on a connection of its own to `database_url`,
+    /// rather than on one of the pool, which would never be given back.
+    /// Without it, only the messages of this instance are published.
+    pub fn with_notifications(mut self, database_url: &str) -> Chatbix<PgPool> {
+        self.notifications_url = Some(database_url.to_owned());
+        self
+    }
+}
+
+impl ChatbixInterface for Chatbix<PgPool> {
+
+    // TODO: change InitParams into (&'a str,TlsMode<'h>)
+    // so that the connection is init here instead of outside
+    // UPDATE: ^not sure that it's the right thing to do ...
+    type InitParams = PgPool;
+
+    fn new(init_params: Self::InitParams) -> Chatbix<PgPool> {
+        Chatbix {
+            connection: init_params,
+            connected_users: RwLock::new(ConnectedUsers::new()),
+            typing_users: RwLock::new(TypingUsers::new()),
+            cached_users: RwLock::new(CachedUsers::new()),
+            hub: Hub::new(),
+            config: Config::default(),
+            waiting: Slots::new(Config::default().max_waiting),
+            notifications_url: None,
+            listening: AtomicBool::new(false),
+        }
+    }
+
+    fn new_message(&self, author: &str, tags: i32, reply_to: Option<i32>, new_message: &NewMessage) -> Result<Message> {
+        let timestamp : NaiveDateTime = now();
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        // see chat_next_change_id() in the migrations for why change_ids don't come from a sequence
+        let rows = pg.query("WITH change AS (SELECT chat_next_change_id() AS change_id) \
+                             INSERT INTO chat_messages (author, timestamp, content, tags, color, channel, reply_to, change_id, created_change_id) \
+                             VALUES ($1, $2, $3, $4, $5, $6, $7, (SELECT change_id FROM change), (SELECT change_id FROM change)) RETURNING *",
+                            &[&author, &timestamp, &new_message.content, &tags, &new_message.color, &new_message.channel, &reply_to])?;
+        if let Some(reply_to) = reply_to {
+            // so that the new reply count reaches the polling clients
+            pg.execute("UPDATE chat_messages SET change_id = chat_next_change_id() WHERE id = $1", &[&reply_to])?;
+        }
+        Ok(message_from_row(rows.get(0)))
+    }
+
+    fn get_message(&self, id: i32) -> Result<Option<Message>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT * FROM chat_messages WHERE id = $1", &[&id])?;
+        Ok(rows.into_iter().next().map(message_from_row))
+    }
+
+    fn add_mentions(&self, message_id: i32, usernames: &[String]) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("INSERT INTO chat_mentions (message_id, username) SELECT $1, unnest($2::text[]) ON CONFLICT DO NOTHING",
+                   &[&message_id, &usernames])?;
+        Ok(())
+    }
+
+    fn get_mentions(&self, username: &str, before: Option<i32>, limit: i64, hidden_channels: &[String]) -> Result<Vec<Message>> {
+        let limit = max(limit, 0);
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT chat_messages.* FROM chat_mentions JOIN chat_messages ON chat_messages.id = chat_mentions.message_id \
+                             WHERE chat_mentions.username = $1 AND ($2::integer IS NULL OR chat_messages.id < $2) \
+                             AND chat_messages.deleted_at IS NULL AND (channel IS NULL OR NOT (channel = ANY ($4))) \
+                             ORDER BY chat_messages.id DESC LIMIT $3",
+                            &[&username, &before, &limit, &hidden_channels])?;
+        Ok(rows.into_iter().map(message_from_row).collect())
+    }
+
+    fn edit_message(&self, id: i32, content: &str, edited_by: &str) -> Result<Message> {
+        let edited_at : NaiveDateTime = now();
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let transaction = pg.transaction()?;
+        transaction.execute("INSERT INTO chat_message_revisions (message_id, content, edited_at, edited_by) \
+                             SELECT id, content, $2, $3 FROM chat_messages WHERE id = $1",
+                            &[&id, &edited_at, &edited_by])?;
+        let message = {
+            let rows = transaction.query("UPDATE chat_messages SET content = $2, edited_at = $3, change_id = chat_next_change_id() \
+                                          WHERE id = $1 RETURNING *",
+                                         &[&id, &content, &edited_at])?;
+            match rows.into_iter().next() {
+                Some(row) => message_from_row(row),
+                None => bail!(ErrorKind::UnknownMessage),
+            }
+        };
+        transaction.commit()?;
+        Ok(message)
+    }
+
+    fn get_thread(&self, root_id: i32) -> Result<Vec<Message>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT * FROM chat_messages WHERE (id = $1 OR reply_to = $1) AND deleted_at IS NULL ORDER BY timestamp ASC, id ASC",
+                            &[&root_id])?;
+        Ok(rows.into_iter().map(message_from_row).collect())
+    }
+
+    fn get_reply_counts(&self, ids: &[i32]) -> Result<HashMap<i32, i64>> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT reply_to, COUNT(*) AS count FROM chat_messages \
+                             WHERE reply_to = ANY ($1) AND deleted_at IS NULL GROUP BY reply_to",
+                            &[&ids])?;
+        Ok(rows.iter().map(|row| (row.get("reply_to"), row.get("count"))).collect())
+    }
+
+    fn add_reaction(&self, message_id: i32, username: &str, emoji: &str) -> Result<()> {
+        let timestamp : NaiveDateTime = now();
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let added = pg.execute("INSERT INTO chat_reactions (message_id, username, emoji, created_at) VALUES ($1, $2, $3, $4) \
+                                ON CONFLICT DO NOTHING",
+                               &[&message_id, &username, &emoji, &timestamp])?;
+        if added > 0 {
+            // so that the reaction reaches the polling clients
+            pg.execute("UPDATE chat_messages SET change_id = chat_next_change_id() WHERE id = $1", &[&message_id])?;
+        }
+        Ok(())
+    }
+
+    fn remove_reaction(&self, message_id: i32, username: &str, emoji: &str) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let removed = pg.execute("DELETE FROM chat_reactions WHERE message_id = $1 AND username = $2 AND emoji = $3",
+                                 &[&message_id, &username, &emoji])?;
+        if removed > 0 {
+            pg.execute("UPDATE chat_messages SET change_id = chat_next_change_id() WHERE id = $1", &[&message_id])?;
+        }
+        Ok(())
+    }
+
+    fn get_reactions(&self, ids: &[i32]) -> Result<Vec<Reaction>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT message_id, username, emoji FROM chat_reactions WHERE message_id = ANY ($1) ORDER BY created_at ASC",
+                            &[&ids])?;
+        Ok(rows.iter().map(|row| Reaction {
+            message_id: row.get("message_id"),
+            username: row.get("username"),
+            emoji: row.get("emoji"),
+        }).collect())
+    }
+
+    fn set_pinned(&self, message_id: i32, pinned_by: Option<&str>) -> Result<()> {
+        let pinned_at : Option<NaiveDateTime> = pinned_by.map(|_| now());
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("UPDATE chat_messages SET pinned_at = $2, pinned_by = $3, change_id = chat_next_change_id() WHERE id = $1",
+                   &[&message_id, &pinned_at, &pinned_by])?;
+        Ok(())
+    }
+
+    fn get_pins(&self, channel: Option<&str>) -> Result<Vec<Message>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT * FROM chat_messages WHERE pinned_at IS NOT NULL AND deleted_at IS NULL \
+                             AND channel IS NOT DISTINCT FROM $1 ORDER BY pinned_at DESC",
+                            &[&channel])?;
+        Ok(rows.into_iter().map(message_from_row).collect())
+    }
+
+    fn create_channel(&self, channel: &Channel) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let created = pg.execute("INSERT INTO chat_channels (name, topic, description, creator, created_at, archived, private, direct) \
+                                  VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT DO NOTHING",
+                                 &[&channel.name, &channel.topic, &channel.description, &channel.creator, &channel.created_at,
+                                   &channel.archived, &channel.private, &channel.direct])?;
+        if created == 0 {
+            bail!(ErrorKind::ChannelNameInUse);
+        }
+        Ok(())
+    }
+
+    fn update_channel(&self, channel: &Channel) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("UPDATE chat_channels SET topic = $2, description = $3, archived = $4, private = $5 WHERE name = $1",
+                   &[&channel.name, &channel.topic, &channel.description, &channel.archived, &channel.private])?;
+        Ok(())
+    }
+
+    fn add_channel_member(&self, channel: &str, username: &str) -> Result<()> {
+        let added_at : NaiveDateTime = now();
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("INSERT INTO chat_channel_members (channel, username, added_at) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                   &[&channel, &username, &added_at])?;
+        Ok(())
+    }
+
+    fn remove_channel_member(&self, channel: &str, username: &str) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("DELETE FROM chat_channel_members WHERE channel = $1 AND username = $2",&[&channel, &username])?;
+        Ok(())
+    }
+
+    fn get_channel_members(&self, channel: &str) -> Result<Vec<String>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT username FROM chat_channel_members WHERE channel = $1 ORDER BY username ASC",&[&channel])?;
+        Ok(rows.iter().map(|row| row.get("username")).collect())
+    }
+
+    fn get_hidden_channels(&self, username: Option<&str>) -> Result<Vec<String>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT name FROM chat_channels WHERE private AND name NOT IN \
+                             (SELECT channel FROM chat_channel_members WHERE username IS NOT DISTINCT FROM $1)",
+                            &[&username])?;
+        Ok(rows.iter().map(|row| row.get("name")).collect())
+    }
+
+    fn get_channel(&self, name: &str) -> Result<Option<Channel>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query(&format!("{} WHERE name = $1", CHANNEL_QUERY), &[&name])?;
+        Ok(rows.into_iter().next().map(channel_from_row))
+    }
+
+    fn get_channels(&self) -> Result<Vec<Channel>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query(&format!("{} ORDER BY name ASC", CHANNEL_QUERY), &[])?;
+        Ok(rows.into_iter().map(channel_from_row).collect())
+    }
+
+    fn get_direct_channels(&self, username: &str) -> Result<Vec<Channel>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query(&format!("{} WHERE direct AND name IN (SELECT channel FROM chat_channel_members WHERE username = $1)", CHANNEL_QUERY),
+                            &[&username])?;
+        Ok(rows.into_iter().map(channel_from_row).collect())
+    }
+
+    fn get_last_messages(&self, channels: &[String]) -> Result<Vec<Message>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT DISTINCT ON (channel) * FROM chat_messages WHERE deleted_at IS NULL AND channel = ANY ($1) \
+                             ORDER BY channel, id DESC",
+                            &[&channels])?;
+        Ok(rows.into_iter().map(message_from_row).collect())
+    }
+
+    fn set_read_marker(&self, username: &str, channel: &str, message_id: i32) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("INSERT INTO chat_read_markers (username, channel, last_read_id) VALUES ($1, $2, $3) \
+                    ON CONFLICT (username, channel) DO UPDATE SET last_read_id = GREATEST(chat_read_markers.last_read_id, EXCLUDED.last_read_id)",
+                   &[&username, &channel, &message_id])?;
+        Ok(())
+    }
+
+    fn get_unread(&self, username: &str, channels: &[String], include_default_channel: bool) -> Result<HashMap<String, Unread>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT COALESCE(m.channel, '') AS channel, COUNT(*) AS count, \
+                                    COUNT(mentions.message_id) AS mentions \
+                             FROM (SELECT * FROM chat_messages WHERE deleted_at IS NULL AND author <> $1 \
+                                   AND (($3 AND channel IS NULL) OR channel = ANY ($2))) AS m \
+                             LEFT JOIN chat_read_markers AS r ON r.username = $1 AND r.channel = COALESCE(m.channel, '') \
+                             LEFT JOIN chat_mentions AS mentions ON mentions.message_id = m.id AND mentions.username = $1 \
+                             WHERE m.id > COALESCE(r.last_read_id, 0) \
+                             GROUP BY COALESCE(m.channel, '')",
+                            &[&username, &channels, &include_default_channel])?;
+        Ok(rows.iter().map(|row| (row.get("channel"), Unread {
+            count: row.get("count"),
+            mentions: row.get("mentions"),
+        })).collect())
+    }
+
+    fn get_revisions(&self, message_id: i32) -> Result<Vec<Revision>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT * FROM chat_message_revisions WHERE message_id = $1 ORDER BY id ASC", &[&message_id])?;
+        Ok(rows.into_iter().map(|row| Revision {
+            message_id: row.get("message_id"),
+            content: row.get("content"),
+            edited_at: row.get("edited_at"),
+            edited_by: row.get("edited_by"),
+        }).collect())
+    }
+
+    fn delete_message(&self, id: i32, deleted_by: &str, reason: Option<&str>) -> Result<()> {
+        let deleted_at : NaiveDateTime = now();
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let deleted = pg.execute("UPDATE chat_messages SET deleted_at = $2, deleted_by = $3, delete_reason = $4, change_id = chat_next_change_id() \
+                                  WHERE id = $1 AND deleted_at IS NULL",
+                                 &[&id, &deleted_at, &deleted_by, &reason])?;
+        if deleted == 0 {
+            bail!(ErrorKind::UnknownMessage);
+        }
+        // the reply count of the root changed
+        pg.execute("UPDATE chat_messages SET change_id = chat_next_change_id() WHERE id = (SELECT reply_to FROM chat_messages WHERE id = $1)",
+                   &[&id])?;
+        Ok(())
+    }
+
+    fn purge_message(&self, id: i32) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let purged = pg.execute("DELETE FROM chat_messages WHERE id = $1",&[&id])?;
+        if purged == 0 {
+            bail!(ErrorKind::UnknownMessage);
+        }
+        Ok(())
+    }
+
+    fn get_messages<V: AsRef<[String]>>(&self, interval: Interval, channels: V, include_default_channel: bool) -> Result<Vec<Message>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = match interval {
+            Interval::Last(last) => {
+                if include_default_channel {
+                    pg.query("SELECT * FROM (SELECT * FROM chat_messages WHERE deleted_at IS NULL AND (channel IS NULL OR channel = ANY ($1)) ORDER BY timestamp DESC LIMIT $2) as pote ORDER BY timestamp ASC;",
+                             &[&channels.as_ref(),&last])
+                } else {
+                    pg.query("SELECT * FROM (SELECT * FROM chat_messages WHERE deleted_at IS NULL AND channel = ANY ($1) ORDER BY timestamp DESC LIMIT $2) as pote ORDER BY timestamp ASC;",
+                             &[&channels.as_ref(),&last])
+                }
+            },
+            // the messages changed since the last one sent by then come along with the new ones
+            Interval::AllFromTimestamp(timestamp) =>
+                if include_default_channel {
+                    pg.query("SELECT * FROM chat_messages WHERE (chat_messages.timestamp > $1 OR chat_messages.change_id > (SELECT MAX(created_change_id) FROM chat_messages WHERE timestamp <= $1)) AND (channel IS NULL OR channel = ANY ($2)) ORDER BY timestamp ASC;",
+                             &[&timestamp,&channels.as_ref()])
+                } else {
+                    pg.query("SELECT * FROM chat_messages WHERE (chat_messages.timestamp > $1 OR chat_messages.change_id > (SELECT MAX(created_change_id) FROM chat_messages WHERE timestamp <= $1)) AND channel = ANY ($2) ORDER BY timestamp ASC;",
+                             &[&timestamp,&channels.as_ref()])
+                },
+            Interval::FromToTimestamp(timestamp, timestamp_end) =>
+                if include_default_channel {
+                    pg.query("SELECT * FROM chat_messages WHERE deleted_at IS NULL AND chat_messages.timestamp > $1 AND chat_messages.timestamp < $2 AND (channel IS NULL OR channel = ANY ($3)) ORDER BY timestamp ASC;",
+                             &[&timestamp,&timestamp_end,&channels.as_ref()])
+                } else { 
+                    pg.query("SELECT * FROM chat_messages WHERE deleted_at IS NULL AND chat_messages.timestamp > $1 AND chat_messages.timestamp < $2 AND channel = ANY ($3) ORDER BY timestamp ASC;",
+                             &[&timestamp,&timestamp_end,&channels.as_ref()])
+                },
+            // the messages changed since message `id` was sent come along with the new ones
+            Interval::AllFromId(id) =>
+                if include_default_channel {
+                    pg.query("SELECT * FROM chat_messages WHERE (chat_messages.id > $1 OR chat_messages.change_id > (SELECT created_change_id FROM chat_messages WHERE id = $1)) AND (channel IS NULL OR channel = ANY ($2)) ORDER BY timestamp ASC;",
+                             &[&id,&channels.as_ref()])
+                } else {
+                    pg.query("SELECT * FROM chat_messages WHERE (chat_messages.id > $1 OR chat_messages.change_id > (SELECT created_change_id FROM chat_messages WHERE id = $1)) AND channel = ANY ($2) ORDER BY timestamp ASC;",
+                             &[&id,&channels.as_ref()])
+                },
+            Interval::AllFromChange(change_id) =>
+                if include_default_channel {
+                    pg.query("SELECT * FROM chat_messages WHERE chat_messages.change_id > $1 AND (channel IS NULL OR channel = ANY ($2)) ORDER BY timestamp ASC;",
+                             &[&change_id,&channels.as_ref()])
+                } else {
+                    pg.query("SELECT * FROM chat_messages WHERE chat_messages.change_id > $1 AND channel = ANY ($2) ORDER BY timestamp ASC;",
+                             &[&change_id,&channels.as_ref()])
+                },
+        }?;
+        // TODO : collect rows into Result<Vec, Err> instead
+        Ok(rows.into_iter().map(message_from_row).collect())
+    }
+
+    /// reads the committed counter rather than MAX(change_id): as changes take their change_id
+    /// while locking it, every change up to this one has been committed as well
+    fn last_change_id(&self) -> Result<i64> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT change_id FROM chat_last_change", &[])?;
+        Ok(rows.get(0).get("change_id"))
+    }
+    
+    fn create_user(&self, username: &str, password_hash: &str) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        // a single statement, so that two users registering the same name at once can't both succeed
+        let inserted = pg.execute("INSERT INTO chat_users (username, password) VALUES ($1, $2) ON CONFLICT DO NOTHING",&[&username,&password_hash])?;
+        if inserted == 0 {
+            bail!(ErrorKind::UsernameInUse);
+        }
+        Ok(())
+    }
+
+    fn get_user(&self, username: &str) -> Result<Option<StoredUser>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT password, admin FROM chat_users WHERE username = $1",&[&username])?;
+        Ok(rows.into_iter().next().map(|r| StoredUser {
+            password: r.get("password"),
+            admin: r.get("admin"),
+        }))
+    }
+
+    fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("UPDATE chat_users SET password = $2 WHERE username = $1",&[&username,&password_hash])?;
+        Ok(())
+    }
+
+    fn set_user_status(&self, username: &str, status: &UserStatus) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("INSERT INTO chat_user_statuses (username, state, status_text, status_emoji) VALUES ($1, $2, $3, $4) \
+                    ON CONFLICT (username) DO UPDATE SET state = EXCLUDED.state, status_text = EXCLUDED.status_text, \
+                                                         status_emoji = EXCLUDED.status_emoji",
+                   &[&username, &status.state.as_str(), &status.status_text, &status.status_emoji])?;
+        Ok(())
+    }
+
+    fn get_user_status(&self, username: &str) -> Result<Option<UserStatus>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT state, status_text, status_emoji FROM chat_user_statuses WHERE username = $1",&[&username])?;
+        match rows.into_iter().next() {
+            Some(row) => Ok(Some(UserStatus {
+                state: PresenceState::parse(&row.get::<_, String>("state"))?,
+                status_text: row.get("status_text"),
+                status_emoji: row.get("status_emoji"),
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn store_session(&self, session: &Session) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("INSERT INTO chat_sessions (key_hash, username, created_at, last_used, user_agent) \
+                    VALUES ($1, $2, $3, $4, $5)",
+                   &[&session.key_hash, &session.username, &session.created_at, &session.last_used, &session.user_agent])?;
+        Ok(())
+    }
+
+    fn load_session(&self, key_hash: &str) -> Result<Option<(Session, bool)>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT chat_sessions.*, chat_users.admin FROM chat_sessions \
+                             JOIN chat_users ON chat_users.username = chat_sessions.username \
+                             WHERE key_hash = $1",&[&key_hash])?;
+        Ok(rows.into_iter().next().map(|r| (Session {
+            key_hash: r.get("key_hash"),
+            username: r.get("username"),
+            created_at: r.get("created_at"),
+            last_used: r.get("last_used"),
+            user_agent: r.get("user_agent"),
+        }, r.get("admin"))))
+    }
+
+    fn touch_session(&self, key_hash: &str, last_used: NaiveDateTime) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("UPDATE chat_sessions SET last_used = $2 WHERE key_hash = $1",&[&key_hash,&last_used])?;
+        Ok(())
+    }
+
+    fn delete_session(&self, key_hash: &str) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("DELETE FROM chat_sessions WHERE key_hash = $1",&[&key_hash])?;
+        Ok(())
+    }
+
+    fn get_sessions(&self, username: &str) -> Result<Vec<Session>> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("SELECT * FROM chat_sessions WHERE username = $1 ORDER BY last_used DESC",&[&username])?;
+        Ok(rows.into_iter().map(|r| Session {
+            key_hash: r.get("key_hash"),
+            username: r.get("username"),
+            created_at: r.get("created_at"),
+            last_used: r.get("last_used"),
+            user_agent: r.get("user_agent"),
+        }).collect())
+    }
+
+    fn delete_other_sessions(&self, username: &str, key_hash: &str) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("DELETE FROM chat_sessions WHERE username = $1 AND key_hash <> $2",&[&username,&key_hash])?;
+        Ok(())
+    }
+
+    fn delete_expired_sessions(&self, created_before: NaiveDateTime, used_before: NaiveDateTime) -> Result<()> {
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("DELETE FROM chat_sessions WHERE created_at < $1 OR last_used < $2",&[&created_before,&used_before])?;
+        Ok(())
+    }
+
+    /// Returns false while the notifications aren't being received, as nobody would publish the message
+    fn notify_message(&self, message_id: i32) -> Result<bool> {
+        if !self.listening.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        pg.execute("SELECT pg_notify('chatbix_messages', $1)",&[&message_id.to_string()])?;
+        Ok(true)
+    }
+
+    /// Listens to `chatbix_messages` with a connection of its own (see `with_notifications`), in a thread
+    /// which stops after the last Arc<Chatbix> is dropped.
+    ///
+    /// The connection is opened again whenever it is lost, waiting longer and longer between the attempts.
+    /// postgres panics instead of returning an error when the server ends the connection while it waits
+    /// for a notification, so each connection is listened to in a thread of its own, whose end is noticed
+    /// by `join` either way.
+    fn listen_notifications(chatbix: Arc<Self>) {
+        let database_url = match chatbix.notifications_url {
+            Some(ref database_url) => database_url.clone(),
+            None => return,
+        };
+        let chatbix_weak = Arc::downgrade(&chatbix);
+        thread::spawn(move || {
+            let mut backoff = 1;
+            while chatbix_weak.upgrade().is_some() {
+                let result = {
+                    let database_url = database_url.clone();
+                    let chatbix_weak = chatbix_weak.clone();
+                    thread::spawn(move || receive_notifications(&database_url, &chatbix_weak)).join()
+                };
+                let chatbix = match chatbix_weak.upgrade() {
+                    Some(chatbix) => chatbix,
+                    None => break,
+                };
+                if chatbix.listening.swap(false, Ordering::SeqCst) {
+                    backoff = 1;
+                }
+                drop(chatbix);
+                match result {
+                    Ok(Ok(())) => {},
+                    Ok(Err(e)) => println!("Not listening to postgres notifications: `{}`, trying again in {}s", e, backoff),
+                    Err(_) => println!("Lost the connection listening to postgres notifications, trying again in {}s", backoff),
+                }
+                thread::sleep(Duration::from_secs(backoff));
+                backoff = min(backoff * 2, MAX_LISTEN_BACKOFF_SECS);
+            }
+        });
+    }
+
+    fn fulltext_search(&self, query: &str, limit: i64, hidden_channels: &[String]) -> Result<Vec<Match>> {
+        let limit = max(limit, 0);
+        let pg : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let rows = pg.query("select author, content, ts_rank(tsv, query) as rank
+                             from chat_messages,
+                                  to_tsquery($1) as query
+                             where tsv @@ query and deleted_at is null
+                               and (channel is null or not (channel = any ($3)))
+                             order by rank desc
+                             limit $2", &[&query, &limit, &hidden_channels])?;
+        Ok(rows.iter().map(|r| Match {
+            user: r.get("author"),
+            message: r.get("content"),
+            rank: r.get("rank"),
+        }).collect())
+    }
+}
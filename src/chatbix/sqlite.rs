@@ -0,0 +1,738 @@
+use std::sync::{Arc,RwLock};
+use std::sync::atomic::AtomicBool;
+use std::cmp::max;
+use std::collections::HashMap;
+use std::time::Duration;
+use super::{Chatbix,ChatbixInterface,Interval,Match};
+use message::{NewMessage,Message,Revision,Tombstone,Reaction};
+use channel::{Channel,Unread};
+use user::{ConnectedUsers,TypingUsers,CachedUsers,StoredUser,Session,UserStatus,PresenceState};
+use config::Config;
+use hub::Hub;
+use utils::{now,words,Slots};
+use chrono::NaiveDateTime;
+
+use error::*;
+use r2d2::{Pool,PooledConnection,ManageConnection};
+
+use rusqlite::{self,Connection,Row,ErrorCode};
+use rusqlite::types::{ToSql,ToSqlOutput,FromSql,FromSqlResult,FromSqlError,ValueRef};
+
+pub type SqlitePool = Pool<SqliteConnectionManager>;
+
+/// How long a connection waits for the others to release their lock before giving up
+/// with `SQLITE_BUSY`
+const BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// Opens the connections of the pool to the database file.
+///
+/// The database is switched to WAL so that readers don't block the writer,
+/// writers still take turns and wait for each other up to `BUSY_TIMEOUT_MS`.
+pub struct SqliteConnectionManager {
+    path: String,
+}
+
+impl SqliteConnectionManager {
+    pub fn new(path: &str) -> SqliteConnectionManager {
+        SqliteConnectionManager {
+            path: path.to_owned(),
+        }
+    }
+}
+
+impl ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> ::std::result::Result<Connection, rusqlite::Error> {
+        let connection = Connection::open(&self.path)?;
+        connection.busy_timeout(Duration::from_millis(BUSY_TIMEOUT_MS))?;
+        // journal_mode answers with the mode, which execute_batch ignores
+        connection.execute_batch("PRAGMA journal_mode = WAL;")?;
+        Ok(connection)
+    }
+
+    fn is_valid(&self, connection: &mut Connection) -> ::std::result::Result<(), rusqlite::Error> {
+        connection.execute_batch("")
+    }
+
+    fn has_broken(&self, _connection: &mut Connection) -> bool {
+        false
+    }
+}
+
+/// a lock held for longer than the busy timeout is a busy database, not a bug
+fn sql_error(error: rusqlite::Error) -> Error {
+    match error {
+        rusqlite::Error::SqliteFailure(ref failure, _)
+            if failure.code == ErrorCode::DatabaseBusy || failure.code == ErrorCode::DatabaseLocked =>
+                Error::from_kind(ErrorKind::DatabaseBusy),
+        error => Error::from(error),
+    }
+}
+
+/// The format of the timestamps stored as text, which sorts like the timestamps themselves.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// a timestamp as stored by sqlite, which has no type of its own for them
+struct SqlTimestamp(NaiveDateTime);
+
+impl ToSql for SqlTimestamp {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::from(self.0.format(TIMESTAMP_FORMAT).to_string()))
+    }
+}
+
+impl FromSql for SqlTimestamp {
+    fn column_result(value: ValueRef) -> FromSqlResult<SqlTimestamp> {
+        let text = value.as_str()?;
+        NaiveDateTime::parse_from_str(text, TIMESTAMP_FORMAT)
+            .map(SqlTimestamp)
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+fn timestamp(row: &Row, index: usize) -> NaiveDateTime {
+    row.get::<_, SqlTimestamp>(index).0
+}
+
+fn optional_timestamp(row: &Row, index: usize) -> Option<NaiveDateTime> {
+    row.get::<_, Option<SqlTimestamp>>(index).map(|t| t.0)
+}
+
+// every change of a message sets its change_id to `(SELECT change_id + 1 FROM chat_last_change)`,
+// which a trigger then stores in chat_last_change
+const MESSAGE_COLUMNS: &str = "id, author, timestamp, content, tags, color, channel, edited_at, change_id, created_change_id, \
+                               deleted_at, deleted_by, delete_reason, reply_to, pinned_at";
+
+fn message_from_row(row: &Row) -> Message {
+    let deleted_at = optional_timestamp(row, 10);
+    let pinned_at = optional_timestamp(row, 14);
+    Message {
+        id: row.get(0),
+        author: row.get(1),
+        timestamp: timestamp(row, 2),
+        content: row.get(3),
+        tags: row.get(4),
+        color: row.get(5),
+        channel: row.get(6),
+        reply_to: row.get(13),
+        reply_count: 0,
+        reactions: Vec::new(),
+        pinned: pinned_at.is_some(),
+        edited_at: optional_timestamp(row, 7),
+        change_id: row.get(8),
+        created_change_id: row.get(9),
+        deleted: deleted_at.map(|deleted_at| Tombstone {
+            id: row.get(0),
+            deleted_by: row.get::<_, Option<String>>(11).unwrap_or_default(),
+            deleted_at,
+            reason: row.get(12),
+        }),
+    }
+}
+
+const CHANNEL_QUERY: &str = "SELECT name, topic, description, creator, created_at, archived, private, direct, \
+    (SELECT COUNT(*) FROM chat_messages WHERE channel = chat_channels.name AND deleted_at IS NULL), \
+    (SELECT MAX(timestamp) FROM chat_messages WHERE channel = chat_channels.name AND deleted_at IS NULL) \
+    FROM chat_channels";
+
+fn channel_from_row(row: &Row) -> Channel {
+    Channel {
+        name: row.get(0),
+        topic: row.get(1),
+        description: row.get(2),
+        creator: row.get(3),
+        created_at: timestamp(row, 4),
+        archived: row.get(5),
+        private: row.get(6),
+        direct: row.get(7),
+        message_count: row.get(8),
+        last_activity: optional_timestamp(row, 9),
+    }
+}
+
+/// sqlite has no arrays, so `channel = ANY ($1)` has to be written as `channel IN (?2, ?3, ...)`,
+/// with the channels being the parameters starting at `first_param`
+fn channels_clause(first_param: usize, channels: &[String], include_default_channel: bool) -> String {
+    if include_default_channel {
+        format!("(channel IS NULL OR channel IN ({}))", placeholders(first_param, channels.len()))
+    } else {
+        format!("channel IN ({})", placeholders(first_param, channels.len()))
+    }
+}
+
+/// `?first, ?first+1, ...`, `count` times
+fn placeholders(first_param: usize, count: usize) -> String {
+    (0..count)
+        .map(|i| format!("?{}", first_param + i))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl ChatbixInterface for Chatbix<SqlitePool> {
+    type InitParams = SqlitePool;
+
+    fn new(init_params: Self::InitParams) -> Chatbix<SqlitePool> {
+        Chatbix {
+            connection: init_params,
+            connected_users: RwLock::new(ConnectedUsers::new()),
+            typing_users: RwLock::new(TypingUsers::new()),
+            cached_users: RwLock::new(CachedUsers::new()),
+            hub: Hub::new(),
+            config: Config::default(),
+            waiting: Slots::new(Config::default().max_waiting),
+            notifications_url: None,
+            listening: AtomicBool::new(false),
+        }
+    }
+
+    fn new_message(&self, author: &str, tags: i32, reply_to: Option<i32>, new_message: &NewMessage) -> Result<Message> {
+        let timestamp : NaiveDateTime = now();
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("INSERT INTO chat_messages (author, timestamp, content, tags, color, channel, reply_to, change_id, created_change_id) \
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, (SELECT change_id + 1 FROM chat_last_change), (SELECT change_id + 1 FROM chat_last_change))",
+                       &[&author, &SqlTimestamp(timestamp), &new_message.content, &tags, &new_message.color, &new_message.channel, &reply_to])
+            .map_err(sql_error)?;
+        let id = sqlite.last_insert_rowid() as i32;
+        let change_id : i64 = sqlite.query_row("SELECT change_id FROM chat_messages WHERE id = ?1", &[&id], |row| row.get(0))
+            .map_err(sql_error)?;
+        if let Some(reply_to) = reply_to {
+            // so that the new reply count reaches the polling clients
+            sqlite.execute("UPDATE chat_messages SET change_id = (SELECT change_id + 1 FROM chat_last_change) WHERE id = ?1", &[&reply_to])
+                .map_err(sql_error)?;
+        }
+        Ok(Message {
+            id,
+            author: author.to_owned(),
+            timestamp,
+            content: new_message.content.clone(),
+            tags,
+            color: new_message.color.clone(),
+            channel: new_message.channel.clone(),
+            reply_to,
+            reply_count: 0,
+            reactions: Vec::new(),
+            pinned: false,
+            edited_at: None,
+            change_id,
+            created_change_id: change_id,
+            deleted: None,
+        })
+    }
+
+    fn get_message(&self, id: i32) -> Result<Option<Message>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare(&format!("SELECT {} FROM chat_messages WHERE id = ?1", MESSAGE_COLUMNS)).map_err(sql_error)?;
+        let mut rows = statement.query_map(&[&id], message_from_row).map_err(sql_error)?;
+        rows.next().transpose().map_err(sql_error)
+    }
+
+    fn add_mentions(&self, message_id: i32, usernames: &[String]) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        for username in usernames {
+            sqlite.execute("INSERT OR IGNORE INTO chat_mentions (message_id, username) VALUES (?1, ?2)", &[&message_id, username]).map_err(sql_error)?;
+        }
+        Ok(())
+    }
+
+    fn get_mentions(&self, username: &str, before: Option<i32>, limit: i64, hidden_channels: &[String]) -> Result<Vec<Message>> {
+        let limit = max(limit, 0);
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare(&format!("SELECT {} FROM chat_messages \
+                                                     WHERE id IN (SELECT message_id FROM chat_mentions WHERE username = ?1) \
+                                                     AND (?2 IS NULL OR id < ?2) AND deleted_at IS NULL \
+                                                     AND (channel IS NULL OR channel NOT IN ({})) \
+                                                     ORDER BY id DESC LIMIT ?3",
+                                                    MESSAGE_COLUMNS, placeholders(4, hidden_channels.len()))).map_err(sql_error)?;
+        let mut params : Vec<&dyn ToSql> = Vec::with_capacity(hidden_channels.len() + 3);
+        params.push(&username);
+        params.push(&before);
+        params.push(&limit);
+        for channel in hidden_channels {
+            params.push(channel);
+        }
+        let rows = statement.query_map(&params, message_from_row).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn edit_message(&self, id: i32, content: &str, edited_by: &str) -> Result<Message> {
+        let edited_at : NaiveDateTime = now();
+        let mut sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let transaction = sqlite.transaction().map_err(sql_error)?;
+        transaction.execute("INSERT INTO chat_message_revisions (message_id, content, edited_at, edited_by) \
+                             SELECT id, content, ?2, ?3 FROM chat_messages WHERE id = ?1",
+                            &[&id, &SqlTimestamp(edited_at), &edited_by]).map_err(sql_error)?;
+        let updated = transaction.execute("UPDATE chat_messages SET content = ?2, edited_at = ?3, change_id = (SELECT change_id + 1 FROM chat_last_change) \
+                                           WHERE id = ?1",
+                                          &[&id, &content, &SqlTimestamp(edited_at)]).map_err(sql_error)?;
+        if updated == 0 {
+            bail!(ErrorKind::UnknownMessage);
+        }
+        let message = transaction.query_row(&format!("SELECT {} FROM chat_messages WHERE id = ?1", MESSAGE_COLUMNS),
+                                            &[&id], message_from_row).map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)?;
+        Ok(message)
+    }
+
+    fn get_thread(&self, root_id: i32) -> Result<Vec<Message>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare(&format!("SELECT {} FROM chat_messages WHERE (id = ?1 OR reply_to = ?1) AND deleted_at IS NULL \
+                                                     ORDER BY timestamp ASC, id ASC", MESSAGE_COLUMNS)).map_err(sql_error)?;
+        let rows = statement.query_map(&[&root_id], message_from_row).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn get_reply_counts(&self, ids: &[i32]) -> Result<HashMap<i32, i64>> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let params = ids.iter().map(|id| id as &dyn ToSql).collect::<Vec<&dyn ToSql>>();
+        let mut statement = sqlite.prepare(&format!("SELECT reply_to, COUNT(*) FROM chat_messages \
+                                                     WHERE reply_to IN ({}) AND deleted_at IS NULL GROUP BY reply_to",
+                                                    placeholders(1, ids.len()))).map_err(sql_error)?;
+        let rows = statement.query_map(&params, |row| (row.get(0), row.get(1))).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn add_reaction(&self, message_id: i32, username: &str, emoji: &str) -> Result<()> {
+        let timestamp : NaiveDateTime = now();
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let added = sqlite.execute("INSERT OR IGNORE INTO chat_reactions (message_id, username, emoji, created_at) VALUES (?1, ?2, ?3, ?4)",
+                                   &[&message_id, &username, &emoji, &SqlTimestamp(timestamp)]).map_err(sql_error)?;
+        if added > 0 {
+            // so that the reaction reaches the polling clients
+            sqlite.execute("UPDATE chat_messages SET change_id = (SELECT change_id + 1 FROM chat_last_change) WHERE id = ?1", &[&message_id])
+                .map_err(sql_error)?;
+        }
+        Ok(())
+    }
+
+    fn remove_reaction(&self, message_id: i32, username: &str, emoji: &str) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let removed = sqlite.execute("DELETE FROM chat_reactions WHERE message_id = ?1 AND username = ?2 AND emoji = ?3",
+                                     &[&message_id, &username, &emoji]).map_err(sql_error)?;
+        if removed > 0 {
+            sqlite.execute("UPDATE chat_messages SET change_id = (SELECT change_id + 1 FROM chat_last_change) WHERE id = ?1", &[&message_id])
+                .map_err(sql_error)?;
+        }
+        Ok(())
+    }
+
+    fn get_reactions(&self, ids: &[i32]) -> Result<Vec<Reaction>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let params = ids.iter().map(|id| id as &dyn ToSql).collect::<Vec<&dyn ToSql>>();
+        let mut statement = sqlite.prepare(&format!("SELECT message_id, username, emoji FROM chat_reactions \
+                                                     WHERE message_id IN ({}) ORDER BY created_at ASC",
+                                                    placeholders(1, ids.len()))).map_err(sql_error)?;
+        let rows = statement.query_map(&params, |row| Reaction {
+            message_id: row.get(0),
+            username: row.get(1),
+            emoji: row.get(2),
+        }).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn set_pinned(&self, message_id: i32, pinned_by: Option<&str>) -> Result<()> {
+        let pinned_at = pinned_by.map(|_| SqlTimestamp(now()));
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("UPDATE chat_messages SET pinned_at = ?2, pinned_by = ?3, change_id = (SELECT change_id + 1 FROM chat_last_change) \
+                        WHERE id = ?1",
+                       &[&message_id, &pinned_at, &pinned_by]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn get_pins(&self, channel: Option<&str>) -> Result<Vec<Message>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare(&format!("SELECT {} FROM chat_messages WHERE pinned_at IS NOT NULL AND deleted_at IS NULL \
+                                                     AND channel IS ?1 ORDER BY pinned_at DESC", MESSAGE_COLUMNS)).map_err(sql_error)?;
+        let rows = statement.query_map(&[&channel], message_from_row).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn create_channel(&self, channel: &Channel) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let created = sqlite.execute("INSERT OR IGNORE INTO chat_channels (name, topic, description, creator, created_at, archived, private, direct) \
+                                      VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                                     &[&channel.name, &channel.topic, &channel.description, &channel.creator, &SqlTimestamp(channel.created_at),
+                                       &channel.archived, &channel.private, &channel.direct]).map_err(sql_error)?;
+        if created == 0 {
+            bail!(ErrorKind::ChannelNameInUse);
+        }
+        Ok(())
+    }
+
+    fn update_channel(&self, channel: &Channel) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("UPDATE chat_channels SET topic = ?2, description = ?3, archived = ?4, private = ?5 WHERE name = ?1",
+                       &[&channel.name, &channel.topic, &channel.description, &channel.archived, &channel.private]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn add_channel_member(&self, channel: &str, username: &str) -> Result<()> {
+        let added_at : NaiveDateTime = now();
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("INSERT OR IGNORE INTO chat_channel_members (channel, username, added_at) VALUES (?1, ?2, ?3)",
+                       &[&channel, &username, &SqlTimestamp(added_at)]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn remove_channel_member(&self, channel: &str, username: &str) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("DELETE FROM chat_channel_members WHERE channel = ?1 AND username = ?2", &[&channel, &username]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn get_channel_members(&self, channel: &str) -> Result<Vec<String>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare("SELECT username FROM chat_channel_members WHERE channel = ?1 ORDER BY username ASC").map_err(sql_error)?;
+        let rows = statement.query_map(&[&channel], |row| row.get::<_, String>(0)).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn get_hidden_channels(&self, username: Option<&str>) -> Result<Vec<String>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare("SELECT name FROM chat_channels WHERE private AND name NOT IN \
+                                            (SELECT channel FROM chat_channel_members WHERE username IS ?1)").map_err(sql_error)?;
+        let rows = statement.query_map(&[&username], |row| row.get::<_, String>(0)).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn get_channel(&self, name: &str) -> Result<Option<Channel>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare(&format!("{} WHERE name = ?1", CHANNEL_QUERY)).map_err(sql_error)?;
+        let mut rows = statement.query_map(&[&name], channel_from_row).map_err(sql_error)?;
+        rows.next().transpose().map_err(sql_error)
+    }
+
+    fn get_channels(&self) -> Result<Vec<Channel>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare(&format!("{} ORDER BY name ASC", CHANNEL_QUERY)).map_err(sql_error)?;
+        let rows = statement.query_map(&[], channel_from_row).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn get_direct_channels(&self, username: &str) -> Result<Vec<Channel>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare(&format!("{} WHERE direct AND name IN (SELECT channel FROM chat_channel_members WHERE username = ?1)",
+                                                    CHANNEL_QUERY)).map_err(sql_error)?;
+        let rows = statement.query_map(&[&username], channel_from_row).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn get_last_messages(&self, channels: &[String]) -> Result<Vec<Message>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare(&format!("SELECT {} FROM chat_messages WHERE id IN \
+                                                     (SELECT MAX(id) FROM chat_messages WHERE deleted_at IS NULL AND channel IN ({}) GROUP BY channel)",
+                                                    MESSAGE_COLUMNS, placeholders(1, channels.len()))).map_err(sql_error)?;
+        let params = channels.iter().map(|channel| channel as &dyn ToSql).collect::<Vec<&dyn ToSql>>();
+        let rows = statement.query_map(&params, message_from_row).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn set_read_marker(&self, username: &str, channel: &str, message_id: i32) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("INSERT OR IGNORE INTO chat_read_markers (username, channel, last_read_id) VALUES (?1, ?2, ?3)",
+                       &[&username, &channel, &message_id]).map_err(sql_error)?;
+        sqlite.execute("UPDATE chat_read_markers SET last_read_id = MAX(last_read_id, ?3) WHERE username = ?1 AND channel = ?2",
+                       &[&username, &channel, &message_id]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn get_unread(&self, username: &str, channels: &[String], include_default_channel: bool) -> Result<HashMap<String, Unread>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare(&format!("SELECT COALESCE(m.channel, ''), COUNT(*), \
+                                                            COUNT(mentions.message_id) \
+                                                     FROM (SELECT * FROM chat_messages WHERE deleted_at IS NULL AND author <> ?1 AND {}) AS m \
+                                                     LEFT JOIN chat_read_markers AS r ON r.username = ?1 AND r.channel = COALESCE(m.channel, '') \
+                                                     LEFT JOIN chat_mentions AS mentions ON mentions.message_id = m.id AND mentions.username = ?1 \
+                                                     WHERE m.id > COALESCE(r.last_read_id, 0) \
+                                                     GROUP BY COALESCE(m.channel, '')",
+                                                    channels_clause(2, channels, include_default_channel))).map_err(sql_error)?;
+        let mut params : Vec<&dyn ToSql> = Vec::with_capacity(channels.len() + 1);
+        params.push(&username);
+        for channel in channels {
+            params.push(channel);
+        }
+        let rows = statement.query_map(&params, |row| (row.get::<_, String>(0), Unread {
+            count: row.get(1),
+            mentions: row.get(2),
+        })).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn get_revisions(&self, message_id: i32) -> Result<Vec<Revision>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare("SELECT message_id, content, edited_at, edited_by FROM chat_message_revisions \
+                                            WHERE message_id = ?1 ORDER BY id ASC").map_err(sql_error)?;
+        let rows = statement.query_map(&[&message_id], |row| Revision {
+            message_id: row.get(0),
+            content: row.get(1),
+            edited_at: timestamp(row, 2),
+            edited_by: row.get(3),
+        }).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn delete_message(&self, id: i32, deleted_by: &str, reason: Option<&str>) -> Result<()> {
+        let deleted_at : NaiveDateTime = now();
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let deleted = sqlite.execute("UPDATE chat_messages SET deleted_at = ?2, deleted_by = ?3, delete_reason = ?4, \
+                                      change_id = (SELECT change_id + 1 FROM chat_last_change) \
+                                      WHERE id = ?1 AND deleted_at IS NULL",
+                                     &[&id, &SqlTimestamp(deleted_at), &deleted_by, &reason]).map_err(sql_error)?;
+        if deleted == 0 {
+            bail!(ErrorKind::UnknownMessage);
+        }
+        // the reply count of the root changed
+        sqlite.execute("UPDATE chat_messages SET change_id = (SELECT change_id + 1 FROM chat_last_change) \
+                        WHERE id = (SELECT reply_to FROM chat_messages WHERE id = ?1)",
+                       &[&id]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn purge_message(&self, id: i32) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let purged = sqlite.execute("DELETE FROM chat_messages WHERE id = ?1", &[&id]).map_err(sql_error)?;
+        if purged == 0 {
+            bail!(ErrorKind::UnknownMessage);
+        }
+        Ok(())
+    }
+
+    fn get_messages<V: AsRef<[String]>>(&self, interval: Interval, channels: V, include_default_channel: bool) -> Result<Vec<Message>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let channels = channels.as_ref();
+        let mut params : Vec<&dyn ToSql> = Vec::with_capacity(channels.len() + 2);
+        let (from, to);
+        let query = match interval {
+            Interval::Last(ref last) => {
+                params.push(last);
+                format!("SELECT * FROM (SELECT {} FROM chat_messages WHERE deleted_at IS NULL AND {} ORDER BY timestamp DESC LIMIT ?1) ORDER BY timestamp ASC;",
+                        MESSAGE_COLUMNS, channels_clause(2, channels, include_default_channel))
+            },
+            // the messages changed since the last one sent by then come along with the new ones
+            Interval::AllFromTimestamp(timestamp) => {
+                from = SqlTimestamp(timestamp);
+                params.push(&from);
+                format!("SELECT {} FROM chat_messages \
+                         WHERE (timestamp > ?1 OR change_id > (SELECT MAX(created_change_id) FROM chat_messages WHERE timestamp <= ?1)) \
+                         AND {} ORDER BY timestamp ASC;",
+                        MESSAGE_COLUMNS, channels_clause(2, channels, include_default_channel))
+            },
+            Interval::FromToTimestamp(timestamp, timestamp_end) => {
+                from = SqlTimestamp(timestamp);
+                to = SqlTimestamp(timestamp_end);
+                params.push(&from);
+                params.push(&to);
+                format!("SELECT {} FROM chat_messages WHERE deleted_at IS NULL AND timestamp > ?1 AND timestamp < ?2 AND {} ORDER BY timestamp ASC;",
+                        MESSAGE_COLUMNS, channels_clause(3, channels, include_default_channel))
+            },
+            // the messages changed since message `id` was sent come along with the new ones
+            Interval::AllFromId(ref id) => {
+                params.push(id);
+                format!("SELECT {} FROM chat_messages \
+                         WHERE (id > ?1 OR change_id > (SELECT created_change_id FROM chat_messages WHERE id = ?1)) AND {} ORDER BY timestamp ASC;",
+                        MESSAGE_COLUMNS, channels_clause(2, channels, include_default_channel))
+            },
+            Interval::AllFromChange(ref change_id) => {
+                params.push(change_id);
+                format!("SELECT {} FROM chat_messages WHERE change_id > ?1 AND {} ORDER BY timestamp ASC;",
+                        MESSAGE_COLUMNS, channels_clause(2, channels, include_default_channel))
+            },
+        };
+        for channel in channels {
+            params.push(channel);
+        }
+        let mut statement = sqlite.prepare(&query).map_err(sql_error)?;
+        let rows = statement.query_map(&params, message_from_row).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn last_change_id(&self) -> Result<i64> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.query_row("SELECT change_id FROM chat_last_change", &[], |row| row.get(0)).map_err(sql_error)
+    }
+
+    fn create_user(&self, username: &str, password_hash: &str) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        // a single statement, so that two users registering the same name at once can't both succeed
+        let inserted = sqlite.execute("INSERT OR IGNORE INTO chat_users (username, password) VALUES (?1, ?2)", &[&username, &password_hash])
+            .map_err(sql_error)?;
+        if inserted == 0 {
+            bail!(ErrorKind::UsernameInUse);
+        }
+        Ok(())
+    }
+
+    fn get_user(&self, username: &str) -> Result<Option<StoredUser>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare("SELECT password, admin FROM chat_users WHERE username = ?1").map_err(sql_error)?;
+        let mut rows = statement.query_map(&[&username], |row| StoredUser {
+            password: row.get(0),
+            admin: row.get(1),
+        }).map_err(sql_error)?;
+        rows.next().transpose().map_err(sql_error)
+    }
+
+    fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("UPDATE chat_users SET password = ?2 WHERE username = ?1", &[&username, &password_hash]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn set_user_status(&self, username: &str, status: &UserStatus) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("INSERT OR REPLACE INTO chat_user_statuses (username, state, status_text, status_emoji) VALUES (?1, ?2, ?3, ?4)",
+                       &[&username, &status.state.as_str(), &status.status_text, &status.status_emoji]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn get_user_status(&self, username: &str) -> Result<Option<UserStatus>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare("SELECT state, status_text, status_emoji FROM chat_user_statuses WHERE username = ?1").map_err(sql_error)?;
+        let mut rows = statement.query_map(&[&username], |row| (row.get::<_, String>(0), row.get::<_, Option<String>>(1), row.get::<_, Option<String>>(2))).map_err(sql_error)?;
+        match rows.next() {
+            Some(row) => {
+                let (state, status_text, status_emoji) = row.map_err(sql_error)?;
+                Ok(Some(UserStatus {
+                    state: PresenceState::parse(&state)?,
+                    status_text,
+                    status_emoji,
+                }))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn store_session(&self, session: &Session) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("INSERT INTO chat_sessions (key_hash, username, created_at, last_used, user_agent) \
+                        VALUES (?1, ?2, ?3, ?4, ?5)",
+                       &[&session.key_hash, &session.username, &SqlTimestamp(session.created_at), &SqlTimestamp(session.last_used), &session.user_agent])
+            .map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn load_session(&self, key_hash: &str) -> Result<Option<(Session, bool)>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare("SELECT chat_sessions.key_hash, chat_sessions.username, chat_sessions.created_at, \
+                                                   chat_sessions.last_used, chat_sessions.user_agent, chat_users.admin \
+                                            FROM chat_sessions \
+                                            JOIN chat_users ON chat_users.username = chat_sessions.username \
+                                            WHERE key_hash = ?1").map_err(sql_error)?;
+        let mut rows = statement.query_map(&[&key_hash], |row| (Session {
+            key_hash: row.get(0),
+            username: row.get(1),
+            created_at: timestamp(row, 2),
+            last_used: timestamp(row, 3),
+            user_agent: row.get(4),
+        }, row.get(5))).map_err(sql_error)?;
+        rows.next().transpose().map_err(sql_error)
+    }
+
+    fn touch_session(&self, key_hash: &str, last_used: NaiveDateTime) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("UPDATE chat_sessions SET last_used = ?2 WHERE key_hash = ?1", &[&key_hash, &SqlTimestamp(last_used)]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn delete_session(&self, key_hash: &str) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("DELETE FROM chat_sessions WHERE key_hash = ?1", &[&key_hash]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn get_sessions(&self, username: &str) -> Result<Vec<Session>> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        let mut statement = sqlite.prepare("SELECT key_hash, username, created_at, last_used, user_agent FROM chat_sessions \
+                                            WHERE username = ?1 ORDER BY last_used DESC").map_err(sql_error)?;
+        let rows = statement.query_map(&[&username], |row| Session {
+            key_hash: row.get(0),
+            username: row.get(1),
+            created_at: timestamp(row, 2),
+            last_used: timestamp(row, 3),
+            user_agent: row.get(4),
+        }).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+
+    fn delete_other_sessions(&self, username: &str, key_hash: &str) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("DELETE FROM chat_sessions WHERE username = ?1 AND key_hash <> ?2", &[&username, &key_hash]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn delete_expired_sessions(&self, created_before: NaiveDateTime, used_before: NaiveDateTime) -> Result<()> {
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        sqlite.execute("DELETE FROM chat_sessions WHERE created_at < ?1 OR last_used < ?2", &[&SqlTimestamp(created_before), &SqlTimestamp(used_before)]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    /// sqlite has nothing like NOTIFY: instances sharing a file only see each other's messages by polling
+    fn notify_message(&self, _message_id: i32) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn listen_notifications(_chatbix: Arc<Self>) {
+    }
+
+    /// The query is split into words which must all be present in the message,
+    /// tsquery operators like `&` or `|` are ignored.
+    fn fulltext_search(&self, query: &str, limit: i64, hidden_channels: &[String]) -> Result<Vec<Match>> {
+        let limit = max(limit, 0);
+        // quoting every word prevents them from being interpreted as FTS5 operators
+        let query = words(query).iter()
+            .map(|w| format!("\"{}\"", w))
+            .collect::<Vec<String>>()
+            .join(" ");
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sqlite : PooledConnection<_> = self.connection.get().map_err(|_| Error::from_kind(ErrorKind::DatabaseBusy))?;
+        // bm25 is lower for better matches, while the rank is expected to be higher
+        let mut statement = sqlite.prepare(&format!("SELECT chat_messages.author, chat_messages.content, -bm25(chat_messages_fts) AS rank
+                                                     FROM chat_messages_fts
+                                                     JOIN chat_messages ON chat_messages.id = chat_messages_fts.rowid
+                                                     WHERE chat_messages_fts MATCH ?1 AND chat_messages.deleted_at IS NULL
+                                                       AND (chat_messages.channel IS NULL OR chat_messages.channel NOT IN ({}))
+                                                     ORDER BY rank DESC
+                                                     LIMIT ?2", placeholders(3, hidden_channels.len()))).map_err(sql_error)?;
+        let mut params : Vec<&dyn ToSql> = Vec::with_capacity(hidden_channels.len() + 2);
+        params.push(&query);
+        params.push(&limit);
+        for channel in hidden_channels {
+            params.push(channel);
+        }
+        let rows = statement.query_map(&params, |r| Match {
+            user: r.get(0),
+            message: r.get(1),
+            rank: r.get::<_, f64>(2) as f32,
+        }).map_err(sql_error)?;
+        rows.map(|row| row.map_err(sql_error)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_numbered_from_the_first_param() {
+        assert_eq!(placeholders(2, 3), "?2, ?3, ?4");
+        assert_eq!(placeholders(1, 1), "?1");
+        assert_eq!(placeholders(1, 0), "");
+    }
+
+    #[test]
+    fn channels_clause_includes_the_default_channel_if_asked() {
+        let channels = vec!["a".to_owned(), "b".to_owned()];
+        assert_eq!(channels_clause(3, &channels, true), "(channel IS NULL OR channel IN (?3, ?4))");
+        assert_eq!(channels_clause(3, &channels, false), "channel IN (?3, ?4)");
+        assert_eq!(channels_clause(1, &[], true), "(channel IS NULL OR channel IN ())");
+    }
+}
@@ -0,0 +1,114 @@
+use std::env;
+use std::str::FromStr;
+use password::PasswordParams;
+use user::SessionLimits;
+use chrono::Duration;
+
+/// Settings of the server, read from the environment (or the .env file) on startup.
+/// Every setting has a default, so that none of them is required.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// CHATBIX_SCRYPT_LOG_N, CHATBIX_SCRYPT_R and CHATBIX_SCRYPT_P
+    pub password: PasswordParams,
+    /// CHATBIX_SESSION_MAX_AGE, CHATBIX_SESSION_IDLE_TIMEOUT, CHATBIX_SESSION_CACHE_TTL
+    /// and CHATBIX_SESSION_PERSIST_INTERVAL, in seconds
+    pub sessions: SessionLimits,
+    /// CHATBIX_ANONYMOUS_NAMES (allow, forbid or suffix) and CHATBIX_ANONYMOUS_SUFFIX
+    pub anonymous_names: AnonymousNames,
+    /// CHATBIX_STRICT_CHANNELS: whether messages can only be sent to channels which were created
+    /// and are not archived
+    pub strict_channels: bool,
+    /// CHATBIX_HTTP_THREADS: the number of threads answering the http requests
+    pub http_threads: usize,
+    /// CHATBIX_MAX_WAITING: how many event streams and long polls can be open at once.
+    /// Each of them keeps an http thread busy, so this must stay well below `http_threads`
+    pub max_waiting: usize,
+}
+
+/// What happens when someone who isn't authenticated uses the name of a registered user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnonymousNames {
+    /// anyone can use any name, only the logged_in tag tells them apart
+    Allow,
+    /// registered names can only be used with a valid auth_key
+    Forbid,
+    /// the suffix is appended to registered names used without a valid auth_key
+    Suffix(String),
+}
+
+impl AnonymousNames {
+    fn from_env(default: AnonymousNames) -> AnonymousNames {
+        let default_suffix = match default {
+            AnonymousNames::Suffix(ref suffix) => suffix.clone(),
+            _ => String::from(" (guest)"),
+        };
+        match env::var("CHATBIX_ANONYMOUS_NAMES") {
+            Ok(ref mode) if mode == "allow" => AnonymousNames::Allow,
+            Ok(ref mode) if mode == "forbid" => AnonymousNames::Forbid,
+            Ok(ref mode) if mode == "suffix" => {
+                let suffix = env::var("CHATBIX_ANONYMOUS_SUFFIX").unwrap_or(default_suffix);
+                if suffix.is_empty() {
+                    panic!("CHATBIX_ANONYMOUS_SUFFIX can't be empty");
+                }
+                AnonymousNames::Suffix(suffix)
+            },
+            Ok(mode) => panic!("Invalid value `{}` for CHATBIX_ANONYMOUS_NAMES (expected allow, forbid or suffix)", mode),
+            Err(_) => default,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            password: PasswordParams::default(),
+            sessions: SessionLimits::default(),
+            anonymous_names: AnonymousNames::Allow,
+            strict_channels: false,
+            http_threads: 64,
+            max_waiting: 32,
+        }
+    }
+}
+
+/// panics if the variable is set but can't be parsed,
+/// so that a typo is noticed right away instead of being silently replaced by the default
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => value,
+            Err(_) => panic!("Invalid value `{}` for {}", value, name),
+        },
+        Err(_) => default,
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let default = Config::default();
+        let http_threads = env_or("CHATBIX_HTTP_THREADS", default.http_threads);
+        let config = Config {
+            password: PasswordParams {
+                log_n: env_or("CHATBIX_SCRYPT_LOG_N", default.password.log_n),
+                r: env_or("CHATBIX_SCRYPT_R", default.password.r),
+                p: env_or("CHATBIX_SCRYPT_P", default.password.p),
+            },
+            sessions: SessionLimits {
+                max_age: Duration::seconds(env_or("CHATBIX_SESSION_MAX_AGE", default.sessions.max_age.num_seconds())),
+                idle_timeout: Duration::seconds(env_or("CHATBIX_SESSION_IDLE_TIMEOUT", default.sessions.idle_timeout.num_seconds())),
+                cache_ttl: Duration::seconds(env_or("CHATBIX_SESSION_CACHE_TTL", default.sessions.cache_ttl.num_seconds())),
+                persist_interval: Duration::seconds(env_or("CHATBIX_SESSION_PERSIST_INTERVAL", default.sessions.persist_interval.num_seconds())),
+            },
+            anonymous_names: AnonymousNames::from_env(default.anonymous_names),
+            strict_channels: env_or("CHATBIX_STRICT_CHANNELS", default.strict_channels),
+            http_threads,
+            max_waiting: env_or("CHATBIX_MAX_WAITING", http_threads / 2),
+        };
+        if config.max_waiting >= config.http_threads {
+            panic!("CHATBIX_MAX_WAITING must be lower than CHATBIX_HTTP_THREADS, or no thread would be left for the other requests");
+        }
+        // panics now rather than on the first login if the parameters are invalid
+        config.password.scrypt_params();
+        config
+    }
+}
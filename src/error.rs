@@ -1,3 +1,6 @@
+// error_chain 0.9 implements the `description` and `cause` methods which are now deprecated
+#![allow(deprecated)]
+
 use iron::{status,IronError,IronResult,Response};
 use bodyparser::{BodyError, BodyErrorCause};
 
@@ -16,8 +19,10 @@ impl JsonError {
     }
 }
 
-impl Into<IronResult<Response>> for Error {
-    fn into(self) -> IronResult<Response> {
+impl Error {
+    /// the message and the status an error is answered with,
+    /// or the error itself if it is an unexpected one
+    pub fn describe(self) -> ::std::result::Result<(String, status::Status), Error> {
         let error = self;
         let (response_string, status) = match error {
             Error(ErrorKind::ChronoParseError(parse_error),_) =>
@@ -32,10 +37,38 @@ impl Into<IronResult<Response>> for Error {
                 ("invalid auth_key".to_owned(), status::Unauthorized),
             Error(ErrorKind::Forbidden, _) =>
                 ("insufficient rights".to_owned(), status::Forbidden),
+            Error(ErrorKind::TooManyWaiting, _) =>
+                ("too many requests are waiting, try again later".to_owned(), status::ServiceUnavailable),
             Error(ErrorKind::NoJsonBodyDetected, _) => 
                 ("no json body detected".to_owned(), status::BadRequest),
             Error(ErrorKind::NotLoggedIn, _) => 
                 ("not logged in".to_owned(), status::Unauthorized),
+            Error(ErrorKind::SessionExpired, _) =>
+                ("session expired, please log in again".to_owned(), status::Unauthorized),
+            Error(ErrorKind::UnknownSession, _) =>
+                ("unknown session".to_owned(), status::NotFound),
+            Error(ErrorKind::UnknownMessage, _) =>
+                ("unknown message".to_owned(), status::NotFound),
+            Error(ErrorKind::InvalidEmoji, _) =>
+                ("invalid emoji".to_owned(), status::UnprocessableEntity),
+            Error(ErrorKind::InvalidStatus, _) =>
+                ("invalid status".to_owned(), status::UnprocessableEntity),
+            Error(ErrorKind::InvalidChannelName, _) =>
+                ("invalid channel name".to_owned(), status::UnprocessableEntity),
+            Error(ErrorKind::InvalidParticipants, _) =>
+                ("invalid participants".to_owned(), status::UnprocessableEntity),
+            Error(ErrorKind::UnknownUser, _) =>
+                ("unknown user".to_owned(), status::NotFound),
+            Error(ErrorKind::UnknownChannel, _) =>
+                ("unknown channel".to_owned(), status::NotFound),
+            Error(ErrorKind::ChannelArchived, _) =>
+                ("this channel is archived".to_owned(), status::Forbidden),
+            Error(ErrorKind::ChannelNameInUse, _) =>
+                ("channel name already taken".to_owned(), status::Conflict),
+            Error(ErrorKind::NotInChannel, _) =>
+                ("the message is not in this channel".to_owned(), status::UnprocessableEntity),
+            Error(ErrorKind::MissingParameter(name), _) =>
+                (format!("missing parameter: {}", name), status::UnprocessableEntity),
             Error(ErrorKind::UsernameInUse, _) => 
                 ("username already taken".to_owned(), status::Conflict),
             Error(ErrorKind::BodyparserError(body_error),_) =>
@@ -44,7 +77,17 @@ impl Into<IronResult<Response>> for Error {
                     BodyErrorCause::IoError(io_error) => (format!("body error: {}",io_error),status::InternalServerError),
                     BodyErrorCause::JsonError(json_error) => (format!("body error: {}",json_error),status::UnprocessableEntity),
                 },
-            e => return Err(IronError::new(e, status::InternalServerError)),
+            e => return Err(e),
+        };
+        Ok((response_string, status))
+    }
+}
+
+impl From<Error> for IronResult<Response> {
+    fn from(error: Error) -> IronResult<Response> {
+        let (response_string, status) = match error.describe() {
+            Ok(description) => description,
+            Err(e) => return Err(IronError::new(e, status::InternalServerError)),
         };
         let json_error = JsonError::new(response_string);
         Ok(Response::with((::serde_json::to_string(&json_error).unwrap(), status)))
@@ -69,12 +112,40 @@ error_chain! {
         Forbidden
         NotLoggedIn
         DatabaseBusy
+        TooManyWaiting
         NoJsonBodyDetected
+        UnknownSession
+        UnknownMessage
+        NotInChannel
+        InvalidEmoji
+        InvalidStatus
+        InvalidChannelName
+        UnknownChannel
+        UnknownUser
+        InvalidParticipants
+        ChannelArchived
+        ChannelNameInUse
+        SessionExpired
+        MissingParameter(name: &'static str) {
+            description("a required parameter is missing")
+            display("missing parameter: {}", name)
+        }
+        SchemaOutOfDate(pending: Vec<i32>) {
+            description("the database schema is out of date")
+            display("the database schema is out of date, pending migrations: {:?} (run `chatbix migrate up`)", pending)
+        }
+        MigrationFailed(version: i32, reason: String) {
+            description("a migration failed")
+            display("migration {} failed: {}", version, reason)
+        }
     }
 
     foreign_links {
         ChronoParseError(::chrono::ParseError);
         BodyparserError(BodyError);
         ParseIntError(::std::num::ParseIntError);
+        SqliteError(::rusqlite::Error) #[cfg(feature = "sqlite")];
+        PostgresError(::postgres::error::Error) #[cfg(feature = "postgres")];
+        PostgresConnectError(::postgres::error::ConnectError) #[cfg(feature = "postgres")];
     }
 }
@@ -3,9 +3,12 @@ use std::env;
 use chatbix::*;
 use std::sync::Arc;
 use iron::{status,mime,Iron,Chain,Response,Request,IronResult,IronError,Set,self};
+use iron::headers::ContentType;
 use mount::Mount;
 use router::Router;
 use super::routes;
+use super::auth::AuthMiddleware;
+use super::websocket;
 use staticfile::{Static, Cache};
 use persistent::Read as PerRead;
 use std::io::Write as IoWrite;
@@ -22,7 +25,8 @@ macro_rules! chatbix_route {
                             let chatbix_arc = tmp_chatbix_arc.clone();
                             ($route)(r, chatbix_arc)
                         },
-                        $url)
+                        // route ids must be unique, and some urls have both a GET and a POST route
+                        concat!(stringify!($method), " ", $url))
     }
 }
 
@@ -30,6 +34,10 @@ struct ChatbixAfterMiddleware;
 
 impl iron::AfterMiddleware for ChatbixAfterMiddleware {
     fn after(&self, _r: &mut Request, res: Response) -> IronResult<Response> {
+        // the routes which don't answer with JSON say so
+        if res.headers.has::<ContentType>() {
+            return Ok(res);
+        }
         let json_mime : mime::Mime = mime::Mime(mime::TopLevel::Application, mime::SubLevel::Json,
                                                 vec![(mime::Attr::Charset,mime::Value::Utf8)]);
         Ok(res.set(json_mime))
@@ -69,10 +77,13 @@ pub fn handler<C>(chatbix: Chatbix<C>) where Chatbix<C>: ChatbixInterface, C: Se
     let chatbix_arc = Arc::new(chatbix);
     let mut mount = Mount::new();
     let mut api_handler = Router::new();
+    Chatbix::listen_notifications(chatbix_arc.clone());
     let chatbix_weak = Arc::downgrade(&chatbix_arc);
     thread::spawn(move || {
         while let Some(chatbix_arc) = chatbix_weak.upgrade() {
             chatbix_arc.refresh_users();
+            chatbix_arc.refresh_cached_users();
+            chatbix_arc.refresh_typing();
             // wait 2 seconds to filter connected users
             thread::sleep(Duration::new(2,0));
         };
@@ -80,19 +91,47 @@ pub fn handler<C>(chatbix: Chatbix<C>) where Chatbix<C>: ChatbixInterface, C: Se
     });
     chatbix_route!(get,"get_messages",routes::get_messages, chatbix_arc, api_handler);
     chatbix_route!(post,"new_message",routes::new_message, chatbix_arc, api_handler);
+    chatbix_route!(post,"edit_message",routes::edit_message, chatbix_arc, api_handler);
+    chatbix_route!(get,"revisions",routes::revisions, chatbix_arc, api_handler);
+    chatbix_route!(post,"react",routes::react, chatbix_arc, api_handler);
+    chatbix_route!(post,"unreact",routes::unreact, chatbix_arc, api_handler);
+    chatbix_route!(post,"pin",routes::pin, chatbix_arc, api_handler);
+    chatbix_route!(post,"unpin",routes::unpin, chatbix_arc, api_handler);
+    chatbix_route!(get,"pins",routes::pins, chatbix_arc, api_handler);
+    chatbix_route!(get,"channels",routes::channels, chatbix_arc, api_handler);
+    chatbix_route!(post,"channels/create",routes::create_channel, chatbix_arc, api_handler);
+    chatbix_route!(post,"channels/update",routes::update_channel, chatbix_arc, api_handler);
+    chatbix_route!(get,"channels/members",routes::channel_members, chatbix_arc, api_handler);
+    chatbix_route!(post,"channels/members",routes::edit_channel_members, chatbix_arc, api_handler);
+    chatbix_route!(post,"dm",routes::open_direct, chatbix_arc, api_handler);
+    chatbix_route!(post,"mark_read",routes::mark_read, chatbix_arc, api_handler);
+    chatbix_route!(post,"typing",routes::typing, chatbix_arc, api_handler);
+    chatbix_route!(get,"mentions",routes::mentions, chatbix_arc, api_handler);
+    chatbix_route!(get,"dms",routes::conversations, chatbix_arc, api_handler);
     chatbix_route!(post,"login",routes::login, chatbix_arc, api_handler);
     chatbix_route!(post,"logout",routes::logout, chatbix_arc, api_handler);
     chatbix_route!(post,"register",routes::register, chatbix_arc, api_handler);
     chatbix_route!(get,"heartbeat",routes::heartbeat, chatbix_arc, api_handler);
+    chatbix_route!(get,"stream",routes::stream, chatbix_arc, api_handler);
     chatbix_route!(get,"fulltext",routes::fulltext_search, chatbix_arc, api_handler);
+    chatbix_route!(get,"sessions",routes::sessions, chatbix_arc, api_handler);
+    chatbix_route!(get,"status",routes::get_status, chatbix_arc, api_handler);
+    chatbix_route!(post,"status",routes::set_status, chatbix_arc, api_handler);
+    chatbix_route!(post,"sessions/revoke",routes::revoke_session, chatbix_arc, api_handler);
+    chatbix_route!(post,"sessions/refresh",routes::refresh_session, chatbix_arc, api_handler);
     chatbix_route!(post,"admin/delete_message",routes::delete_message, chatbix_arc, api_handler);
+    chatbix_route!(post,"admin/purge_message",routes::purge_message, chatbix_arc, api_handler);
     let mut api_handler = Chain::new(api_handler);
     api_handler.link_before(PerRead::<bodyparser::MaxBodyLength>::one(1024 * 1024)); // limit size of requests to 1MB
+    api_handler.link_before(AuthMiddleware::new(chatbix_arc.clone()));
     api_handler.link_after(ChatbixAfterMiddleware);
     mount.mount("/api", api_handler);
-    let mut static_root = Static::new(env::var("STATIC_ROOT_DIR").unwrap_or(String::new()));
+    let mut static_root = Static::new(env::var("STATIC_ROOT_DIR").unwrap_or_default());
     static_root.set_mut(Cache::new(::std::time::Duration::new(86400, 0)));
     mount.mount("/", static_root);
+    websocket::listen(chatbix_arc.clone(), env::var("LISTEN_WS_URL").unwrap_or("0.0.0.0:8081".to_owned()));
     let listen_url = env::var("LISTEN_URL").unwrap_or("0.0.0.0:8080".to_owned());
-    let _listening = Iron::new(mount).http(&*listen_url).unwrap();
+    let mut iron = Iron::new(mount);
+    iron.threads = chatbix_arc.config().http_threads;
+    let _listening = iron.http(&*listen_url).unwrap();
 }
@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use message::{Message,Tombstone};
+use user::{ConnectedUser,Typing};
+
+/// Something which happened, pushed to the clients which don't poll.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// a new message, or one which changed (edited, reacted to, pinned...)
+    Message(Message),
+    /// the tombstone of a deleted message, along with the channel it was in
+    Deleted(Tombstone, Option<String>),
+    /// the connected users, when someone connected or disconnected
+    Presence(Vec<ConnectedUser>),
+    /// the users typing in a channel, when someone started or stopped
+    Typing(Typing),
+}
+
+/// An event, along with who is allowed to receive it.
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub event: Event,
+    /// None if anyone can see the event, the usernames of the members of its private channel otherwise
+    pub audience: Option<Vec<String>>,
+}
+
+impl Published {
+    /// the channel of the event, None for the default channel and for presence changes
+    pub fn channel(&self) -> Option<&str> {
+        match self.event {
+            Event::Message(ref message) => message.channel.as_deref(),
+            Event::Deleted(_, ref channel) => channel.as_deref(),
+            Event::Presence(_) => None,
+            Event::Typing(ref typing) if typing.channel.is_empty() => None,
+            Event::Typing(ref typing) => Some(typing.channel.as_str()),
+        }
+    }
+
+    /// whether someone subscribed to these channels should receive the event
+    pub fn is_for(&self, username: Option<&str>, channels: &[String], include_default_channel: bool) -> bool {
+        if let Event::Presence(_) = self.event {
+            return true;
+        }
+        let in_channels = match self.channel() {
+            None => include_default_channel,
+            Some(channel) => channels.iter().any(|c| c == channel),
+        };
+        let allowed = match (self.audience.as_ref(), username) {
+            (None, _) => true,
+            (Some(audience), Some(username)) => audience.iter().any(|u| u == username),
+            (Some(_), None) => false,
+        };
+        in_channels && allowed
+    }
+}
+
+/// called for every event published, until it returns false
+type Listener = Box<dyn Fn(&Published) -> bool + Send>;
+
+/// Broadcasts the events to every listener in the process.
+///
+/// A listener is called for every event published after it subscribed, until it returns false.
+/// Listeners are called while the hub is locked, so they must not block.
+pub struct Hub {
+    listeners: Mutex<Vec<(usize, Listener)>>,
+    next_id: AtomicUsize,
+}
+
+impl Hub {
+    pub fn new() -> Hub {
+        Hub {
+            listeners: Mutex::new(Vec::new()),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the id of the listener, to `unsubscribe` it before it returns false
+    pub fn subscribe<F>(&self, listener: F) -> usize where F: Fn(&Published) -> bool + Send + 'static {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.listeners.lock().unwrap().push((id, Box::new(listener)));
+        id
+    }
+
+    /// removes the listener, if it is still there
+    pub fn unsubscribe(&self, id: usize) {
+        self.listeners.lock().unwrap().retain(|&(listener_id, _)| listener_id != id);
+    }
+
+    pub fn publish(&self, published: Published) {
+        self.listeners.lock().unwrap().retain(|(_, listener)| listener(&published));
+    }
+
+    #[cfg(test)]
+    pub fn listener_count(&self) -> usize {
+        self.listeners.lock().unwrap().len()
+    }
+}
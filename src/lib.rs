@@ -4,8 +4,14 @@
 extern crate error_chain;
 
 extern crate r2d2;
+#[cfg(feature = "postgres")]
 extern crate r2d2_postgres;
+#[cfg(feature = "postgres")]
 extern crate postgres;
+#[cfg(feature = "postgres")]
+extern crate fallible_iterator;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
 
 extern crate dotenv;
 extern crate chrono;
@@ -17,8 +23,10 @@ extern crate router;
 extern crate bodyparser;
 extern crate urlencoded;
 extern crate persistent;
+extern crate ws;
 extern crate crypto;
 extern crate rand;
+extern crate rustc_serialize;
 
 extern crate serde;
 #[macro_use]
@@ -28,26 +36,186 @@ extern crate serde_json;
 #[macro_use]
 mod error;
 mod message;
+mod channel;
 mod chatbix;
 mod user;
 mod routes;
 mod handler;
 mod utils;
+mod migrations;
+mod config;
+mod password;
+mod auth;
+mod hub;
+mod websocket;
+mod stream;
 
 use dotenv::dotenv;
 use std::env;
+use std::process;
 
+#[cfg(feature = "postgres")]
 use r2d2_postgres::{TlsMode, PostgresConnectionManager};
 
 use chatbix::*;
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+use migrations::{Migration,MigrationTarget};
+use config::Config;
 
-pub fn run_pg() {
+pub use migrations::MigrateCommand;
+
+/// Runs the server with the backend matching the scheme of `DATABASE_URL`:
+///
+/// * `postgres://...` or `postgresql://...` for postgres
+/// * `sqlite://path/to/file.db` for sqlite
+/// * `memory://` to not use a database at all
+pub fn run() {
     dotenv().ok();
     let database_url = env::var("DATABASE_URL")
         .expect("DATABASE_URL must be set");
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        run_pg(database_url)
+    } else if let Some(path) = database_url.strip_prefix("sqlite://") {
+        run_sqlite(path)
+    } else if database_url.starts_with("memory:") {
+        run_memory()
+    } else {
+        panic!("Unsupported DATABASE_URL `{}`: expected a postgres://, sqlite:// or memory:// url", database_url)
+    }
+}
+
+/// refuses to go any further if some migrations have not been applied
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+fn check_schema<T: MigrationTarget>(target: &T, migrations: &[Migration]) {
+    if let Err(e) = migrations::check(target, migrations) {
+        println!("Refusing to start: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn pg_pool(database_url: String) -> r2d2::Pool<PostgresConnectionManager> {
     let manager = PostgresConnectionManager::new(database_url,TlsMode::None).expect("Failed to establish connection to postgres instance");
     let pg_pool_config = r2d2::Config::builder().pool_size(15).min_idle(Some(3)).build();
-    let pg_pool = r2d2::Pool::new(pg_pool_config, manager).unwrap();
-    let chatbix = Chatbix::new(pg_pool);
+    r2d2::Pool::new(pg_pool_config, manager).unwrap()
+}
+
+#[cfg(feature = "postgres")]
+pub fn run_pg(database_url: String) {
+    let pg_pool = pg_pool(database_url.clone());
+    check_schema(&*pg_pool.get().expect("Failed to get a postgres connection"), migrations::POSTGRES_MIGRATIONS);
+    let chatbix = Chatbix::<PgPool>::new(pg_pool).with_config(Config::from_env()).with_notifications(&database_url);
+    handler::handler(chatbix);
+}
+
+#[cfg(not(feature = "postgres"))]
+pub fn run_pg(_database_url: String) {
+    panic!("chatbix was built without postgres support, enable the `postgres` feature")
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_pool(path: &str) -> SqlitePool {
+    let manager = SqliteConnectionManager::new(path);
+    let sqlite_pool_config = r2d2::Config::builder().pool_size(15).build();
+    r2d2::Pool::new(sqlite_pool_config, manager).expect("Failed to open sqlite database")
+}
+
+#[cfg(feature = "sqlite")]
+pub fn run_sqlite(path: &str) {
+    let sqlite_pool = sqlite_pool(path);
+    check_schema(&*sqlite_pool.get().expect("Failed to get a sqlite connection"), migrations::SQLITE_MIGRATIONS);
+    let chatbix = Chatbix::<SqlitePool>::new(sqlite_pool).with_config(Config::from_env());
+    handler::handler(chatbix);
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn run_sqlite(_path: &str) {
+    panic!("chatbix was built without sqlite support, enable the `sqlite` feature")
+}
+
+#[cfg(any(feature = "postgres", feature = "sqlite"))]
+fn run_migrate_command<T: MigrationTarget>(target: &T, migrations: &[Migration], command: MigrateCommand) -> error::Result<()> {
+    match command {
+        MigrateCommand::Up => {
+            let applied = migrations::up(target, migrations)?;
+            if applied.is_empty() {
+                println!("Nothing to do, the schema is up to date");
+            }
+            for migration in applied {
+                println!("Applied {}", migration.name);
+            }
+        },
+        MigrateCommand::Down => {
+            match migrations::down(target, migrations)? {
+                Some(migration) => println!("Reverted {}", migration.name),
+                None => println!("Nothing to revert"),
+            }
+        },
+        MigrateCommand::Status => {
+            for (migration, applied) in migrations::status(target, migrations)? {
+                println!("[{}] {}", if applied { "x" } else { " " }, migration.name);
+            }
+        },
+    };
+    Ok(())
+}
+
+/// Applies, reverts or lists the migrations of the database described by `DATABASE_URL`
+pub fn migrate(command: MigrateCommand) {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL")
+        .expect("DATABASE_URL must be set");
+    let result = if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        migrate_pg(database_url, command)
+    } else if let Some(path) = database_url.strip_prefix("sqlite://") {
+        migrate_sqlite(path, command)
+    } else if database_url.starts_with("memory:") {
+        println!("The memory backend has no schema to migrate");
+        Ok(())
+    } else {
+        panic!("Unsupported DATABASE_URL `{}`: expected a postgres://, sqlite:// or memory:// url", database_url)
+    };
+    if let Err(e) = result {
+        println!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn migrate_pg(database_url: String, command: MigrateCommand) -> error::Result<()> {
+    let pg_pool = pg_pool(database_url);
+    let pg = pg_pool.get().expect("Failed to get a postgres connection");
+    run_migrate_command(&*pg, migrations::POSTGRES_MIGRATIONS, command)
+}
+
+#[cfg(not(feature = "postgres"))]
+fn migrate_pg(_database_url: String, _command: MigrateCommand) -> error::Result<()> {
+    panic!("chatbix was built without postgres support, enable the `postgres` feature")
+}
+
+#[cfg(feature = "sqlite")]
+fn migrate_sqlite(path: &str, command: MigrateCommand) -> error::Result<()> {
+    let sqlite_pool = sqlite_pool(path);
+    let sqlite = sqlite_pool.get().expect("Failed to get a sqlite connection");
+    run_migrate_command(&*sqlite, migrations::SQLITE_MIGRATIONS, command)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn migrate_sqlite(_path: &str, _command: MigrateCommand) -> error::Result<()> {
+    panic!("chatbix was built without sqlite support, enable the `sqlite` feature")
+}
+
+/// Runs the server without any database: messages and users are only kept in memory,
+/// and are lost when the server stops.
+///
+/// The users listed in CHATBIX_MEMORY_ADMINS (comma separated) are admins once registered.
+pub fn run_memory() {
+    dotenv().ok();
+    let admins = env::var("CHATBIX_MEMORY_ADMINS").unwrap_or_default()
+        .split(',')
+        .map(|admin| admin.trim().to_owned())
+        .filter(|admin| !admin.is_empty())
+        .collect();
+    let chatbix = Chatbix::<MemoryStore>::new(MemoryStore::new().with_admins(admins)).with_config(Config::from_env());
     handler::handler(chatbix);
 }
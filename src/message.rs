@@ -1,7 +1,9 @@
 use chrono::NaiveDateTime;
-use super::utils::timestamp_ser;
+use super::utils::{timestamp_ser,optional_timestamp_ser};
 
-#[derive(Debug,Serialize)]
+use error::*;
+
+#[derive(Debug,Serialize,Clone)]
 pub struct Message {
     pub id: i32,
     pub author: String,
@@ -35,14 +37,132 @@ pub struct Message {
     pub tags: i32,
     pub color: Option<String>,
     pub channel: Option<String>,
+    /// the root of the thread this message replies to
+    pub reply_to: Option<i32>,
+    /// the number of replies to this message, if it is the root of a thread
+    pub reply_count: i64,
+    /// the reactions to this message, in the order they were first used
+    pub reactions: Vec<ReactionCount>,
+    /// whether the message is pinned in its channel
+    pub pinned: bool,
+    /// when the content was last edited, if ever
+    #[serde(serialize_with = "optional_timestamp_ser")]
+    pub edited_at: Option<NaiveDateTime>,
+    /// increases every time anything about the message changes,
+    /// `Interval::AllFromChange` returns the messages changed after one
+    #[serde(skip_serializing)]
+    pub change_id: i64,
+    /// the change_id the message was sent with: the messages changed after it
+    /// come along with the ones sent after it in `Interval::AllFromId`
+    #[serde(skip_serializing)]
+    pub created_change_id: i64,
+    /// deleted messages are never sent as such, only their tombstone is
+    #[serde(skip_serializing)]
+    pub deleted: Option<Tombstone>,
+}
+
+/// what is left of a deleted message until it is purged
+#[derive(Debug,Serialize,Clone)]
+pub struct Tombstone {
+    /// the id of the deleted message
+    pub id: i32,
+    pub deleted_by: String,
+    #[serde(serialize_with = "timestamp_ser")]
+    pub deleted_at: NaiveDateTime,
+    pub reason: Option<String>,
+}
+
+/// a row of chat_reactions
+#[derive(Debug,Clone)]
+pub struct Reaction {
+    pub message_id: i32,
+    pub username: String,
+    pub emoji: String,
+}
+
+/// how many users reacted to a message with some emoji
+#[derive(Debug,Serialize,Clone)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    /// whether the user making the request is one of them
+    pub me: bool,
+}
+
+/// a previous content of an edited message
+#[derive(Debug,Serialize,Clone)]
+pub struct Revision {
+    pub message_id: i32,
+    pub content: String,
+    /// when this content was replaced
+    #[serde(serialize_with = "timestamp_ser")]
+    pub edited_at: NaiveDateTime,
+    /// who replaced it
+    pub edited_by: String,
 }
 
-#[derive(Debug,Deserialize)]
+#[derive(Debug,Deserialize,Clone)]
 pub struct NewMessage {
+    /// ignored if the sender is authenticated, required otherwise
+    #[serde(default)]
     pub username: String,
     pub content: String,
     pub tags: Option<i32>,
     pub color: Option<String>,
     pub channel: Option<String>,
     pub auth_key: Option<String>,
+    /// the message this one replies to, which must be in the same channel
+    pub reply_to: Option<i32>,
+}
+
+/// Emojis are stored as sent by the clients, either the character itself or a `:shortcode:`.
+pub fn check_emoji(emoji: &str) -> Result<()> {
+    if emoji.is_empty() || emoji.len() > 64 || emoji.chars().any(char::is_whitespace) {
+        bail!(ErrorKind::InvalidEmoji);
+    }
+    Ok(())
+}
+
+/// The usernames mentioned as `@username` in the content, without duplicates, in order of appearance.
+///
+/// A mention must not follow an alphanumeric character, so that email addresses aren't mentions,
+/// and a trailing dot is taken as the end of the sentence.
+pub fn parse_mentions(content: &str) -> Vec<String> {
+    let mut mentions : Vec<String> = Vec::new();
+    let mut pieces = content.split('@');
+    let mut previous = match pieces.next() {
+        Some(piece) => piece,
+        None => return mentions,
+    };
+    for piece in pieces {
+        let after_word = previous.chars().last().map_or(false, char::is_alphanumeric);
+        previous = piece;
+        if after_word {
+            continue;
+        }
+        let end = piece.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.')).unwrap_or(piece.len());
+        let username = piece[..end].trim_end_matches('.');
+        if !username.is_empty() && !mentions.iter().any(|m| m == username) {
+            mentions.push(username.to_owned());
+        }
+    }
+    mentions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_mentions;
+
+    #[test]
+    fn parse_mentions_finds_every_username_once() {
+        assert_eq!(parse_mentions("@alice and @bob-2, hi @alice"), vec!["alice", "bob-2"]);
+        assert_eq!(parse_mentions("@first.last said hi to @some_one."), vec!["first.last", "some_one"]);
+    }
+
+    #[test]
+    fn parse_mentions_ignores_what_isnt_a_mention() {
+        assert!(parse_mentions("write to someone@example.com").is_empty());
+        assert!(parse_mentions("a lone @ or @. is nothing").is_empty());
+        assert!(parse_mentions("").is_empty());
+    }
 }
@@ -0,0 +1,311 @@
+// the memory backend has no schema
+#![cfg_attr(not(any(feature = "postgres", feature = "sqlite")), allow(dead_code, unused_macros))]
+
+use std::fmt::Display;
+
+use error::*;
+
+/// A versioned change of the database schema, embedded in the binary.
+///
+/// The sql files live in `migrations/<backend>/<name>/{up,down}.sql`.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+macro_rules! migration {
+    ($backend:tt, $version:expr, $name:tt) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $backend, "/", $name, "/up.sql")),
+            down: include_str!(concat!("../migrations/", $backend, "/", $name, "/down.sql")),
+        }
+    }
+}
+
+/// must be sorted by version
+#[cfg(feature = "postgres")]
+pub static POSTGRES_MIGRATIONS: &[Migration] = &[
+    migration!("postgres", 1, "0001_initial"),
+    migration!("postgres", 2, "0002_password_phc"),
+    migration!("postgres", 3, "0003_sessions"),
+    migration!("postgres", 4, "0004_message_edits"),
+    migration!("postgres", 5, "0005_tombstones"),
+    migration!("postgres", 6, "0006_replies"),
+    migration!("postgres", 7, "0007_reactions"),
+    migration!("postgres", 8, "0008_pins"),
+    migration!("postgres", 9, "0009_channels"),
+    migration!("postgres", 10, "0010_private_channels"),
+    migration!("postgres", 11, "0011_direct_messages"),
+    migration!("postgres", 12, "0012_read_markers"),
+    migration!("postgres", 13, "0013_mentions"),
+    migration!("postgres", 14, "0014_user_statuses"),
+];
+
+/// must be sorted by version
+#[cfg(feature = "sqlite")]
+pub static SQLITE_MIGRATIONS: &[Migration] = &[
+    migration!("sqlite", 1, "0001_initial"),
+    migration!("sqlite", 2, "0002_sessions"),
+    migration!("sqlite", 3, "0003_message_edits"),
+    migration!("sqlite", 4, "0004_tombstones"),
+    migration!("sqlite", 5, "0005_replies"),
+    migration!("sqlite", 6, "0006_reactions"),
+    migration!("sqlite", 7, "0007_pins"),
+    migration!("sqlite", 8, "0008_channels"),
+    migration!("sqlite", 9, "0009_private_channels"),
+    migration!("sqlite", 10, "0010_direct_messages"),
+    migration!("sqlite", 11, "0011_read_markers"),
+    migration!("sqlite", 12, "0012_mentions"),
+    migration!("sqlite", 13, "0013_user_statuses"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrateCommand {
+    /// apply every pending migration
+    Up,
+    /// revert the last applied migration
+    Down,
+    /// list the migrations and whether they are applied or not
+    Status,
+}
+
+/// A database able to run migrations and to remember which ones were applied
+/// (in the `schema_migrations` table).
+pub trait MigrationTarget {
+    /// creates the `schema_migrations` table if it doesn't exist yet,
+    /// and returns the versions that were applied
+    fn applied_versions(&self) -> Result<Vec<i32>>;
+
+    /// runs `migration.up` and records it, in a single transaction
+    fn apply(&self, migration: &Migration) -> Result<()>;
+
+    /// runs `migration.down` and forgets it, in a single transaction
+    fn revert(&self, migration: &Migration) -> Result<()>;
+}
+
+fn migration_error<E: Display>(version: i32, e: E) -> Error {
+    Error::from_kind(ErrorKind::MigrationFailed(version, format!("{}", e)))
+}
+
+/// returns every migration along with whether it has been applied or not
+pub fn status<'a, T: MigrationTarget>(target: &T, migrations: &'a [Migration]) -> Result<Vec<(&'a Migration, bool)>> {
+    let applied = target.applied_versions()?;
+    Ok(migrations.iter().map(|m| (m, applied.contains(&m.version))).collect())
+}
+
+/// applies every pending migration, and returns the ones that were applied
+pub fn up<'a, T: MigrationTarget>(target: &T, migrations: &'a [Migration]) -> Result<Vec<&'a Migration>> {
+    let mut applied = Vec::new();
+    for (migration, is_applied) in status(target, migrations)? {
+        if !is_applied {
+            target.apply(migration)?;
+            applied.push(migration);
+        }
+    }
+    Ok(applied)
+}
+
+/// reverts the last applied migration, if any
+pub fn down<'a, T: MigrationTarget>(target: &T, migrations: &'a [Migration]) -> Result<Option<&'a Migration>> {
+    let last_applied = status(target, migrations)?.into_iter()
+        .filter(|&(_, is_applied)| is_applied)
+        .map(|(migration, _)| migration)
+        .last();
+    if let Some(migration) = last_applied {
+        target.revert(migration)?;
+    }
+    Ok(last_applied)
+}
+
+/// fails with `SchemaOutOfDate` if some migrations still have to be applied
+pub fn check<T: MigrationTarget>(target: &T, migrations: &[Migration]) -> Result<()> {
+    let pending = status(target, migrations)?.into_iter()
+        .filter(|&(_, is_applied)| !is_applied)
+        .map(|(migration, _)| migration.version)
+        .collect::<Vec<i32>>();
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        bail!(ErrorKind::SchemaOutOfDate(pending))
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl MigrationTarget for ::postgres::Connection {
+    fn applied_versions(&self) -> Result<Vec<i32>> {
+        self.batch_execute("CREATE TABLE IF NOT EXISTS schema_migrations (
+                                version INTEGER PRIMARY KEY,
+                                name TEXT NOT NULL,
+                                applied_at TIMESTAMP NOT NULL DEFAULT now()
+                            );").map_err(|e| migration_error(0, e))?;
+        let rows = self.query("SELECT version FROM schema_migrations ORDER BY version", &[])
+       .map_err(|e| migration_error(0, e))?;
+        Ok(rows.iter().map(|r| r.get("version")).collect())
+    }
+
+    fn apply(&self, migration: &Migration) -> Result<()> {
+        let transaction = self.transaction().map_err(|e| migration_error(migration.version, e))?;
+        transaction.batch_execute(migration.up).map_err(|e| migration_error(migration.version, e))?;
+        transaction.execute("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                            &[&migration.version, &migration.name])
+        .map_err(|e| migration_error(migration.version, e))?;
+        transaction.commit().map_err(|e| migration_error(migration.version, e))
+    }
+
+    fn revert(&self, migration: &Migration) -> Result<()> {
+        let transaction = self.transaction().map_err(|e| migration_error(migration.version, e))?;
+        transaction.batch_execute(migration.down).map_err(|e| migration_error(migration.version, e))?;
+        transaction.execute("DELETE FROM schema_migrations WHERE version = $1", &[&migration.version])
+        .map_err(|e| migration_error(migration.version, e))?;
+        transaction.commit().map_err(|e| migration_error(migration.version, e))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl MigrationTarget for ::rusqlite::Connection {
+    fn applied_versions(&self) -> Result<Vec<i32>> {
+        self.execute_batch("CREATE TABLE IF NOT EXISTS schema_migrations (
+                                version INTEGER PRIMARY KEY,
+                                name TEXT NOT NULL,
+                                applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+                            );").map_err(|e| migration_error(0, e))?;
+        let mut statement = self.prepare("SELECT version FROM schema_migrations ORDER BY version")
+       .map_err(|e| migration_error(0, e))?;
+        let rows = statement.query_map(&[], |r| r.get(0)).map_err(|e| migration_error(0, e))?;
+        rows.map(|r| r.map_err(|e| migration_error(0, e))).collect()
+    }
+
+    // the sqlite connection is shared through `&self`, so the transaction is opened
+    // by hand instead of with `Connection::transaction`, which requires `&mut self`
+    fn apply(&self, migration: &Migration) -> Result<()> {
+        let batch = format!("BEGIN;\n{}\nINSERT INTO schema_migrations (version, name) VALUES ({}, '{}');\nCOMMIT;",
+                            migration.up, migration.version, migration.name);
+        self.execute_batch(&batch).map_err(|e| {
+            let _ = self.execute_batch("ROLLBACK;");
+            migration_error(migration.version, e)
+        })
+    }
+
+    fn revert(&self, migration: &Migration) -> Result<()> {
+        let batch = format!("BEGIN;\n{}\nDELETE FROM schema_migrations WHERE version = {};\nCOMMIT;",
+                            migration.down, migration.version);
+        self.execute_batch(&batch).map_err(|e| {
+            let _ = self.execute_batch("ROLLBACK;");
+            migration_error(migration.version, e)
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use super::*;
+
+    /// remembers what was run instead of touching a database
+    struct FakeTarget {
+        applied: RefCell<Vec<i32>>,
+        log: RefCell<Vec<String>>,
+    }
+
+    impl FakeTarget {
+        fn new(applied: Vec<i32>) -> FakeTarget {
+            FakeTarget {
+                applied: RefCell::new(applied),
+                log: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl MigrationTarget for FakeTarget {
+        fn applied_versions(&self) -> Result<Vec<i32>> {
+            Ok(self.applied.borrow().clone())
+        }
+
+        fn apply(&self, migration: &Migration) -> Result<()> {
+            self.applied.borrow_mut().push(migration.version);
+            self.log.borrow_mut().push(migration.up.to_owned());
+            Ok(())
+        }
+
+        fn revert(&self, migration: &Migration) -> Result<()> {
+            self.applied.borrow_mut().retain(|&version| version != migration.version);
+            self.log.borrow_mut().push(migration.down.to_owned());
+            Ok(())
+        }
+    }
+
+    static MIGRATIONS: & [Migration] = &[
+        Migration { version: 1, name: "0001_first", up: "up 1", down: "down 1" },
+        Migration { version: 2, name: "0002_second", up: "up 2", down: "down 2" },
+        Migration { version: 3, name: "0003_third", up: "up 3", down: "down 3" },
+    ];
+
+    fn assert_sorted(migrations: &[Migration]) {
+        for pair in migrations.windows(2) {
+            assert!(pair[0].version < pair[1].version, "migration {} comes after {}", pair[1].name, pair[0].name);
+        }
+        for migration in migrations {
+            assert!(migration.name.starts_with(&format!("{:04}_", migration.version)), "{} isn't version {}", migration.name, migration.version);
+        }
+    }
+
+    #[test]
+    fn up_applies_the_pending_migrations_in_order() {
+        let target = FakeTarget::new(vec![1]);
+        let applied = up(&target, MIGRATIONS).unwrap();
+        assert_eq!(applied.iter().map(|m| m.version).collect::<Vec<i32>>(), vec![2, 3]);
+        assert_eq!(*target.log.borrow(), vec!["up 2", "up 3"]);
+        assert!(up(&target, MIGRATIONS).unwrap().is_empty());
+    }
+
+    #[test]
+    fn down_reverts_the_last_applied_migration_only() {
+        let target = FakeTarget::new(vec![1, 2]);
+        assert_eq!(down(&target, MIGRATIONS).unwrap().map(|m| m.version), Some(2));
+        assert_eq!(down(&target, MIGRATIONS).unwrap().map(|m| m.version), Some(1));
+        assert!(down(&target, MIGRATIONS).unwrap().is_none());
+        assert_eq!(*target.log.borrow(), vec!["down 2", "down 1"]);
+    }
+
+    #[test]
+    fn check_lists_the_pending_migrations() {
+        assert!(check(&FakeTarget::new(vec![1, 2, 3]), MIGRATIONS).is_ok());
+        match check(&FakeTarget::new(vec![1]), MIGRATIONS) {
+            Err(Error(ErrorKind::SchemaOutOfDate(pending), _)) => assert_eq!(pending, vec![2, 3]),
+            _ => panic!("expected SchemaOutOfDate"),
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn postgres_migrations_are_sorted() {
+        assert_sorted(POSTGRES_MIGRATIONS);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_migrations_are_sorted() {
+        assert_sorted(SQLITE_MIGRATIONS);
+    }
+
+    #[test]
+    fn test_migrations_are_sorted() {
+        assert_sorted(MIGRATIONS);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_migrations_can_be_reverted_and_applied_again() {
+        let connection = ::rusqlite::Connection::open_in_memory().unwrap();
+        assert_eq!(up(&connection, SQLITE_MIGRATIONS).unwrap().len(), SQLITE_MIGRATIONS.len());
+        while down(&connection, SQLITE_MIGRATIONS).unwrap().is_some() {}
+        assert!(connection.applied_versions().unwrap().is_empty());
+        let tables : i64 = connection.query_row("SELECT COUNT(*) FROM sqlite_master WHERE name LIKE 'chat_%'", &[], |row| row.get(0)).unwrap();
+        assert_eq!(tables, 0);
+        assert_eq!(up(&connection, SQLITE_MIGRATIONS).unwrap().len(), SQLITE_MIGRATIONS.len());
+        assert!(check(&connection, SQLITE_MIGRATIONS).is_ok());
+    }
+}
@@ -0,0 +1,156 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha512;
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::util::fixed_time_eq;
+use rand::{OsRng, Rng};
+use rustc_serialize::base64::{self, ToBase64, FromBase64};
+
+/// PHC strings use base64 without padding
+const B64: base64::Config = base64::Config {
+    char_set: base64::CharacterSet::Standard,
+    newline: base64::Newline::LF,
+    pad: false,
+    line_length: None,
+};
+
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// scrypt cost parameters used when hashing new passwords
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordParams {
+    /// log2 of N, the CPU/memory cost
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for PasswordParams {
+    fn default() -> PasswordParams {
+        PasswordParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl PasswordParams {
+    /// panics if the parameters are invalid
+    pub fn scrypt_params(&self) -> ScryptParams {
+        ScryptParams::new(self.log_n, self.r, self.p)
+    }
+}
+
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// the password is right, but it is stored with an outdated scheme or cost:
+    /// it should be hashed again and replaced
+    NeedsRehash,
+}
+
+/// hashes the password with a random salt, and returns it as a PHC string like
+/// `$scrypt$ln=15,r=8,p=1$<salt>$<hash>`
+pub fn hash(password: &str, params: &PasswordParams) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng::new().expect("Failed to access the OS random number generator").fill_bytes(&mut salt);
+    let mut output = [0u8; HASH_LEN];
+    scrypt(password.as_bytes(), &salt, &params.scrypt_params(), &mut output);
+    format!("$scrypt$ln={},r={},p={}${}${}", params.log_n, params.r, params.p, salt.to_base64(B64), output.to_base64(B64))
+}
+
+/// Passwords used to be stored as the first 64 hex chars of their (unsalted) sha512.
+fn legacy_hash(password: &str) -> String {
+    let mut hasher = Sha512::new();
+    hasher.input_str(password);
+    let hex_password = hasher.result_str();
+    hex_password.split_at(64).0.to_owned()
+}
+
+fn parse_phc(stored: &str) -> Option<(PasswordParams, Vec<u8>, Vec<u8>)> {
+    let parts = stored.split('$').collect::<Vec<&str>>();
+    if parts.len() != 5 || !parts[0].is_empty() || parts[1] != "scrypt" {
+        return None;
+    }
+    let mut params = PasswordParams { log_n: 0, r: 0, p: 0 };
+    for param in parts[2].split(',') {
+        let mut param = param.splitn(2, '=');
+        match (param.next(), param.next()) {
+            (Some("ln"), Some(v)) => params.log_n = match v.parse() { Ok(v) => v, Err(_) => return None },
+            (Some("r"), Some(v)) => params.r = match v.parse() { Ok(v) => v, Err(_) => return None },
+            (Some("p"), Some(v)) => params.p = match v.parse() { Ok(v) => v, Err(_) => return None },
+            _ => return None,
+        }
+    }
+    if params.log_n == 0 || params.log_n >= 64 || params.r == 0 || params.p == 0 {
+        return None;
+    }
+    match (parts[3].from_base64(), parts[4].from_base64()) {
+        (Ok(salt), Ok(hash)) => Some((params, salt, hash)),
+        _ => None,
+    }
+}
+
+/// checks the password against what is stored in the database
+pub fn verify(password: &str, stored: &str, params: &PasswordParams) -> Verification {
+    if !stored.starts_with('$') {
+        return if fixed_time_eq(legacy_hash(password).as_bytes(), stored.as_bytes()) {
+            Verification::NeedsRehash
+        } else {
+            Verification::Invalid
+        };
+    }
+    let (stored_params, salt, stored_hash) = match parse_phc(stored) {
+        Some(phc) => phc,
+        None => return Verification::Invalid,
+    };
+    let mut output = vec![0u8; stored_hash.len()];
+    scrypt(password.as_bytes(), &salt, &stored_params.scrypt_params(), &mut output);
+    if !fixed_time_eq(&output, &stored_hash) {
+        Verification::Invalid
+    } else if stored_params != *params {
+        Verification::NeedsRehash
+    } else {
+        Verification::Valid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cheap enough for the tests
+    const PARAMS: PasswordParams = PasswordParams { log_n: 4, r: 1, p: 1 };
+
+    #[test]
+    fn hashed_passwords_are_verified() {
+        let stored = hash("hunter2", &PARAMS);
+        assert!(stored.starts_with("$scrypt$ln=4,r=1,p=1$"));
+        assert!(matches!(verify("hunter2", &stored, &PARAMS), Verification::Valid));
+        assert!(matches!(verify("hunter3", &stored, &PARAMS), Verification::Invalid));
+    }
+
+    #[test]
+    fn hashes_with_other_params_need_a_rehash() {
+        let stored = hash("hunter2", &PasswordParams { log_n: 5, ..PARAMS });
+        assert!(matches!(verify("hunter2", &stored, &PARAMS), Verification::NeedsRehash));
+        assert!(matches!(verify("hunter3", &stored, &PARAMS), Verification::Invalid));
+    }
+
+    #[test]
+    fn legacy_hashes_are_verified_then_need_a_rehash() {
+        // the first 64 hex chars of sha512("password")
+        let stored = "b109f3bbbc244eb82441917ed06d618b9008dd09b3befd1b5e07394c706a8bb9";
+        assert_eq!(legacy_hash("password"), stored);
+        assert!(matches!(verify("password", stored, &PARAMS), Verification::NeedsRehash));
+        assert!(matches!(verify("Password", stored, &PARAMS), Verification::Invalid));
+    }
+
+    #[test]
+    fn malformed_hashes_never_match() {
+        assert!(matches!(verify("", "", &PARAMS), Verification::Invalid));
+        assert!(matches!(verify("hunter2", "$scrypt$ln=4,r=1$c2FsdA$aGFzaA", &PARAMS), Verification::Invalid));
+        assert!(matches!(verify("hunter2", "$bcrypt$ln=4,r=1,p=1$c2FsdA$aGFzaA", &PARAMS), Verification::Invalid));
+    }
+}
@@ -1,13 +1,20 @@
 extern crate bodyparser;
 extern crate serde_json;
 
+use std::fmt;
 use std::sync::Arc;
+use std::collections::HashMap;
+use std::cmp::min;
 use super::chatbix::*;
 use super::message::*;
-use super::user::ConnectedUser;
+use super::user::{ConnectedUser,SessionInfo,UserStatus};
+use super::channel::{Channel,Conversation,Unread};
 use super::utils::timestamp_parse;
-use iron::status;
+use super::auth;
+use super::stream::EventStream;
+use iron::{status,mime};
 use iron::prelude::*;
+use iron::headers::{UserAgent,Authorization,Bearer,CacheControl,CacheDirective};
 use urlencoded::{UrlEncodedQuery,UrlDecodingError};
 
 use error::*;
@@ -23,6 +30,27 @@ struct JsonSuccess {
     auth_key: Option<String>,
     #[serde(skip_serializing_if="Option::is_none")]
     fulltext: Option<Vec<Match>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    sessions: Option<Vec<SessionInfo>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    revisions: Option<Vec<Revision>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    change_id: Option<i64>,
+    deleted: Option<Vec<Tombstone>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    channels: Option<Vec<Channel>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    members: Option<Vec<String>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    conversations: Option<Vec<Conversation>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    unread: Option<HashMap<String, Unread>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    typing: Option<HashMap<String, Vec<String>>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    user_status: Option<UserStatus>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    users_by_channel: Option<HashMap<String, Vec<String>>>,
 }
 
 impl JsonSuccess {
@@ -33,6 +61,17 @@ impl JsonSuccess {
             users_connected: None,
             auth_key: None,
             fulltext: None,
+            sessions: None,
+            revisions: None,
+            change_id: None,
+            deleted: None,
+            channels: None,
+            members: None,
+            conversations: None,
+            unread: None,
+            typing: None,
+            user_status: None,
+            users_by_channel: None,
         }
     }
 
@@ -51,6 +90,57 @@ impl JsonSuccess {
         }
     }
 
+    pub fn with_channels(channels: Vec<Channel>) -> JsonSuccess {
+        JsonSuccess {
+            channels: Some(channels),
+            ..Self::empty()
+        }
+    }
+
+    pub fn with_members(members: Vec<String>) -> JsonSuccess {
+        JsonSuccess {
+            members: Some(members),
+            ..Self::empty()
+        }
+    }
+
+    pub fn with_conversations(conversations: Vec<Conversation>) -> JsonSuccess {
+        JsonSuccess {
+            conversations: Some(conversations),
+            ..Self::empty()
+        }
+    }
+
+    /// the tombstones are only there for incremental queries
+    pub fn deleted(self, deleted: Option<Vec<Tombstone>>) -> JsonSuccess {
+        JsonSuccess {
+            deleted,
+            ..self
+        }
+    }
+
+    /// only for authenticated users
+    pub fn unread(self, unread: Option<HashMap<String, Unread>>) -> JsonSuccess {
+        JsonSuccess {
+            unread,
+            ..self
+        }
+    }
+
+    pub fn typing(self, typing: HashMap<String, Vec<String>>) -> JsonSuccess {
+        JsonSuccess {
+            typing: Some(typing),
+            ..self
+        }
+    }
+
+    pub fn users_by_channel(self, users_by_channel: HashMap<String, Vec<String>>) -> JsonSuccess {
+        JsonSuccess {
+            users_by_channel: Some(users_by_channel),
+            ..self
+        }
+    }
+
     pub fn with_auth_key(auth_key: String) -> JsonSuccess {
         JsonSuccess {
             auth_key: Some(auth_key),
@@ -65,29 +155,65 @@ impl JsonSuccess {
         }
     }
 
-    pub fn to_string(&self) -> String {
-        ::serde_json::to_string(&self).unwrap()
+    pub fn with_sessions(sessions: Vec<SessionInfo>) -> JsonSuccess {
+        JsonSuccess {
+            sessions: Some(sessions),
+            ..Self::empty()
+        }
+    }
+
+    pub fn with_user_status(user_status: UserStatus) -> JsonSuccess {
+        JsonSuccess {
+            user_status: Some(user_status),
+            ..Self::empty()
+        }
+    }
+
+    /// what to send back as `change_id` to get what changed since
+    pub fn change_id(self, change_id: i64) -> JsonSuccess {
+        JsonSuccess {
+            change_id: Some(change_id),
+            ..self
+        }
+    }
+
+    pub fn with_revisions(revisions: Vec<Revision>) -> JsonSuccess {
+        JsonSuccess {
+            revisions: Some(revisions),
+            ..Self::empty()
+        }
+    }
+}
+
+impl fmt::Display for JsonSuccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&::serde_json::to_string(&self).unwrap())
     }
 }
 
 pub fn new_message<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
-    let message : Result<_> = req.get_ref::<bodyparser::Struct<NewMessage>>()
+    let message : Result<_> = req.get::<bodyparser::Struct<NewMessage>>()
         .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
     let message = chatbix_try!(message);
-    let status : IronResult<()> = match message.as_ref() {
+    match message {
         None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
-        Some(new_message) => Ok(chatbix_try!(chatbix.new_message(new_message))),
+        Some(new_message) => {
+            let user = chatbix_try!(auth::user(req, &chatbix, Some(new_message.username.as_str()), new_message.auth_key.as_deref()));
+            chatbix_try!(chatbix.post_message(&new_message, user.as_ref()))
+        },
     };
-    status.map(|_| Response::with((status::Ok,JsonSuccess::empty().to_string())))
+    Ok(Response::with((status::Ok,JsonSuccess::empty().to_string())))
 }
 
 pub fn heartbeat<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
     let mut channels : Vec<String> = Vec::new();
     let mut include_default_channel : bool = true;
-    let mut credentials : Option<(String,Option<String>,bool)> = None;
+    let mut credentials : (Option<String>,Option<String>) = (None,None);
+    let mut active = true;
+    let mut wait : u64 = 0;
     let interval = match req.get_ref::<UrlEncodedQuery>() {
         Ok(hashmap) => {
-            if let Some(tmp_chans) = hashmap.get("channels").and_then(|c| c.get(0)) {
+            if let Some(tmp_chans) = hashmap.get("channels").and_then(|c| c.first()) {
                 channels = tmp_chans.split(',').map(|s:&str| s.to_owned()).collect::<Vec<String>>();
             };
             if let Some(tmp_chans) = hashmap.get("channel") {
@@ -98,46 +224,114 @@ pub fn heartbeat<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Re
             if hashmap.get("no_default_channel").is_some() {
                 include_default_channel = false;
             };
-            let username = hashmap.get("username").map(|u| u.get(0).unwrap().clone());
-            let auth_key = hashmap.get("auth_key").map(|k| k.get(0).unwrap().clone());
-            let active = hashmap.get("active").map(|active| {
-                let active = active.get(0).unwrap();
+            let username = hashmap.get("username").map(|u| u.first().unwrap().clone());
+            let auth_key = hashmap.get("auth_key").map(|k| k.first().unwrap().clone());
+            active = hashmap.get("active").map(|active| {
+                let active = active.first().unwrap();
                 !(active == "false" || active == "FALSE" || active == "0")
             }).unwrap_or(true);
-            if let Some(username) = username {
-                credentials = Some((username,auth_key.clone(),active));
+            credentials = (username,auth_key);
+            if let Some(seconds) = hashmap.get("wait") {
+                wait = chatbix_try!(seconds.first().unwrap().parse::<u64>().map_err(Error::from));
             };
-            match (hashmap.get("timestamp"),hashmap.get("message_id")) {
-                (None,None) => Interval::default(),
-                (Some(timestamps),None) => Interval::AllFromTimestamp(chatbix_try!(timestamp_parse(timestamps.get(0).unwrap()))),
-                (_,Some(message_id)) => Interval::AllFromId(chatbix_try!(message_id.get(0).unwrap().parse::<i32>().map_err(|e| Error::from(e)))),
+            match (hashmap.get("timestamp"),hashmap.get("message_id"),hashmap.get("change_id")) {
+                (None,None,None) => Interval::default(),
+                (Some(timestamps),None,None) => Interval::AllFromTimestamp(chatbix_try!(timestamp_parse(timestamps.first().unwrap()))),
+                (_,Some(message_id),None) => Interval::AllFromId(chatbix_try!(message_id.first().unwrap().parse::<i32>().map_err(Error::from))),
+                (_,_,Some(change_id)) => Interval::AllFromChange(chatbix_try!(change_id.first().unwrap().parse::<i64>().map_err(Error::from))),
             }
         },
         Err(UrlDecodingError::EmptyQuery) => Interval::default(),
         Err(UrlDecodingError::BodyError(body_error)) => {
-            return Err(IronError::new(body_error,(status::BadRequest)))
+            return Err(IronError::new(body_error,status::BadRequest))
         },
     };
-    let connected_users = match credentials {
-        Some((username,Some(auth_key),active)) => {
-            chatbix_try!(chatbix.heartbeat_mut(&*username, Some(&*auth_key), active))
-        },
-        Some((username,None,active)) => {
-            chatbix_try!(chatbix.heartbeat_mut(&*username, None, active))
-        },
-        None => chatbix.heartbeat()
+    let (username, auth_key) = credentials;
+    let sent_header = req.headers.has::<Authorization<Bearer>>();
+    let user = match auth::user(req, &chatbix, username.as_deref(), auth_key.as_deref()) {
+        Ok(user) => user,
+        // an invalid legacy auth_key only means that the user is not logged in
+        Err(_) if !sent_header => None,
+        Err(e) => return e.into(),
+    };
+    let connected_users = match (user.as_ref(), username) {
+        (Some(user), _) => chatbix_try!(chatbix.heartbeat_mut(Some(user), &user.username, active, &channels, include_default_channel)),
+        (None, Some(username)) => chatbix_try!(chatbix.heartbeat_mut(None, &username, active, &channels, include_default_channel)),
+        (None, None) => chatbix_try!(chatbix.heartbeat(&channels, include_default_channel, None))
     };
-    let messages = chatbix_try!(chatbix.get_messages(interval,channels,include_default_channel));
-    Ok(Response::with((status::Ok,JsonSuccess::with_messages_and_connected(messages, connected_users).to_string())))
+    let (messages, deleted, change_id) = chatbix_try!(chatbix.wait_messages(interval,&channels,include_default_channel,user.as_ref(),wait));
+    let unread = match user {
+        Some(ref user) => Some(chatbix_try!(chatbix.unread(user, &channels, include_default_channel))),
+        None => None,
+    };
+    let typing = chatbix_try!(chatbix.typing_in(&channels, include_default_channel, user.as_ref()));
+    let users_by_channel = chatbix_try!(chatbix.users_by_channel(&channels, include_default_channel, user.as_ref()));
+    Ok(Response::with((status::Ok,JsonSuccess::with_messages_and_connected(messages, connected_users).deleted(deleted).change_id(change_id)
+                       .unread(unread).typing(typing).users_by_channel(users_by_channel).to_string())))
 }
 // ^ TODO: refactor this with heartbeat
 
+/// Keeps the response open, and streams the events of the channels as `text/event-stream`.
+///
+/// Reconnecting clients get what they missed since `Last-Event-ID`, which is a change id.
+pub fn stream<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let mut last_event_id : Option<i64> = match req.headers.get_raw("Last-Event-ID").and_then(|values| values.first()) {
+        Some(value) => Some(chatbix_try!(String::from_utf8_lossy(value).trim().parse::<i64>().map_err(Error::from))),
+        None => None,
+    };
+    let mut channels : Vec<String> = Vec::new();
+    let mut include_default_channel = true;
+    let mut credentials : (Option<String>,Option<String>) = (None, None);
+    match req.get_ref::<UrlEncodedQuery>() {
+        Ok(hashmap) => {
+            credentials = (hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                           hashmap.get("auth_key").map(|k| k.first().unwrap().clone()));
+            if let Some(tmp_chans) = hashmap.get("channels").and_then(|c| c.first()) {
+                channels = tmp_chans.split(',').map(|s:&str| s.to_owned()).collect::<Vec<String>>();
+            };
+            if let Some(tmp_chans) = hashmap.get("channel") {
+                for c in tmp_chans {
+                    channels.push(c.clone());
+                }
+            };
+            if hashmap.get("no_default_channel").is_some() {
+                include_default_channel = false;
+            };
+            // EventSource can't send headers, and only sends Last-Event-ID by itself when reconnecting
+            if let (None, Some(change_id)) = (last_event_id, hashmap.get("last_event_id")) {
+                last_event_id = Some(chatbix_try!(change_id.first().unwrap().parse::<i64>().map_err(Error::from)));
+            };
+        },
+        Err(UrlDecodingError::EmptyQuery) => {},
+        Err(UrlDecodingError::BodyError(body_error)) =>
+            return Err(IronError::new(body_error,status::BadRequest)),
+    };
+    let user = chatbix_try!(auth::user(req, &chatbix, credentials.0.as_deref(), credentials.1.as_deref()));
+    let event_stream = chatbix_try!(EventStream::new(&chatbix, user, &channels, include_default_channel, last_event_id));
+    let event_stream_mime : mime::Mime = "text/event-stream".parse().unwrap();
+    let mut response = Response::with((status::Ok, event_stream_mime));
+    response.headers.set(CacheControl(vec![CacheDirective::NoCache]));
+    response.body = Some(Box::new(event_stream));
+    Ok(response)
+}
+
 pub fn get_messages<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
     let mut channels : Vec<String> = Vec::new();
     let mut include_default_channel = true;
+    let mut thread : Option<i32> = None;
+    let mut wait : u64 = 0;
+    let mut credentials : (Option<String>,Option<String>) = (None, None);
     let interval = match req.get_ref::<UrlEncodedQuery>() {
         Ok(hashmap) => {
-            if let Some(tmp_chans) = hashmap.get("channels").and_then(|c| c.get(0)) {
+            credentials = (hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                           hashmap.get("auth_key").map(|k| k.first().unwrap().clone()));
+            if let Some(root_id) = hashmap.get("thread") {
+                thread = Some(chatbix_try!(root_id.first().unwrap().parse::<i32>().map_err(Error::from)));
+            };
+            if let Some(seconds) = hashmap.get("wait") {
+                wait = chatbix_try!(seconds.first().unwrap().parse::<u64>().map_err(Error::from));
+            };
+            if let Some(tmp_chans) = hashmap.get("channels").and_then(|c| c.first()) {
                 channels = tmp_chans.split(',').map(|s:&str| s.to_owned()).collect::<Vec<String>>();
             };
             if let Some(tmp_chans) = hashmap.get("channel") {
@@ -148,33 +342,48 @@ pub fn get_messages<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult
             if hashmap.get("no_default_channel").is_some() {
                 include_default_channel = false;
             };
-            match (hashmap.get("message_id"),hashmap.get("timestamp"),hashmap.get("timestamp_end")) {
-                (None,Some(timestamps),None) => {
-                    let timestamp = chatbix_try!(timestamp_parse(timestamps.get(0).unwrap())); 
+            match (hashmap.get("change_id"),hashmap.get("message_id"),hashmap.get("timestamp"),hashmap.get("timestamp_end")) {
+                (Some(change_id),_,_,_) => {
+                    let change_id = chatbix_try!(change_id.first().unwrap().parse::<i64>()
+                        .map_err(Error::from));
+                    Interval::AllFromChange(change_id)
+                },
+                (None,None,Some(timestamps),None) => {
+                    let timestamp = chatbix_try!(timestamp_parse(timestamps.first().unwrap())); 
                     Interval::AllFromTimestamp(timestamp)
                 },
-                (None,Some(timestamps),Some(timestamps_end)) => {
-                    let timestamp = chatbix_try!(timestamp_parse(timestamps.get(0).unwrap()));
-                    let timestamp_end = chatbix_try!(timestamp_parse(timestamps_end.get(0).unwrap()));
+                (None,None,Some(timestamps),Some(timestamps_end)) => {
+                    let timestamp = chatbix_try!(timestamp_parse(timestamps.first().unwrap()));
+                    let timestamp_end = chatbix_try!(timestamp_parse(timestamps_end.first().unwrap()));
                     Interval::FromToTimestamp(timestamp, timestamp_end)
                 },
-                (Some(message_id),_,_) => {
-                    let message_id = chatbix_try!(message_id.get(0).unwrap().parse::<i32>()
-                        .map_err(|e| Error::from(e)));
+                (None,Some(message_id),_,_) => {
+                    let message_id = chatbix_try!(message_id.first().unwrap().parse::<i32>()
+                        .map_err(Error::from));
                     Interval::AllFromId(message_id)
                 },
-                (_,_,_) => {
+                (_,_,_,_) => {
                     Interval::default()
                 },
             }
         },
         Err(UrlDecodingError::EmptyQuery) => Interval::default(),
         Err(UrlDecodingError::BodyError(body_error)) => {
-            return Err(IronError::new(body_error,(status::BadRequest)))
+            return Err(IronError::new(body_error,status::BadRequest))
         },
     };
-    let messages = chatbix_try!(chatbix.get_messages(interval,channels,include_default_channel));
-    Ok(Response::with((status::Ok,JsonSuccess::with_messages(messages).to_string())))
+    let user = chatbix_try!(auth::user(req, &chatbix, credentials.0.as_deref(), credentials.1.as_deref()));
+    if let Some(root_id) = thread {
+        let messages = chatbix_try!(chatbix.thread(root_id, user.as_ref()));
+        return Ok(Response::with((status::Ok,JsonSuccess::with_messages(messages).to_string())));
+    }
+    let (messages, deleted, change_id) = chatbix_try!(chatbix.wait_messages(interval,channels,include_default_channel,user.as_ref(),wait));
+    Ok(Response::with((status::Ok,JsonSuccess::with_messages(messages).deleted(deleted).change_id(change_id).to_string())))
+}
+
+/// stored along with sessions, so that users can tell them apart
+fn user_agent(req: &Request) -> Option<String> {
+    req.headers.get::<UserAgent>().map(|user_agent| user_agent.0.clone())
 }
 
 #[derive(Debug, Deserialize)]
@@ -184,84 +393,543 @@ struct LoginPayload {
 }
 
 pub fn register<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let user_agent = user_agent(req);
     let login_payload : Result<_> = req.get_ref::<bodyparser::Struct<LoginPayload>>()
         .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
     let login_payload = chatbix_try!(login_payload);
     let auth_key = match login_payload.as_ref() {
         None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
-        Some(p) => chatbix_try!(chatbix.register(p.username.as_str(), p.password.as_str())),
+        Some(p) => chatbix_try!(chatbix.register(p.username.as_str(), p.password.as_str(), user_agent.as_deref())),
     };
     Ok(Response::with((status::Ok,JsonSuccess::with_auth_key(auth_key).to_string())))
 }
 
 pub fn login<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let user_agent = user_agent(req);
     let login_payload : Result<_> = req.get_ref::<bodyparser::Struct<LoginPayload>>()
         .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
     let login_payload = chatbix_try!(login_payload);
     let auth_key = match login_payload.as_ref() {
         None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
-        Some(p) => chatbix_try!(chatbix.login(p.username.as_str(), p.password.as_str())),
+        Some(p) => chatbix_try!(chatbix.login(p.username.as_str(), p.password.as_str(), user_agent.as_deref())),
     };
     Ok(Response::with((status::Ok,JsonSuccess::with_auth_key(auth_key).to_string())))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct DelMessage {
     pub message_id: i32,
-    pub username: String,
-    pub auth_key: String,
+    /// shown in the tombstone
+    pub reason: Option<String>,
+    pub username: Option<String>,
+    pub auth_key: Option<String>,
 }
 
 /// admin only : taht is why auth_key is required
 pub fn delete_message<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
-    let del_message : Result<_> = req.get_ref::<bodyparser::Struct<DelMessage>>()
+    let del_message : Result<_> = req.get::<bodyparser::Struct<DelMessage>>()
         .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
     let del_message = chatbix_try!(del_message);
-    match del_message.as_ref() { 
+    match del_message {
         None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
-        Some(del_message) => chatbix_try!(chatbix.try_del(del_message.username.as_str(), del_message.auth_key.as_str(), del_message.message_id))
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.try_del(&user, p.message_id, p.reason.as_deref()))
+        },
     };
     Ok(Response::with((status::Ok,JsonSuccess::empty().to_string())))
 }
 
-#[derive(Debug, Deserialize)]
+/// admin only : removes the message for good, whether it was deleted before or not
+pub fn purge_message<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    // same payload as deleting, without the reason
+    let purge_message : Result<_> = req.get::<bodyparser::Struct<DelMessage>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let purge_message = chatbix_try!(purge_message);
+    match purge_message {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.try_purge(&user, p.message_id))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::empty().to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct EditPayload {
+    message_id: i32,
+    content: String,
+    username: Option<String>,
+    auth_key: Option<String>,
+}
+
+/// the author or an admin only, returns the edited message
+pub fn edit_message<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let edit_payload : Result<_> = req.get::<bodyparser::Struct<EditPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let edit_payload = chatbix_try!(edit_payload);
+    let message = match edit_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.edit(&user, p.message_id, p.content.as_str()))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::with_messages(vec![message]).to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ReactPayload {
+    message_id: i32,
+    emoji: String,
+    username: Option<String>,
+    auth_key: Option<String>,
+}
+
+fn react_or_unreact<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>, add: bool)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let react_payload : Result<_> = req.get::<bodyparser::Struct<ReactPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let react_payload = chatbix_try!(react_payload);
+    let message = match react_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.react(&user, p.message_id, p.emoji.as_str(), add))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::with_messages(vec![message]).to_string())))
+}
+
+/// returns the message with its updated reactions
+pub fn react<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    react_or_unreact(req, chatbix, true)
+}
+
+/// returns the message with its updated reactions
+pub fn unreact<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    react_or_unreact(req, chatbix, false)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PinPayload {
+    message_id: i32,
+    username: Option<String>,
+    auth_key: Option<String>,
+}
+
+fn pin_or_unpin<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>, pin: bool)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let pin_payload : Result<_> = req.get::<bodyparser::Struct<PinPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let pin_payload = chatbix_try!(pin_payload);
+    let message = match pin_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.pin(&user, p.message_id, pin))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::with_messages(vec![message]).to_string())))
+}
+
+/// admin only, returns the pinned message
+pub fn pin<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    pin_or_unpin(req, chatbix, true)
+}
+
+/// admin only, returns the unpinned message
+pub fn unpin<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    pin_or_unpin(req, chatbix, false)
+}
+
+/// the pinned messages of a channel, the default one if none is given
+pub fn pins<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let (channel, username, auth_key) = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(hashmap) => (hashmap.get("channel").map(|c| c.first().unwrap().clone()),
+                        hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                        hashmap.get("auth_key").map(|k| k.first().unwrap().clone())),
+        Err(UrlDecodingError::EmptyQuery) => (None, None, None),
+        Err(UrlDecodingError::BodyError(body_error)) =>
+            return Err(IronError::new(body_error,status::BadRequest)),
+    };
+    let user = chatbix_try!(auth::user(req, &chatbix, username.as_deref(), auth_key.as_deref()));
+    let messages = chatbix_try!(chatbix.pins(channel.as_deref(), user.as_ref()));
+    Ok(Response::with((status::Ok,JsonSuccess::with_messages(messages).to_string())))
+}
+
+/// the channels the user can see: private channels are only listed for their members
+pub fn channels<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let (username, auth_key) = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(hashmap) => (hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                        hashmap.get("auth_key").map(|k| k.first().unwrap().clone())),
+        Err(UrlDecodingError::EmptyQuery) => (None, None),
+        Err(UrlDecodingError::BodyError(body_error)) =>
+            return Err(IronError::new(body_error,status::BadRequest)),
+    };
+    let user = chatbix_try!(auth::user(req, &chatbix, username.as_deref(), auth_key.as_deref()));
+    let channels = chatbix_try!(chatbix.channels(user.as_ref()));
+    Ok(Response::with((status::Ok,JsonSuccess::with_channels(channels).to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ChannelPayload {
+    name: String,
+    topic: Option<String>,
+    description: Option<String>,
+    /// only when updating
+    archived: Option<bool>,
+    private: Option<bool>,
+    username: Option<String>,
+    auth_key: Option<String>,
+}
+
+/// returns the new channel, owned by the user creating it
+pub fn create_channel<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let channel_payload : Result<_> = req.get::<bodyparser::Struct<ChannelPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let channel_payload = chatbix_try!(channel_payload);
+    let channel = match channel_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.new_channel(&user, p.name.as_str(), p.topic.as_deref(), p.description.as_deref(),
+                                             p.private.unwrap_or(false)))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::with_channels(vec![channel]).to_string())))
+}
+
+/// the owner of the channel or an admin only, returns the updated channel
+pub fn update_channel<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let channel_payload : Result<_> = req.get::<bodyparser::Struct<ChannelPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let channel_payload = chatbix_try!(channel_payload);
+    let channel = match channel_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.edit_channel(&user, p.name.as_str(), p.topic, p.description, p.archived, p.private))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::with_channels(vec![channel]).to_string())))
+}
+
+/// the members of a channel, for the users who can see it
+pub fn channel_members<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let (name, username, auth_key) = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(hashmap) => (hashmap.get("name").map(|n| n.first().unwrap().clone()),
+                        hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                        hashmap.get("auth_key").map(|k| k.first().unwrap().clone())),
+        Err(UrlDecodingError::EmptyQuery) => (None, None, None),
+        Err(UrlDecodingError::BodyError(body_error)) =>
+            return Err(IronError::new(body_error,status::BadRequest)),
+    };
+    let name = match name {
+        Some(name) => name,
+        None => return Error::from_kind(ErrorKind::MissingParameter("name")).into(),
+    };
+    let user = chatbix_try!(auth::required_user(req, &chatbix, username.as_deref(), auth_key.as_deref()));
+    let members = chatbix_try!(chatbix.channel_members(&user, &name));
+    Ok(Response::with((status::Ok,JsonSuccess::with_members(members).to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MembersPayload {
+    name: String,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+    username: Option<String>,
+    auth_key: Option<String>,
+}
+
+/// the owner of the channel or an admin only, returns the members of the channel
+pub fn edit_channel_members<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let members_payload : Result<_> = req.get::<bodyparser::Struct<MembersPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let members_payload = chatbix_try!(members_payload);
+    let members = match members_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.edit_channel_members(&user, p.name.as_str(), &p.add, &p.remove))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::with_members(members).to_string())))
+}
+
+/// the messages mentioning the user, the most recent first:
+/// the next page is the one `before` the last message of the current one
+pub fn mentions<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let mut before : Option<i32> = None;
+    let mut limit : i64 = 50;
+    let mut credentials : (Option<String>,Option<String>) = (None, None);
+    match req.get_ref::<UrlEncodedQuery>() {
+        Ok(hashmap) => {
+            credentials = (hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                           hashmap.get("auth_key").map(|k| k.first().unwrap().clone()));
+            if let Some(message_id) = hashmap.get("before") {
+                before = Some(chatbix_try!(message_id.first().unwrap().parse::<i32>().map_err(Error::from)));
+            };
+            if let Some(l) = hashmap.get("limit") {
+                limit = chatbix_try!(l.first().unwrap().parse::<i64>().map_err(Error::from));
+            };
+        },
+        Err(UrlDecodingError::EmptyQuery) => {},
+        Err(UrlDecodingError::BodyError(body_error)) =>
+            return Err(IronError::new(body_error,status::BadRequest)),
+    };
+    let user = chatbix_try!(auth::required_user(req, &chatbix, credentials.0.as_deref(), credentials.1.as_deref()));
+    let messages = chatbix_try!(chatbix.mentions(&user, before, min(limit, 200)));
+    Ok(Response::with((status::Ok,JsonSuccess::with_messages(messages).to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MarkReadPayload {
+    /// the last message read, which gives the channel
+    message_id: i32,
+    username: Option<String>,
+    auth_key: Option<String>,
+}
+
+pub fn mark_read<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let mark_read_payload : Result<_> = req.get::<bodyparser::Struct<MarkReadPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let mark_read_payload = chatbix_try!(mark_read_payload);
+    match mark_read_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.mark_read(&user, p.message_id))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::empty().to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TypingPayload {
+    /// None for the default channel
+    channel: Option<String>,
+    /// false once the user stopped typing, without sending anything
+    typing: Option<bool>,
+    /// ignored if the user is authenticated, required otherwise
+    username: Option<String>,
+    auth_key: Option<String>,
+}
+
+/// tells the others that the user is typing, to be sent again every few seconds while they are
+pub fn typing<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let typing_payload : Result<_> = req.get::<bodyparser::Struct<TypingPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let typing_payload = chatbix_try!(typing_payload);
+    match typing_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let username = p.username.as_deref();
+            let user = chatbix_try!(auth::user(req, &chatbix, username, p.auth_key.as_deref()));
+            chatbix_try!(chatbix.set_typing(user.as_ref(), username.unwrap_or(""), p.channel.as_deref(), p.typing.unwrap_or(true)))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::empty().to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct DirectPayload {
+    /// the other users of the conversation
+    participants: Vec<String>,
+    username: Option<String>,
+    auth_key: Option<String>,
+}
+
+/// opens a direct conversation, or returns the existing one with the same participants
+pub fn open_direct<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let direct_payload : Result<_> = req.get::<bodyparser::Struct<DirectPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let direct_payload = chatbix_try!(direct_payload);
+    let conversation = match direct_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.open_direct(&user, &p.participants))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::with_conversations(vec![conversation]).to_string())))
+}
+
+/// the direct conversations of the user, the most recently active first
+pub fn conversations<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let (username, auth_key) = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(hashmap) => (hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                        hashmap.get("auth_key").map(|k| k.first().unwrap().clone())),
+        Err(UrlDecodingError::EmptyQuery) => (None, None),
+        Err(UrlDecodingError::BodyError(body_error)) =>
+            return Err(IronError::new(body_error,status::BadRequest)),
+    };
+    let user = chatbix_try!(auth::required_user(req, &chatbix, username.as_deref(), auth_key.as_deref()));
+    let conversations = chatbix_try!(chatbix.conversations(&user));
+    Ok(Response::with((status::Ok,JsonSuccess::with_conversations(conversations).to_string())))
+}
+
+pub fn revisions<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let (message_id, username, auth_key) = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(hashmap) => match hashmap.get("message_id") {
+            Some(message_id) => (chatbix_try!(message_id.first().unwrap().parse::<i32>().map_err(Error::from)),
+                                 hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                                 hashmap.get("auth_key").map(|k| k.first().unwrap().clone())),
+            None => return Error::from_kind(ErrorKind::MissingParameter("message_id")).into(),
+        },
+        Err(UrlDecodingError::EmptyQuery) =>
+            return Error::from_kind(ErrorKind::MissingParameter("message_id")).into(),
+        Err(UrlDecodingError::BodyError(body_error)) =>
+            return Err(IronError::new(body_error,status::BadRequest)),
+    };
+    let user = chatbix_try!(auth::user(req, &chatbix, username.as_deref(), auth_key.as_deref()));
+    let revisions = chatbix_try!(chatbix.revisions(message_id, user.as_ref()));
+    Ok(Response::with((status::Ok,JsonSuccess::with_revisions(revisions).to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
 struct LogoutPayload {
-    username: String,
-    auth_key: String,
+    username: Option<String>,
+    auth_key: Option<String>,
 }
 
 pub fn logout<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
-    let logout_payload : Result<_> = req.get_ref::<bodyparser::Struct<LogoutPayload>>()
+    let logout_payload : Result<_> = req.get::<bodyparser::Struct<LogoutPayload>>()
         .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
     let logout_payload = chatbix_try!(logout_payload);
-    match logout_payload.as_ref() {
+    match logout_payload {
         None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
-        Some(p) => chatbix_try!(chatbix.logout(p.username.as_str(), p.auth_key.as_str())),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.logout(&user))
+        },
     };
     Ok(Response::with((status::Ok,JsonSuccess::empty().to_string())))
 }
 
+pub fn sessions<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let (username, auth_key) = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(hashmap) => (hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                        hashmap.get("auth_key").map(|k| k.first().unwrap().clone())),
+        Err(UrlDecodingError::EmptyQuery) => (None, None),
+        Err(UrlDecodingError::BodyError(body_error)) =>
+            return Err(IronError::new(body_error,status::BadRequest)),
+    };
+    let user = chatbix_try!(auth::required_user(req, &chatbix, username.as_deref(), auth_key.as_deref()));
+    let sessions = chatbix_try!(chatbix.sessions(&user));
+    Ok(Response::with((status::Ok,JsonSuccess::with_sessions(sessions).to_string())))
+}
+
+/// the status chosen by the user, even if they are invisible
+pub fn get_status<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let (username, auth_key) = match req.get_ref::<UrlEncodedQuery>() {
+        Ok(hashmap) => (hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                        hashmap.get("auth_key").map(|k| k.first().unwrap().clone())),
+        Err(UrlDecodingError::EmptyQuery) => (None, None),
+        Err(UrlDecodingError::BodyError(body_error)) =>
+            return Err(IronError::new(body_error,status::BadRequest)),
+    };
+    let user = chatbix_try!(auth::required_user(req, &chatbix, username.as_deref(), auth_key.as_deref()));
+    let user_status = chatbix_try!(chatbix.status(&user));
+    Ok(Response::with((status::Ok,JsonSuccess::with_user_status(user_status).to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct StatusPayload {
+    /// online, away, dnd or invisible, online by default
+    state: Option<String>,
+    status_text: Option<String>,
+    status_emoji: Option<String>,
+    username: Option<String>,
+    auth_key: Option<String>,
+}
+
+pub fn set_status<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let status_payload : Result<_> = req.get::<bodyparser::Struct<StatusPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let status_payload = chatbix_try!(status_payload);
+    let user_status = match status_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.set_status(&user, p.state.as_deref().unwrap_or("online"),
+                                            p.status_text.as_deref(), p.status_emoji.as_deref()))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::with_user_status(user_status).to_string())))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RevokePayload {
+    username: Option<String>,
+    auth_key: Option<String>,
+    /// the id of the session to revoke, as returned by /api/sessions
+    session_id: Option<String>,
+    /// revoke every session but the one making the request
+    all_others: Option<bool>,
+}
+
+pub fn revoke_session<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let revoke_payload : Result<_> = req.get::<bodyparser::Struct<RevokePayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let revoke_payload = chatbix_try!(revoke_payload);
+    match revoke_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            match (p.all_others, p.session_id.as_ref()) {
+                (Some(true), _) => chatbix_try!(chatbix.revoke_other_sessions(&user)),
+                (_, Some(session_id)) => chatbix_try!(chatbix.revoke_session(&user, session_id.as_str())),
+                (_, None) => return Error::from_kind(ErrorKind::MissingParameter("session_id")).into(),
+            }
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::empty().to_string())))
+}
+
+/// replaces the auth_key by a new one: the old one can't be used anymore
+pub fn refresh_session<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)-> IronResult<Response> where Chatbix<I>: ChatbixInterface {
+    let user_agent = user_agent(req);
+    // same credentials as logging out
+    let refresh_payload : Result<_> = req.get::<bodyparser::Struct<LogoutPayload>>()
+        .map_err(|e| Error::from_kind(ErrorKind::BodyparserError(e)));
+    let refresh_payload = chatbix_try!(refresh_payload);
+    let auth_key = match refresh_payload {
+        None => return Error::from_kind(ErrorKind::NoJsonBodyDetected).into(),
+        Some(p) => {
+            let user = chatbix_try!(auth::required_user(req, &chatbix, p.username.as_deref(), p.auth_key.as_deref()));
+            chatbix_try!(chatbix.refresh_session(&user, user_agent.as_deref()))
+        },
+    };
+    Ok(Response::with((status::Ok,JsonSuccess::with_auth_key(auth_key).to_string())))
+}
+
 pub fn fulltext_search<I>(req: &mut Request, chatbix: Arc<Chatbix<I>>)
     -> IronResult<Response>
     where Chatbix<I>: ChatbixInterface
 {
-    let (query, limit) = match req.get_ref::<UrlEncodedQuery>() {
+    let (query, limit, username, auth_key) = match req.get_ref::<UrlEncodedQuery>() {
         Ok(hashmap) => {
             match (hashmap.get("query"), hashmap.get("limit")) {
                 (Some(query),limit) => 
-                    (query.get(0).unwrap().clone(), 
-                     limit.and_then(|l| l.get(0))
+                    (query.first().unwrap().clone(), 
+                     limit.and_then(|l| l.first())
                           .and_then(|l| l.parse().ok())
-                          .unwrap_or(100)),
+                          .unwrap_or(100),
+                     hashmap.get("username").map(|u| u.first().unwrap().clone()),
+                     hashmap.get("auth_key").map(|k| k.first().unwrap().clone())),
                 _ => return Error::from_kind(ErrorKind::EmptyQuery).into(),
             }
         },
         Err(UrlDecodingError::EmptyQuery) => 
             return Error::from_kind(ErrorKind::EmptyQuery).into(),
         Err(UrlDecodingError::BodyError(body_error)) => 
-            return Err(IronError::new(body_error,(status::BadRequest))),
+            return Err(IronError::new(body_error,status::BadRequest)),
     };
-    let res = chatbix_try!(chatbix.fulltext_search(&query, limit));
+    let user = chatbix_try!(auth::user(req, &chatbix, username.as_deref(), auth_key.as_deref()));
+    let res = chatbix_try!(chatbix.search(&query, limit, user.as_ref()));
     Ok(Response::with((status::Ok,
                        JsonSuccess::with_fulltext(res).to_string())))
 }
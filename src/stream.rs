@@ -0,0 +1,152 @@
+use std::io::{self,Write};
+use std::sync::mpsc::{channel,Receiver,RecvTimeoutError};
+use std::time::Duration;
+use iron::response::WriteBody;
+use chatbix::{Chatbix,ChatbixInterface,Interval};
+use hub::{Event,Published};
+use user::{AuthenticatedUser,reading};
+use utils::Slot;
+
+use error::*;
+
+/// The body of a `text/event-stream` response, which is written for as long as the client listens.
+///
+/// The messages get their change id as `id`, so that `Last-Event-ID` is always the last change received.
+pub struct EventStream {
+    receiver: Receiver<Published>,
+    /// what the client missed since its `Last-Event-ID`, sent before anything else
+    backlog: Vec<Event>,
+    last_id: i64,
+    /// freed when the client leaves
+    _slot: Slot,
+}
+
+impl EventStream {
+    /// Subscribes to the events of these channels, as seen by `user`.
+    ///
+    /// If the client is reconnecting, the events since change `last_event_id` are sent first.
+    pub fn new<C>(chatbix: &Chatbix<C>, user: Option<AuthenticatedUser>, channels: &[String], include_default_channel: bool, last_event_id: Option<i64>)
+        -> Result<EventStream>
+        where Chatbix<C>: ChatbixInterface
+    {
+        let slot = chatbix.waiting_slot()?;
+        let channels = chatbix.visible_channels(channels, user.as_ref())?;
+        // subscribed before reading the backlog, so that nothing is missed in between
+        let (sender, receiver) = channel();
+        {
+            let username = user.as_ref().map(|user| user.username.clone());
+            let channels = channels.clone();
+            chatbix.hub().subscribe(move |published| {
+                if !published.is_for(username.as_deref(), &channels, include_default_channel) {
+                    return true;
+                }
+                let published = match published.event {
+                    // only the users reading the channels of the stream
+                    Event::Presence(ref users) => Published {
+                        event: Event::Presence(reading(users, &channels, include_default_channel)),
+                        audience: None,
+                    },
+                    _ => published.clone(),
+                };
+                sender.send(published).is_ok()
+            });
+        }
+        let mut backlog = Vec::new();
+        if let Some(change_id) = last_event_id {
+            let (messages, deleted, _) = chatbix.messages(Interval::AllFromChange(change_id), &channels, include_default_channel, user.as_ref())?;
+            backlog.extend(messages.into_iter().map(Event::Message));
+            backlog.extend(deleted.unwrap_or_default().into_iter().map(|tombstone| Event::Deleted(tombstone, None)));
+        }
+        Ok(EventStream {
+            receiver,
+            backlog,
+            last_id: last_event_id.unwrap_or(0),
+            _slot: slot,
+        })
+    }
+
+    fn write_event(&mut self, res: &mut dyn Write, event: &Event) -> io::Result<()> {
+        let (name, data) = match *event {
+            Event::Message(ref message) => ("message", ::serde_json::to_string(message).unwrap()),
+            Event::Deleted(ref tombstone, _) => ("deleted", ::serde_json::to_string(tombstone).unwrap()),
+            Event::Presence(ref users) => ("presence", ::serde_json::to_string(users).unwrap()),
+            Event::Typing(ref typing) => ("typing", ::serde_json::to_string(typing).unwrap()),
+        };
+        writeln!(res, "event: {}", name)?;
+        if let Event::Message(ref message) = *event {
+            if message.change_id > self.last_id {
+                self.last_id = message.change_id;
+                writeln!(res, "id: {}", message.change_id)?;
+            }
+        }
+        write!(res, "data: {}\n\n", data)
+    }
+}
+
+impl WriteBody for EventStream {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        // how long EventSource waits before reconnecting, in milliseconds
+        write!(res, "retry: 3000\n\n")?;
+        let backlog = ::std::mem::take(&mut self.backlog);
+        for event in &backlog {
+            self.write_event(res, event)?;
+        }
+        res.flush()?;
+        loop {
+            match self.receiver.recv_timeout(Duration::from_secs(15)) {
+                Ok(published) => self.write_event(res, &published.event)?,
+                // comments are ignored by the clients, but fail once they are gone
+                Err(RecvTimeoutError::Timeout) => write!(res, ": keepalive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            res.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chatbix::MemoryStore;
+    use config::Config;
+    use message::NewMessage;
+    use super::*;
+
+    fn new_message(content: &str) -> NewMessage {
+        NewMessage {
+            username: "someone".to_owned(),
+            content: content.to_owned(),
+            tags: None,
+            color: None,
+            channel: None,
+            auth_key: None,
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn the_backlog_is_what_changed_since_the_last_event_id() {
+        let chatbix = Chatbix::<MemoryStore>::new(MemoryStore::new());
+        let first = chatbix.post_message(&new_message("first"), None).unwrap();
+        chatbix.post_message(&new_message("second"), None).unwrap();
+        let stream = EventStream::new(&chatbix, None, &[], true, None).unwrap();
+        assert!(stream.backlog.is_empty());
+        let stream = EventStream::new(&chatbix, None, &[], true, Some(first.change_id)).unwrap();
+        match stream.backlog[..] {
+            [Event::Message(ref message)] => assert_eq!(message.content, "second"),
+            ref backlog => panic!("unexpected backlog {:?}", backlog),
+        }
+        assert_eq!(stream.last_id, first.change_id);
+    }
+
+    #[test]
+    fn streams_are_refused_once_every_slot_is_taken() {
+        let chatbix = Chatbix::<MemoryStore>::new(MemoryStore::new()).with_config(Config { max_waiting: 1, ..Config::default() });
+        let stream = EventStream::new(&chatbix, None, &[], true, None).unwrap();
+        match EventStream::new(&chatbix, None, &[], true, None) {
+            Err(Error(ErrorKind::TooManyWaiting, _)) => {},
+            _ => panic!("expected TooManyWaiting"),
+        }
+        drop(stream);
+        assert!(EventStream::new(&chatbix, None, &[], true, None).is_ok());
+    }
+}
@@ -3,11 +3,63 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use chrono::Duration;
-use rand::{thread_rng, Rng};
+use rand::{OsRng, Rng};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use super::utils::timestamp_ser;
 
 use error::*;
 
+/// what a user shows of their presence
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PresenceState {
+    #[default]
+    #[serde(rename = "online")]
+    Online,
+    #[serde(rename = "away")]
+    Away,
+    /// do not disturb
+    #[serde(rename = "dnd")]
+    Dnd,
+    /// connected, but left out of the connected users
+    #[serde(rename = "invisible")]
+    Invisible,
+}
+
+impl PresenceState {
+    /// as sent by the clients and stored
+    #[cfg_attr(not(any(feature = "postgres", feature = "sqlite")), allow(dead_code))]
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PresenceState::Online => "online",
+            PresenceState::Away => "away",
+            PresenceState::Dnd => "dnd",
+            PresenceState::Invisible => "invisible",
+        }
+    }
+
+    pub fn parse(state: &str) -> Result<PresenceState> {
+        match state {
+            "online" => Ok(PresenceState::Online),
+            "away" => Ok(PresenceState::Away),
+            "dnd" => Ok(PresenceState::Dnd),
+            "invisible" => Ok(PresenceState::Invisible),
+            _ => bail!(ErrorKind::InvalidStatus),
+        }
+    }
+}
+
+/// a row of chat_user_statuses: the status chosen by a registered user, kept across restarts
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct UserStatus {
+    pub state: PresenceState,
+    pub status_text: Option<String>,
+    pub status_emoji: Option<String>,
+}
+
+/// in characters
+pub const MAX_STATUS_TEXT_LENGTH: usize = 100;
+
 #[derive(Debug, Serialize, Clone)]
 pub struct ConnectedUser {
     pub username: Arc<String>,
@@ -16,12 +68,43 @@ pub struct ConnectedUser {
     pub last_active: NaiveDateTime,
     #[serde(serialize_with = "timestamp_ser")]
     pub last_answer: NaiveDateTime,
+    /// the state chosen by the user, `away` once an online user has been inactive for a while
+    pub state: PresenceState,
+    pub status_text: Option<String>,
+    pub status_emoji: Option<String>,
+    #[serde(skip_serializing)]
+    chosen_state: PresenceState,
+    /// the channels of the last heartbeat of the user, which the others can't see
+    #[serde(skip_serializing)]
+    channels: Vec<String>,
+    #[serde(skip_serializing)]
+    default_channel: bool,
+}
+
+impl ConnectedUser {
+    /// whether the last heartbeat of the user was for one of these channels
+    pub fn is_reading(&self, channels: &[String], include_default_channel: bool) -> bool {
+        (include_default_channel && self.default_channel) || self.channels.iter().any(|c| channels.contains(c))
+    }
+
+    /// computes `state` again, returns whether it changed
+    fn update_state(&mut self, now: NaiveDateTime, away_time: Duration) -> bool {
+        let state = match self.chosen_state {
+            PresenceState::Online if self.last_active + away_time < now => PresenceState::Away,
+            chosen_state => chosen_state,
+        };
+        let changed = state != self.state;
+        self.state = state;
+        changed
+    }
 }
 
 #[derive(Debug)]
 pub struct ConnectedUsers {
     users: HashMap<Arc<String>,ConnectedUser>,
     expiration_time: Duration,
+    /// how long online users can stay inactive before being shown as away
+    away_time: Duration,
 }
 
 impl ConnectedUsers {
@@ -29,34 +112,57 @@ impl ConnectedUsers {
         ConnectedUsers {
             users: HashMap::with_capacity(8),
             expiration_time: Duration::seconds(30),
+            away_time: Duration::minutes(5),
         }
     }
 
-    pub fn refresh(&mut self) {
+    /// forgets the users who stopped answering, returns whether there were some,
+    /// or whether someone was shown as away in the meantime
+    pub fn refresh(&mut self) -> bool {
         let now = ::chrono::UTC::now().naive_utc();
         let expiration_time = self.expiration_time;
-        let users = self.users.drain().filter(|&(_,ref user)|{
+        let away_time = self.away_time;
+        let count = self.users.len();
+        let users = self.users.drain().filter(|(_,user)|{
             user.last_answer + expiration_time > now
         }).collect::<HashMap<Arc<String>,ConnectedUser>>();
         // ^ TODO: See if this is optimised: (probably not)
         // There are probably better ways to filter values in a hashmap
+        let disconnected = users.len() != count;
         self.users = users;
+        let mut changed = false;
+        for user in self.users.values_mut() {
+            changed |= user.update_state(now, away_time);
+        }
+        disconnected || changed
     }
 
-    pub fn update(&mut self, username: &str, logged_in: bool, active: bool) {
+    pub fn contains(&self, username: &str) -> bool {
+        self.users.contains_key(&String::from(username))
+    }
+
+    /// returns whether the others should know: the user just connected, is not away anymore,
+    /// or is reading other channels
+    pub fn update(&mut self, username: &str, logged_in: bool, active: bool, channels: &[String], include_default_channel: bool) -> bool {
         let now = ::chrono::UTC::now().naive_utc();
+        let away_time = self.away_time;
         let push: bool = {
             let username = String::from(username);
             // ^TODO: file an issue to make HashMap borrow
             // twice so that get("example") when the key is
             // Arc<String> or Rc<String> works, allowing us to avoid a useless allocation
             match self.users.get_mut(&username) {
-                Some(mut c) => {
+                Some(c) => {
                     if active {
                         c.last_active = now;
                     };
                     c.last_answer = now;
-                    false
+                    let moved = c.channels != channels || c.default_channel != include_default_channel;
+                    if moved {
+                        c.channels = channels.to_vec();
+                        c.default_channel = include_default_channel;
+                    }
+                    return c.update_state(now, away_time) || moved;
                 },
                 None => true
             }
@@ -64,31 +170,249 @@ impl ConnectedUsers {
         if push {
             let username = Arc::new(String::from(username));
             self.users.insert(username.clone(), ConnectedUser {
-                username: username,
-                logged_in: logged_in,
+                username,
+                logged_in,
                 last_active: now,
-                last_answer: now
+                last_answer: now,
+                state: PresenceState::Online,
+                status_text: None,
+                status_emoji: None,
+                chosen_state: PresenceState::Online,
+                channels: channels.to_vec(),
+                default_channel: include_default_channel,
             });
         };
+        push
     }
 
+    /// changes the status of the user if they are connected, returns whether they are
+    pub fn set_status(&mut self, username: &str, status: &UserStatus) -> bool {
+        let now = ::chrono::UTC::now().naive_utc();
+        let away_time = self.away_time;
+        match self.users.get_mut(&String::from(username)) {
+            Some(user) => {
+                user.chosen_state = status.state;
+                user.status_text = status.status_text.clone();
+                user.status_emoji = status.status_emoji.clone();
+                user.update_state(now, away_time);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// the connected users, the invisible ones excluded
     pub fn as_vec(&self) -> Vec<ConnectedUser> {
-        self.users.iter().map(|(_,u)| u.clone()).collect::<Vec<ConnectedUser>>()
+        self.users.values()
+            .filter(|u| u.state != PresenceState::Invisible)
+            .cloned().collect::<Vec<ConnectedUser>>()
     }
+
+    /// the connected users reading one of these channels, the invisible ones excluded
+    pub fn in_channels(&self, channels: &[String], include_default_channel: bool) -> Vec<ConnectedUser> {
+        reading(&self.as_vec(), channels, include_default_channel)
+    }
+
+    /// the usernames of the connected users reading each of these channels, keyed by channel ('' for the default one)
+    pub fn by_channel(&self, channels: &[String], include_default_channel: bool) -> HashMap<String, Vec<String>> {
+        let users = self.as_vec();
+        let mut by_channel : HashMap<String, Vec<String>> = HashMap::new();
+        for channel in channels {
+            let channel_list = [channel.clone()];
+            by_channel.insert(channel.clone(), usernames(&reading(&users, &channel_list, false)));
+        }
+        if include_default_channel {
+            by_channel.insert(String::new(), usernames(&reading(&users, &[], true)));
+        }
+        by_channel
+    }
+}
+
+/// the users among these who are reading one of the channels
+pub fn reading(users: &[ConnectedUser], channels: &[String], include_default_channel: bool) -> Vec<ConnectedUser> {
+    users.iter().filter(|u| u.is_reading(channels, include_default_channel)).cloned().collect()
 }
 
-pub enum UserConnectionStatus {
-    AuthFailed,
-    NotLoggedIn,
-    Connected(bool) //< whether admin or not
+/// sorted
+fn usernames(users: &[ConnectedUser]) -> Vec<String> {
+    let mut usernames = users.iter().map(|u| (*u.username).clone()).collect::<Vec<String>>();
+    usernames.sort();
+    usernames
 }
 
+/// the users typing in a channel, as pushed to the clients
+#[derive(Debug, Serialize, Clone)]
+pub struct Typing {
+    /// "" for the default channel
+    pub channel: String,
+    /// sorted
+    pub usernames: Vec<String>,
+}
+
+/// Who is typing in which channel ("" being the default one).
+///
+/// Clients say they are typing every few seconds, and are forgotten when they stop doing so.
 #[derive(Debug)]
-pub struct CachedUser {
+pub struct TypingUsers {
+    /// when each user last said they were typing, by channel
+    channels: HashMap<String, HashMap<String, NaiveDateTime>>,
+    expiration_time: Duration,
+}
+
+impl TypingUsers {
+    pub fn new() -> TypingUsers {
+        TypingUsers {
+            channels: HashMap::new(),
+            expiration_time: Duration::seconds(6),
+        }
+    }
+
+    /// forgets the users who stopped typing, returns the channels where there were some
+    pub fn refresh(&mut self) -> Vec<String> {
+        let now = ::chrono::UTC::now().naive_utc();
+        let expiration_time = self.expiration_time;
+        let mut changed = Vec::new();
+        for (channel, users) in &mut self.channels {
+            let count = users.len();
+            users.retain(|_, since| *since + expiration_time > now);
+            if users.len() != count {
+                changed.push(channel.clone());
+            }
+        }
+        self.channels.retain(|_, users| !users.is_empty());
+        changed
+    }
+
+    /// returns whether the users typing in the channel changed
+    pub fn update(&mut self, channel: &str, username: &str, typing: bool) -> bool {
+        if typing {
+            let now = ::chrono::UTC::now().naive_utc();
+            let users = self.channels.entry(channel.to_owned()).or_default();
+            users.insert(username.to_owned(), now).is_none()
+        } else {
+            match self.channels.get_mut(channel) {
+                Some(users) => users.remove(username).is_some(),
+                None => false,
+            }
+        }
+    }
+
+    pub fn in_channel(&self, channel: &str) -> Typing {
+        let mut usernames = self.channels.get(channel)
+            .map(|users| users.keys().cloned().collect::<Vec<String>>())
+            .unwrap_or_default();
+        usernames.sort();
+        Typing {
+            channel: channel.to_owned(),
+            usernames,
+        }
+    }
+}
+
+/// a row of chat_users
+pub struct StoredUser {
+    /// either a PHC string, or a legacy truncated sha512
+    pub password: String,
+    pub admin: bool,
+}
+
+/// the user making a request, once their auth_key has been checked
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub username: String,
+    pub admin: bool,
     pub auth_key: String,
+}
+
+/// a row of chat_sessions
+///
+/// the auth_key itself is never stored, only its hash
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub key_hash: String,
+    pub username: String,
+    pub created_at: NaiveDateTime,
+    pub last_used: NaiveDateTime,
+    pub user_agent: Option<String>,
+}
+
+/// how long sessions stay valid, and how they are cached
+#[derive(Debug, Clone, Copy)]
+pub struct SessionLimits {
+    /// counted from the login, whether the session is used or not
+    pub max_age: Duration,
+    /// counted from the last time the session was used
+    pub idle_timeout: Duration,
+    /// how long a session is cached before being read again from the database,
+    /// so that a logout or a revocation made through another instance is seen by this one as well
+    pub cache_ttl: Duration,
+    /// how often the last use of a session is written to the database
+    pub persist_interval: Duration,
+}
+
+impl Default for SessionLimits {
+    fn default() -> SessionLimits {
+        SessionLimits {
+            max_age: Duration::days(30),
+            idle_timeout: Duration::days(7),
+            cache_ttl: Duration::seconds(30),
+            persist_interval: Duration::minutes(1),
+        }
+    }
+}
+
+impl SessionLimits {
+    pub fn expired(&self, created_at: NaiveDateTime, last_used: NaiveDateTime, now: NaiveDateTime) -> bool {
+        created_at + self.max_age < now || last_used + self.idle_timeout < now
+    }
+}
+
+/// generates a new random auth_key
+pub fn new_auth_key() -> String {
+    OsRng::new().expect("Failed to access the OS random number generator")
+        .gen_ascii_chars().take(32).collect()
+}
+
+/// what is stored in the database instead of the auth_key
+pub fn hash_auth_key(auth_key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(auth_key);
+    hasher.result_str()
+}
+
+/// what a user can see of their own sessions
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    /// the hash of the auth_key, which is enough to identify the session without revealing the key
+    pub id: String,
+    #[serde(serialize_with = "timestamp_ser")]
+    pub created_at: NaiveDateTime,
+    #[serde(serialize_with = "timestamp_ser")]
+    pub last_used: NaiveDateTime,
+    pub user_agent: Option<String>,
+    /// whether this is the session used to make the request
+    pub current: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedUser {
+    pub username: String,
+    pub key_hash: String,
     pub admin: bool,
+    pub created_at: NaiveDateTime,
+    /// when the session was last used, as far as the database knows
+    pub last_used: NaiveDateTime,
+    /// when the session was read from the database
+    pub cached_at: NaiveDateTime,
 }
 
+/// Cache of the sessions stored in the database, so that checking an auth_key doesn't
+/// require a query every time.
+///
+/// Every login creates its own session, so the cache is indexed by auth_key:
+/// a user can have as many as they have devices.
+///
+/// The sessions cached for longer than `SessionLimits::cache_ttl` are ignored, and thus read again.
 pub struct CachedUsers(HashMap<String, CachedUser>);
 
 impl CachedUsers {
@@ -96,42 +420,160 @@ impl CachedUsers {
         CachedUsers(HashMap::new())
     }
 
-    pub fn login(&mut self, username: &str, admin: bool) -> String {
-        if self.0.contains_key(username) {
-            self.0.get(username).unwrap().auth_key.clone()
-        } else {
-            // generate new key
-            let auth_key : String = thread_rng().gen_ascii_chars().take(16).collect();
-            self.0.insert(username.to_owned(), CachedUser {auth_key: auth_key.clone(), admin: admin});
-            auth_key.clone()
-        }
+    pub fn insert(&mut self, auth_key: &str, cached_user: CachedUser) {
+        self.0.insert(auth_key.to_owned(), cached_user);
     }
 
-    // Maybe return a Result here?
-    // return false if not connected
-    pub fn logout(&mut self, username: &str, auth_key: &str) -> Result<()> {
-        match self.0.get(username) {
-            Some(c) => {
-                if c.auth_key != auth_key {
-                    bail!(ErrorKind::InvalidAuthKey)
-                };
-            },
-            None => bail!(ErrorKind::NotLoggedIn)
-        };
-        self.0.remove(username);
-        Ok(())
-    }
-    
-    pub fn check(&self, username: &str, auth_key: &str) -> UserConnectionStatus {
-        match self.0.get(username) {
-            Some(cached_user) => {
-                match (cached_user.auth_key == auth_key, cached_user.admin) {
-                    (false,_) => UserConnectionStatus::AuthFailed,
-                    (true,false) => UserConnectionStatus::Connected(false),
-                    (true,true) => UserConnectionStatus::Connected(true),
-                }
+    /// removes the session with this key_hash from the cache
+    pub fn revoke(&mut self, key_hash: &str) {
+        self.0.retain(|_, c| c.key_hash != key_hash);
+    }
+
+    /// removes every session of the user from the cache, except the one with this auth_key
+    pub fn revoke_others(&mut self, username: &str, auth_key: &str) {
+        self.0.retain(|k, c| c.username != username || k == auth_key);
+    }
+
+    /// None if the session isn't cached, or was cached for too long
+    pub fn get(&self, auth_key: &str, now: NaiveDateTime, limits: &SessionLimits) -> Option<&CachedUser> {
+        self.0.get(auth_key).filter(|c| c.cached_at + limits.cache_ttl > now)
+    }
+
+    /// Marks the cached session as used now.
+    ///
+    /// returns true if the last use known by the database is older than `limits.persist_interval`,
+    /// in which case the database should be updated as well
+    pub fn touch(&mut self, auth_key: &str, now: NaiveDateTime, limits: &SessionLimits) -> bool {
+        match self.0.get_mut(auth_key) {
+            Some(cached_user) if cached_user.last_used + limits.persist_interval < now => {
+                cached_user.last_used = now;
+                true
             },
-            None => UserConnectionStatus::NotLoggedIn,
+            _ => false,
         }
     }
+
+    /// forgets the sessions which were cached for too long
+    pub fn refresh(&mut self, now: NaiveDateTime, limits: &SessionLimits) {
+        self.0.retain(|_, c| c.cached_at + limits.cache_ttl > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration,NaiveDateTime};
+    use super::*;
+
+    fn cached_user(cached_at: NaiveDateTime) -> CachedUser {
+        CachedUser {
+            username: "someone".to_owned(),
+            key_hash: hash_auth_key("key"),
+            admin: false,
+            created_at: cached_at,
+            last_used: cached_at,
+            cached_at,
+        }
+    }
+
+    #[test]
+    fn sessions_expire_after_max_age_or_idle_timeout() {
+        let limits = SessionLimits { max_age: Duration::days(30), idle_timeout: Duration::days(7), ..SessionLimits::default() };
+        let created_at = NaiveDateTime::from_timestamp_opt(1_500_000_000, 0).unwrap();
+        let used_at = created_at + Duration::days(25);
+        assert!(!limits.expired(created_at, used_at, used_at + Duration::days(1)));
+        // used recently, but logged in too long ago
+        assert!(limits.expired(created_at, used_at, created_at + Duration::days(31)));
+        // not used for too long
+        assert!(limits.expired(created_at, created_at, created_at + Duration::days(8)));
+        assert!(!limits.expired(created_at, created_at, created_at + Duration::days(7)));
+    }
+
+    #[test]
+    fn cached_users_are_read_again_after_a_while() {
+        let limits = SessionLimits { cache_ttl: Duration::seconds(30), ..SessionLimits::default() };
+        let cached_at = NaiveDateTime::from_timestamp_opt(1_500_000_000, 0).unwrap();
+        let mut cached_users = CachedUsers::new();
+        cached_users.insert("key", cached_user(cached_at));
+        assert!(cached_users.get("key", cached_at + Duration::seconds(29), &limits).is_some());
+        assert!(cached_users.get("key", cached_at + Duration::seconds(30), &limits).is_none());
+    }
+
+    #[test]
+    fn refresh_forgets_the_old_cached_users() {
+        let limits = SessionLimits::default();
+        let now = NaiveDateTime::from_timestamp_opt(1_500_000_000, 0).unwrap();
+        let mut cached_users = CachedUsers::new();
+        cached_users.insert("old", cached_user(now - Duration::minutes(1)));
+        cached_users.insert("new", cached_user(now));
+        cached_users.refresh(now, &limits);
+        assert_eq!(cached_users.0.len(), 1);
+        assert!(cached_users.get("new", now, &limits).is_some());
+    }
+
+    #[test]
+    fn typing_users_only_change_when_someone_starts_or_stops() {
+        let mut typing_users = TypingUsers::new();
+        assert!(typing_users.update("", "someone", true));
+        // still typing
+        assert!(!typing_users.update("", "someone", true));
+        assert!(typing_users.update("", "other", true));
+        assert_eq!(typing_users.in_channel("").usernames, vec!["other", "someone"]);
+        assert!(typing_users.update("", "someone", false));
+        assert!(!typing_users.update("", "someone", false));
+        assert!(!typing_users.update("elsewhere", "other", false));
+        assert!(typing_users.refresh().is_empty());
+        typing_users.expiration_time = Duration::zero();
+        assert_eq!(typing_users.refresh(), vec![String::new()]);
+        assert!(typing_users.in_channel("").usernames.is_empty());
+    }
+
+    #[test]
+    fn inactive_users_are_shown_as_away_unless_they_chose_a_state() {
+        let mut connected_users = ConnectedUsers::new();
+        assert!(connected_users.update("someone", true, true, &[], true));
+        assert!(connected_users.update("busy", true, true, &[], true));
+        let dnd = UserStatus { state: PresenceState::Dnd, ..UserStatus::default() };
+        assert!(connected_users.set_status("busy", &dnd));
+        assert!(!connected_users.set_status("nobody", &dnd));
+        assert!(!connected_users.refresh());
+        connected_users.away_time = Duration::zero();
+        assert!(connected_users.refresh());
+        let state = |connected_users: &ConnectedUsers, username: &str| {
+            connected_users.users[&String::from(username)].state
+        };
+        assert_eq!(state(&connected_users, "someone"), PresenceState::Away);
+        assert_eq!(state(&connected_users, "busy"), PresenceState::Dnd);
+        // nothing changed since
+        assert!(!connected_users.refresh());
+    }
+
+    #[test]
+    fn invisible_users_are_left_out_of_the_connected_users() {
+        let mut connected_users = ConnectedUsers::new();
+        connected_users.update("someone", true, true, &[], true);
+        connected_users.update("hidden", true, true, &[], true);
+        let invisible = UserStatus { state: PresenceState::Invisible, ..UserStatus::default() };
+        connected_users.set_status("hidden", &invisible);
+        let usernames = connected_users.as_vec().into_iter().map(|u| (*u.username).clone()).collect::<Vec<String>>();
+        assert_eq!(usernames, vec!["someone"]);
+        assert!(connected_users.contains("hidden"));
+    }
+
+    #[test]
+    fn connected_users_are_listed_in_the_channels_of_their_last_heartbeat() {
+        let mut connected_users = ConnectedUsers::new();
+        let rust = vec!["rust".to_owned()];
+        let go = vec!["go".to_owned()];
+        connected_users.update("crab", true, true, &rust, false);
+        connected_users.update("gopher", true, true, &go, true);
+        assert!(!connected_users.update("crab", true, true, &rust, false));
+        let by_channel = connected_users.by_channel(&["rust".to_owned(), "go".to_owned()], true);
+        assert_eq!(by_channel["rust"], vec!["crab"]);
+        assert_eq!(by_channel["go"], vec!["gopher"]);
+        assert_eq!(by_channel[""], vec!["gopher"]);
+        // moved to another channel, the others should know
+        assert!(connected_users.update("crab", true, true, &go, false));
+        assert_eq!(usernames(&connected_users.in_channels(&go, false)), vec!["crab", "gopher"]);
+        assert!(connected_users.in_channels(&rust, false).is_empty());
+    }
 }
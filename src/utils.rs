@@ -3,11 +3,20 @@ use std::result::Result as StdResult;
 use error::*;
 use serde::Serializer;
 use chrono::{UTC,Timelike};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
 
 pub fn timestamp_ser<S>(time: &NaiveDateTime, serializer: S) -> StdResult<S::Ok, S::Error> where S: Serializer {
     serializer.serialize_i64(time.timestamp())
 }
 
+pub fn optional_timestamp_ser<S>(time: &Option<NaiveDateTime>, serializer: S) -> StdResult<S::Ok, S::Error> where S: Serializer {
+    match *time {
+        Some(ref time) => serializer.serialize_some(&time.timestamp()),
+        None => serializer.serialize_none(),
+    }
+}
+
 /// will both try to parse dates like 2017-01-19T22:56:16
 /// and integers like 1485357232
 pub fn timestamp_parse(t: &str) -> Result<NaiveDateTime> {
@@ -24,3 +33,48 @@ pub fn timestamp_parse(t: &str) -> Result<NaiveDateTime> {
 pub fn now() -> NaiveDateTime {
     UTC::now().naive_utc().with_nanosecond(0).unwrap()
 }
+
+/// splits some text into lowercase words, the same way for indexing and for searching
+pub fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+/// A fixed number of slots, each of them taken until the `Slot` is dropped
+#[derive(Debug)]
+pub struct Slots {
+    taken: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl Slots {
+    pub fn new(max: usize) -> Slots {
+        Slots {
+            taken: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// None if all the slots are taken
+    pub fn take(&self) -> Option<Slot> {
+        let mut taken = self.taken.load(Ordering::SeqCst);
+        while taken < self.max {
+            match self.taken.compare_exchange(taken, taken + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(Slot(self.taken.clone())),
+                Err(actual) => taken = actual,
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug)]
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
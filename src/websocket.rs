@@ -0,0 +1,241 @@
+use std::fmt;
+use std::sync::{Arc,Mutex};
+use std::thread;
+use chatbix::{Chatbix,ChatbixInterface};
+use hub::{Event,Published};
+use message::{Message,Tombstone};
+use user::{AuthenticatedUser,ConnectedUser,Typing,reading};
+use ws;
+
+use error::*;
+
+/// a frame sent by a client
+#[derive(Debug, Deserialize)]
+struct ClientFrame {
+    /// auth, subscribe or heartbeat
+    #[serde(rename = "type")]
+    kind: String,
+    /// auth
+    auth_key: Option<String>,
+    /// subscribe
+    channels: Option<Vec<String>>,
+    /// subscribe
+    no_default_channel: Option<bool>,
+    /// heartbeat, when not authenticated
+    username: Option<String>,
+    /// heartbeat
+    active: Option<bool>,
+}
+
+/// a frame sent to a client
+#[derive(Debug, Serialize)]
+struct ServerFrame<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if="Option::is_none")]
+    message: Option<&'a Message>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    deleted: Option<&'a Tombstone>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    users_connected: Option<&'a [ConnectedUser]>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    typing: Option<&'a Typing>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    channels: Option<&'a [String]>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    username: Option<&'a str>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    error: Option<String>,
+}
+
+impl<'a> ServerFrame<'a> {
+    fn new(kind: &'static str) -> ServerFrame<'a> {
+        ServerFrame {
+            kind,
+            message: None,
+            deleted: None,
+            users_connected: None,
+            typing: None,
+            channels: None,
+            username: None,
+            error: None,
+        }
+    }
+
+    fn event(published: &'a Published) -> ServerFrame<'a> {
+        match published.event {
+            Event::Message(ref message) => ServerFrame {
+                message: Some(message),
+                ..Self::new("message")
+            },
+            Event::Deleted(ref tombstone, _) => ServerFrame {
+                deleted: Some(tombstone),
+                ..Self::new("deleted")
+            },
+            Event::Presence(ref users) => Self::presence(users),
+            Event::Typing(ref typing) => ServerFrame {
+                typing: Some(typing),
+                ..Self::new("typing")
+            },
+        }
+    }
+
+    fn presence(users: &'a [ConnectedUser]) -> ServerFrame<'a> {
+        ServerFrame {
+            users_connected: Some(users),
+            ..Self::new("presence")
+        }
+    }
+
+    fn error(error: Error) -> ServerFrame<'a> {
+        let message = match error.describe() {
+            Ok((message, _)) => message,
+            Err(e) => {
+                println!("Unexpected websocket error: `{0}` ({0:?})", e);
+                "unexpected internal server error".to_owned()
+            },
+        };
+        ServerFrame {
+            error: Some(message),
+            ..Self::new("error")
+        }
+    }
+}
+
+impl<'a> fmt::Display for ServerFrame<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&::serde_json::to_string(&self).unwrap())
+    }
+}
+
+/// what a connection is subscribed to, shared with its hub listener
+struct Subscription {
+    user: Option<AuthenticatedUser>,
+    channels: Vec<String>,
+    include_default_channel: bool,
+    closed: bool,
+}
+
+struct Connection<C> {
+    out: ws::Sender,
+    chatbix: Arc<Chatbix<C>>,
+    subscription: Arc<Mutex<Subscription>>,
+    /// whether the hub listener was registered, on the first subscribe
+    listening: bool,
+}
+
+impl<C> Connection<C> where Chatbix<C>: ChatbixInterface, C: Send + Sync + 'static {
+    fn answer(&self, frame: ClientFrame) -> Result<String> {
+        // the subscription is never locked while calling chatbix, which may publish
+        // events to the listener of this very connection
+        match frame.kind.as_str() {
+            "auth" => {
+                let auth_key = match frame.auth_key {
+                    Some(auth_key) => auth_key,
+                    None => bail!(ErrorKind::MissingParameter("auth_key")),
+                };
+                let user = self.chatbix.authenticate(&auth_key)?;
+                let answer = ServerFrame {
+                    username: Some(&user.username),
+                    ..ServerFrame::new("authenticated")
+                }.to_string();
+                self.subscription.lock().unwrap().user = Some(user);
+                Ok(answer)
+            },
+            "subscribe" => {
+                let user = self.subscription.lock().unwrap().user.clone();
+                let channels = self.chatbix.visible_channels(&frame.channels.unwrap_or_default(), user.as_ref())?;
+                let answer = ServerFrame {
+                    channels: Some(&channels),
+                    ..ServerFrame::new("subscribed")
+                }.to_string();
+                let mut subscription = self.subscription.lock().unwrap();
+                subscription.include_default_channel = !frame.no_default_channel.unwrap_or(false);
+                subscription.channels = channels;
+                Ok(answer)
+            },
+            "heartbeat" => {
+                let (user, channels, include_default_channel) = {
+                    let subscription = self.subscription.lock().unwrap();
+                    (subscription.user.clone(), subscription.channels.clone(), subscription.include_default_channel)
+                };
+                let username = frame.username.unwrap_or_default();
+                let users = self.chatbix.heartbeat_mut(user.as_ref(), &username, frame.active.unwrap_or(true),
+                                                       &channels, include_default_channel)?;
+                Ok(ServerFrame::presence(&users).to_string())
+            },
+            _ => bail!(ErrorKind::MissingParameter("type")),
+        }
+    }
+
+    /// forwards the events published to the hub which are meant for this connection
+    fn listen(&mut self) {
+        if self.listening {
+            return;
+        }
+        self.listening = true;
+        let out = self.out.clone();
+        let subscription = self.subscription.clone();
+        self.chatbix.hub().subscribe(move |published| {
+            let subscription = subscription.lock().unwrap();
+            if subscription.closed {
+                return false;
+            }
+            let username = subscription.user.as_ref().map(|user| user.username.as_str());
+            if !published.is_for(username, &subscription.channels, subscription.include_default_channel) {
+                return true;
+            }
+            let frame = match published.event {
+                // only the users reading the channels of the subscription
+                Event::Presence(ref users) =>
+                    ServerFrame::presence(&reading(users, &subscription.channels, subscription.include_default_channel)).to_string(),
+                _ => ServerFrame::event(published).to_string(),
+            };
+            out.send(frame).is_ok()
+        });
+    }
+}
+
+impl<C> ws::Handler for Connection<C> where Chatbix<C>: ChatbixInterface, C: Send + Sync + 'static {
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let frame : Result<ClientFrame> = msg.as_text()
+            .map_err(|_| Error::from_kind(ErrorKind::NoJsonBodyDetected))
+            .and_then(|text| ::serde_json::from_str(text).map_err(|_| Error::from_kind(ErrorKind::NoJsonBodyDetected)));
+        let subscribing = frame.as_ref().map(|frame| frame.kind == "subscribe").unwrap_or(false);
+        let answer = match frame.and_then(|frame| self.answer(frame)) {
+            Ok(answer) => answer,
+            Err(e) => ServerFrame::error(e).to_string(),
+        };
+        if subscribing {
+            self.listen();
+        }
+        self.out.send(answer)
+    }
+
+    fn on_close(&mut self, _code: ws::CloseCode, _reason: &str) {
+        self.subscription.lock().unwrap().closed = true;
+    }
+}
+
+/// Runs the WebSocket server in its own thread.
+///
+/// Clients authenticate with an `auth` frame, subscribe to channels with a `subscribe` frame,
+/// and then receive the new messages, deletions and presence changes as they happen.
+pub fn listen<C>(chatbix: Arc<Chatbix<C>>, listen_url: String) where Chatbix<C>: ChatbixInterface, C: Send + Sync + 'static {
+    thread::spawn(move || {
+        let result = ws::listen(&*listen_url, |out| Connection {
+            out,
+            chatbix: chatbix.clone(),
+            subscription: Arc::new(Mutex::new(Subscription {
+                user: None,
+                channels: Vec::new(),
+                include_default_channel: true,
+                closed: false,
+            })),
+            listening: false,
+        });
+        if let Err(e) = result {
+            println!("The websocket server stopped: {}", e);
+        }
+    });
+}